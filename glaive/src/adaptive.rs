@@ -0,0 +1,243 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::def::AdaptiveConcurrency;
+use crate::metrics;
+
+// the weight of a latency sample in the long term latency of the gradient limit
+const LONG_RTT_WEIGHT: f64 = 0.05;
+
+// the algorithm adjusting the limit
+enum Algorithm {
+    // additive increase, multiplicative decrease on the drops and the slow responses
+    Aimd {
+        backoff_ratio: f64,
+        timeout: Duration,
+    },
+    // follows the ratio between the long term latency and the sampled latency
+    Gradient {
+        smoothing: f64,
+        tolerance: f64,
+    },
+}
+
+// the outcome of a proxied request
+#[derive(Debug, Clone, Copy)]
+enum Sample {
+    Latency(Duration),
+    // the upstream failed to connect or answered with a server error
+    Dropped,
+}
+
+struct AdaptiveState {
+    limit: f64,
+    in_flight: usize,
+    // the long term latency in milliseconds, gradient only
+    long_rtt: Option<f64>,
+}
+
+// an adaptive concurrency limiter of a cluster
+// the limit is adjusted from the upstream latency and errors
+pub struct AdaptiveLimiter {
+    cluster: String,
+    algorithm: Algorithm,
+    min_limit: f64,
+    max_limit: f64,
+    state: Mutex<AdaptiveState>,
+}
+
+impl AdaptiveLimiter {
+    pub fn new(cluster: String, config: &AdaptiveConcurrency) -> Self {
+        let (algorithm, limits) = match config {
+            AdaptiveConcurrency::Aimd { aimd } => (
+                Algorithm::Aimd {
+                    backoff_ratio: aimd.backoff_ratio.unwrap_or(0.9).clamp(0.1, 0.99),
+                    timeout: Duration::from_millis(aimd.timeout.unwrap_or(1000)),
+                },
+                &aimd.limits,
+            ),
+            AdaptiveConcurrency::Gradient { gradient } => (
+                Algorithm::Gradient {
+                    smoothing: gradient.smoothing.unwrap_or(0.2).clamp(0.01, 1.0),
+                    tolerance: gradient.tolerance.unwrap_or(1.5).max(1.0),
+                },
+                &gradient.limits,
+            ),
+        };
+        let (initial_limit, min_limit, max_limit) = limits.get_limits();
+        metrics::ADAPTIVE_CONCURRENCY_LIMIT
+            .with_label_values(&[&cluster])
+            .set(initial_limit as i64);
+        AdaptiveLimiter {
+            cluster,
+            algorithm,
+            min_limit: min_limit as f64,
+            max_limit: max_limit as f64,
+            state: Mutex::new(AdaptiveState {
+                limit: initial_limit as f64,
+                in_flight: 0,
+                long_rtt: None,
+            }),
+        }
+    }
+
+    // take a slot, returns none when the request should be shed
+    pub fn try_acquire(self: &Arc<Self>) -> Option<AdaptivePermit> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit as usize {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(AdaptivePermit {
+            limiter: self.clone(),
+            sample: None,
+        })
+    }
+
+    // free the slot and adjust the limit with the sample
+    fn release(&self, sample: Option<Sample>) {
+        let mut state = self.state.lock().unwrap();
+        let in_flight = state.in_flight;
+        state.in_flight -= 1;
+        // the requests without an upstream response, e.g. the cache hits, are not sampled
+        let Some(sample) = sample else {
+            return;
+        };
+        let limit = state.limit;
+        let new_limit = match &self.algorithm {
+            Algorithm::Aimd {
+                backoff_ratio,
+                timeout,
+            } => match sample {
+                Sample::Latency(latency) if latency <= *timeout => {
+                    // the limit only grows when it is in use
+                    if in_flight * 2 >= limit as usize {
+                        limit + 1.0
+                    } else {
+                        limit
+                    }
+                }
+                _ => limit * backoff_ratio,
+            },
+            Algorithm::Gradient {
+                smoothing,
+                tolerance,
+            } => {
+                let gradient = match sample {
+                    Sample::Latency(latency) => {
+                        let rtt = latency.as_secs_f64() * 1000.0;
+                        let long_rtt = match state.long_rtt {
+                            Some(long_rtt) => {
+                                long_rtt * (1.0 - LONG_RTT_WEIGHT) + rtt * LONG_RTT_WEIGHT
+                            }
+                            None => rtt,
+                        };
+                        state.long_rtt = Some(long_rtt);
+                        // the limit only moves when it is in use
+                        if (in_flight as f64) < limit / 2.0 {
+                            return;
+                        }
+                        (tolerance * long_rtt / rtt.max(0.001)).clamp(0.5, 1.0)
+                    }
+                    Sample::Dropped => 0.5,
+                };
+                // the square root of the limit is left for the queueing requests
+                let target = limit * gradient + limit.sqrt();
+                limit * (1.0 - smoothing) + target * smoothing
+            }
+        };
+        state.limit = new_limit.clamp(self.min_limit, self.max_limit);
+        metrics::ADAPTIVE_CONCURRENCY_LIMIT
+            .with_label_values(&[&self.cluster])
+            .set(state.limit as i64);
+    }
+}
+
+// a slot of the adaptive limiter, the limit is adjusted when the permit is dropped
+pub struct AdaptivePermit {
+    limiter: Arc<AdaptiveLimiter>,
+    sample: Option<Sample>,
+}
+
+impl AdaptivePermit {
+    // keep the upstream latency, a drop is kept over the latency of a retry
+    pub fn record_latency(&mut self, latency: Duration) {
+        if self.sample.is_none() {
+            self.sample = Some(Sample::Latency(latency));
+        }
+    }
+    // the upstream failed to connect or answered with a server error
+    pub fn record_drop(&mut self) {
+        self.sample = Some(Sample::Dropped);
+    }
+    pub fn has_sample(&self) -> bool {
+        self.sample.is_some()
+    }
+}
+
+impl Drop for AdaptivePermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.sample);
+    }
+}
+
+#[cfg(test)]
+mod adaptive_mod {
+    use super::*;
+
+    fn limiter(config: &str) -> Arc<AdaptiveLimiter> {
+        let config: AdaptiveConcurrency = serde_yaml::from_str(config).unwrap();
+        Arc::new(AdaptiveLimiter::new("test".to_string(), &config))
+    }
+
+    fn get_limit(limiter: &AdaptiveLimiter) -> usize {
+        limiter.state.lock().unwrap().limit as usize
+    }
+
+    #[test]
+    fn aimd_test() {
+        let limiter = limiter("aimd:\n  initial_limit: 2\n  min_limit: 1\n  timeout: 100");
+        let mut first = limiter.try_acquire().unwrap();
+        let mut second = limiter.try_acquire().unwrap();
+        // the requests over the limit are shed
+        assert!(limiter.try_acquire().is_none());
+        first.record_latency(Duration::from_millis(10));
+        drop(first);
+        assert_eq!(get_limit(&limiter), 3);
+        second.record_drop();
+        drop(second);
+        assert_eq!(get_limit(&limiter), 2);
+    }
+
+    #[test]
+    fn gradient_test() {
+        let limiter = limiter("gradient:\n  initial_limit: 10\n  smoothing: 1.0\n  tolerance: 1.0");
+        let permits: Vec<_> = (0..10).map(|_| limiter.try_acquire().unwrap()).collect();
+        for (index, mut permit) in permits.into_iter().enumerate() {
+            // the latency grows tenfold after the first sample
+            let latency = if index == 0 { 10 } else { 100 };
+            permit.record_latency(Duration::from_millis(latency));
+        }
+        // the limit shrinks as the latency grows
+        assert!(get_limit(&limiter) < 10);
+    }
+}
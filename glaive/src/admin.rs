@@ -0,0 +1,417 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+use pingora::services::listening::Service;
+
+use async_trait::async_trait;
+use http::{header, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::cache_index::{CachePurger, PurgeTarget};
+use crate::credential::constant_time_eq;
+use crate::def::Admin;
+use crate::metrics;
+use crate::quota::{QuotaManager, QuotaUsage};
+
+// admin error response body
+#[derive(Debug, Serialize)]
+struct AdminError {
+    status_code: u16,
+    message: String,
+}
+
+// admin quota usage body
+#[derive(Debug, Serialize)]
+struct ConsumerQuota {
+    consumer: String,
+    quotas: Vec<QuotaUsage>,
+}
+
+// admin cache purge body, e.g. {"cluster": "svc", "tag": "catalog", "soft": true}
+#[derive(Debug, Deserialize)]
+struct CachePurge {
+    // the cluster of the objects, every cluster by default
+    cluster: Option<String>,
+    // mark the objects stale instead of removing them
+    soft: Option<bool>,
+    // the url, the prefix or the tag, every object by default
+    #[serde(flatten)]
+    target: PurgeTarget,
+}
+
+// admin cache purge result body
+#[derive(Debug, Serialize)]
+struct CachePurged {
+    purged: usize,
+    // false when the index of a cluster may miss some objects, e.g. on redis or disk
+    complete: bool,
+}
+
+// the maximum size of an admin request body
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+// the admin api, served on its own listener
+pub struct AdminApp {
+    token: Option<String>,
+    whitelist: Option<Vec<String>>,
+    quotas: Option<Arc<QuotaManager>>,
+    cache_purgers: Vec<CachePurger>,
+}
+
+impl AdminApp {
+    fn new(
+        config: &Admin,
+        quotas: Option<Arc<QuotaManager>>,
+        cache_purgers: Vec<CachePurger>,
+    ) -> Self {
+        AdminApp {
+            token: config.token.clone(),
+            whitelist: config.whitelist.clone(),
+            quotas,
+            cache_purgers,
+        }
+    }
+
+    // build the admin service listening on the configured address
+    pub fn new_service(
+        config: &Admin,
+        quotas: Option<Arc<QuotaManager>>,
+        cache_purgers: Vec<CachePurger>,
+    ) -> Service<AdminApp> {
+        let app = AdminApp::new(config, quotas, cache_purgers);
+        let mut service = Service::new("admin api".to_string(), app);
+        service.add_tcp(&config.address);
+        service
+    }
+
+    // check the token and the client address, the admin requests are refused when neither is configured
+    fn authorize(&self, http_session: &ServerSession) -> Result<(), Response<Vec<u8>>> {
+        if self.token.is_none() && self.whitelist.is_none() {
+            return Err(Self::error_response(
+                StatusCode::FORBIDDEN,
+                "Admin token or whitelist is not configured",
+            ));
+        }
+        if let Some(whitelist) = &self.whitelist {
+            // the peer address, the forwarded headers can be set by anyone
+            let address = http_session
+                .client_addr()
+                .and_then(|address| address.as_inet())
+                .map(|address| address.ip().to_string());
+            let allowed = address.is_some_and(|address| whitelist.contains(&address));
+            if !allowed {
+                return Err(Self::error_response(
+                    StatusCode::FORBIDDEN,
+                    "Restricted ip address",
+                ));
+            }
+        }
+        if let Some(token) = &self.token {
+            let authorized = http_session
+                .req_header()
+                .headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|value| constant_time_eq(value.trim(), token));
+            if !authorized {
+                let mut response =
+                    Self::error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Bearer"),
+                );
+                return Err(response);
+            }
+        }
+        Ok(())
+    }
+
+    // build a json response
+    fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Vec<u8>> {
+        let body = serde_json::to_vec(body).unwrap_or_default();
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap()
+    }
+
+    // build a json error response
+    fn error_response(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+        let body = AdminError {
+            status_code: status.as_u16(),
+            message: message.to_string(),
+        };
+        Self::json_response(status, &body)
+    }
+
+    // the prometheus metrics
+    fn metrics(&self) -> Response<Vec<u8>> {
+        let (body, content_type) = metrics::encode();
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap()
+    }
+
+    // read or reset the quota counters of a consumer
+    async fn quota(&self, method: &str, consumer: &str) -> Response<Vec<u8>> {
+        let Some(quotas) = &self.quotas else {
+            return Self::error_response(StatusCode::NOT_FOUND, "Quotas are not configured");
+        };
+        if method == "DELETE" {
+            return match quotas.reset(consumer).await {
+                Ok(true) => Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(vec![])
+                    .unwrap(),
+                Ok(false) => Self::error_response(StatusCode::NOT_FOUND, "Consumer has no quota"),
+                Err(e) => {
+                    warn!("unable to reset the quota of {}: {}", consumer, e);
+                    Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "Quota unavailable")
+                }
+            };
+        }
+        match quotas.get_usage(consumer).await {
+            Ok(Some(usage)) => {
+                let body = ConsumerQuota {
+                    consumer: consumer.to_string(),
+                    quotas: usage,
+                };
+                Self::json_response(StatusCode::OK, &body)
+            }
+            Ok(None) => Self::error_response(StatusCode::NOT_FOUND, "Consumer has no quota"),
+            Err(e) => {
+                warn!("unable to read the quota of {}: {}", consumer, e);
+                Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "Quota unavailable")
+            }
+        }
+    }
+
+    // purge the cached objects of one or every cluster
+    async fn purge_cache(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            match http_session.read_request_body().await {
+                Ok(Some(chunk)) if body.len() + chunk.len() <= MAX_BODY_SIZE => {
+                    body.extend_from_slice(&chunk)
+                }
+                Ok(Some(_)) => {
+                    return Self::error_response(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "Request body too large",
+                    )
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    return Self::error_response(StatusCode::BAD_REQUEST, "Invalid request body")
+                }
+            }
+        }
+        let request: CachePurge = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(_) => {
+                return Self::error_response(StatusCode::BAD_REQUEST, "Invalid purge request")
+            }
+        };
+        let purgers = self
+            .cache_purgers
+            .iter()
+            .filter(|purger| {
+                request
+                    .cluster
+                    .as_ref()
+                    .is_none_or(|cluster| purger.get_cluster() == cluster)
+            })
+            .collect::<Vec<_>>();
+        if request.cluster.is_some() && purgers.is_empty() {
+            return Self::error_response(StatusCode::NOT_FOUND, "Cluster has no cache");
+        }
+        let soft = request.soft.unwrap_or(false);
+        let mut purged = 0;
+        let mut complete = true;
+        for purger in purgers {
+            match purger.purge(&request.target, soft).await {
+                Ok((count, indexed)) => {
+                    purged += count;
+                    complete &= indexed;
+                }
+                Err(e) => {
+                    warn!(
+                        "unable to purge the cache of {}: {}",
+                        purger.get_cluster(),
+                        e
+                    );
+                    return Self::error_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Cache unavailable",
+                    );
+                }
+            }
+        }
+        info!("purged {} cached objects with {:?}", purged, request.target);
+        Self::json_response(StatusCode::OK, &CachePurged { purged, complete })
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req_header = http_session.req_header();
+        let method = req_header.method.as_str().to_string();
+        let path = req_header.uri.path().to_string();
+        if path != "/metrics" {
+            if let Err(response) = self.authorize(http_session) {
+                return response;
+            }
+        }
+        // route the admin request
+        match (method.as_str(), path.as_str()) {
+            ("GET", "/metrics") => self.metrics(),
+            ("POST", "/cache/purge") => self.purge_cache(http_session).await,
+            ("GET" | "DELETE", path) if path.starts_with("/quotas/") => {
+                let consumer = &path["/quotas/".len()..];
+                self.quota(&method, consumer).await
+            }
+            _ => Self::error_response(StatusCode::NOT_FOUND, "Path does not exist"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod admin_mod {
+    use super::*;
+    use crate::def::Cache;
+
+    use std::time::{Duration, SystemTime};
+
+    use pingora::cache::trace::Span;
+    use pingora::cache::{CacheKey, CacheMeta, Storage};
+    use pingora::http::ResponseHeader;
+    use tokio::io::AsyncWriteExt;
+
+    // the admin api with the memory cache of the svc cluster
+    fn build_app(config: &str) -> (AdminApp, &'static (dyn Storage + Sync)) {
+        let config: Admin = serde_yaml::from_str(config).unwrap();
+        let cache: Cache = serde_yaml::from_str(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\ntag_header: Surrogate-Key",
+        )
+        .unwrap();
+        let (bucket, policy) = cache.build_cache();
+        let bucket = bucket.unwrap();
+        let purger = CachePurger::with_bucket("svc", bucket, policy.unwrap()).unwrap();
+        (AdminApp::new(&config, None, vec![purger]), bucket.storage)
+    }
+
+    async fn put(storage: &'static (dyn Storage + Sync), uri: &str, tags: &str) {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header.insert_header("Surrogate-Key", tags).unwrap();
+        let now = SystemTime::now();
+        let meta = CacheMeta::new(now + Duration::from_secs(60), now, 0, 0, header);
+        let key = CacheKey::new("svc", uri, "");
+        let trace = Span::inactive().handle();
+        let mut handler = storage.get_miss_handler(&key, &meta, &trace).await.unwrap();
+        handler.write_body("body".into(), true).await.unwrap();
+        handler.finish().await.unwrap();
+    }
+
+    async fn send(app: &AdminApp, request: &str, body: &str) -> (u16, String) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let request = format!("{}Content-Length: {}\r\n\r\n{}", request, body.len(), body);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut session = ServerSession::new_http1(Box::new(server));
+        session.read_request().await.unwrap();
+        let response = app.response(&mut session).await;
+        let status = response.status().as_u16();
+        (status, String::from_utf8(response.into_body()).unwrap())
+    }
+
+    #[test]
+    fn cache_purge_test() {
+        let request: CachePurge =
+            serde_json::from_str(r#"{"cluster": "svc", "tag": "catalog", "soft": true}"#).unwrap();
+        assert_eq!(request.cluster.as_deref(), Some("svc"));
+        assert_eq!(request.soft, Some(true));
+        assert!(matches!(request.target, PurgeTarget::Tag { tag } if tag == "catalog"));
+        let request: CachePurge = serde_json::from_str(r#"{"url": "/items?id=1"}"#).unwrap();
+        assert!(request.cluster.is_none());
+        assert!(matches!(request.target, PurgeTarget::Url { url } if url == "/items?id=1"));
+        let request: CachePurge = serde_json::from_str(r#"{"cluster": "svc"}"#).unwrap();
+        assert!(matches!(request.target, PurgeTarget::All {}));
+        // a typo next to the cluster does not purge everything
+        assert!(serde_json::from_str::<CachePurge>(r#"{"cluster": "svc", "urls": "/"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn purge_test() {
+        let (app, storage) = build_app("address: 127.0.0.1:6189\ntoken: secret");
+        put(storage, "/items/1", "catalog").await;
+        put(storage, "/items/2", "catalog").await;
+        let purge = r#"{"cluster": "svc", "tag": "catalog"}"#;
+        let (status, _) = send(&app, "POST /cache/purge HTTP/1.1\r\n", purge).await;
+        assert_eq!(status, 401);
+        let authorized = "POST /cache/purge HTTP/1.1\r\nAuthorization: Bearer secret\r\n";
+        let (status, body) = send(&app, authorized, purge).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"purged":2,"complete":true}"#);
+        let (status, _) = send(&app, authorized, r#"{"cluster": "other"}"#).await;
+        assert_eq!(status, 404);
+        let (status, _) = send(&app, authorized, r#"{"urls": "/items/1"}"#).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn authorize_test() {
+        let (app, _) = build_app("address: 127.0.0.1:6189\ntoken: secret");
+        // the metrics are open
+        let (status, _) = send(&app, "GET /metrics HTTP/1.1\r\n", "").await;
+        assert_eq!(status, 200);
+        let (status, _) = send(&app, "DELETE /quotas/alice HTTP/1.1\r\n", "").await;
+        assert_eq!(status, 401);
+        let (status, _) = send(
+            &app,
+            "DELETE /quotas/alice HTTP/1.1\r\nAuthorization: Bearer other\r\n",
+            "",
+        )
+        .await;
+        assert_eq!(status, 401);
+        let authorized = "DELETE /quotas/alice HTTP/1.1\r\nAuthorization: Bearer secret\r\n";
+        let (status, _) = send(&app, authorized, "").await;
+        assert_eq!(status, 404);
+
+        // the admin requests are refused without a token nor a whitelist
+        let (app, _) = build_app("address: 127.0.0.1:6189");
+        let (status, _) = send(&app, "GET /quotas/alice HTTP/1.1\r\n", "").await;
+        assert_eq!(status, 403);
+        // the client address is not in the whitelist, the token alone is not enough then
+        let (app, _) =
+            build_app("address: 127.0.0.1:6189\ntoken: secret\nwhitelist: [\"127.0.0.1\"]");
+        let (status, _) = send(&app, authorized, "").await;
+        assert_eq!(status, 403);
+    }
+}
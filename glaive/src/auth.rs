@@ -1,242 +1,756 @@
-/**
- * Copyright (c) 2024-2025 Glaive, Inc.
- *
- * This file is part of Glaive Gateway
- *
- * This program is free software: you can redistribute it and/or modify
- * it under the terms of the GNU Affero General Public License as published by
- * the Free Software Foundation, either version 3 of the License, or
- * (at your option) any later version.
- *
- * This program is distributed in the hope that it will be useful
- * but WITHOUT ANY WARRANTY; without even the implied warranty of
- * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
- * GNU Affero General Public License for more details.
- *
- * You should have received a copy of the GNU Affero General Public License
- * along with this program.  If not, see <http://www.gnu.org/licenses/>.
- */
-
-use pingora::prelude::Session;
-
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use serde::{Deserialize, Serialize};
-
-use crate::cluster::ClusterMetadata;
-use crate::def::{Jwt, Key};
-use crate::proxy::{ProxyRouter, RouterCtx};
-use crate::response::ResponseProvider;
-
-// claim for jsonwebtoken
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    exp: usize,
-    consumer: String,
-}
-
-// error response body
-#[derive(Debug, Serialize, Deserialize)]
-struct Forbidden {
-    status_code: usize,
-    message: String,
-}
-
-pub struct AuthProvider {
-    response_provider: ResponseProvider,
-}
-
-impl AuthProvider {
-    // get auth provider
-    pub fn new() -> Self {
-        AuthProvider {
-            response_provider: ResponseProvider::new(),
-        }
-    }
-
-    // basic key auth
-    pub async fn basic_key(&self, key: &Key, session: &mut Session, _ctx: &mut RouterCtx) -> bool {
-        let req_header = session.req_header();
-        // get key from bearer headers
-        if let Some(creds) = req_header
-            .headers
-            .get("Authorization")
-            .map(|v| v.as_bytes())
-        {
-            // if key exist, parse key to str
-            if let Ok(creds_str) = std::str::from_utf8(creds) {
-                // clean key from headers
-                let cleaned_key = creds_str.trim_start_matches("Bearer ").trim();
-                // checks if key is allowed
-                match key.allowed.contains(&cleaned_key.to_string()) {
-                    true => false, // if key are contained, continue request
-                    false => {
-                        // return 403 because key is not provided/allowed
-                        let _ = &self
-                            .response_provider
-                            .error_response(session, 403, "Invalid API Key", None)
-                            .await;
-                        true
-                    }
-                }
-            } else {
-                // return 502, because this error happen during string parse
-                let _ = &self
-                    .response_provider
-                    .error_response(session, 502, "Unable to parse key", None)
-                    .await;
-                true
-            }
-        } else {
-            // return 403 because key does not exist in request
-            let _ = &self
-                .response_provider
-                .error_response(session, 403, "Key is required", None)
-                .await;
-            true
-        }
-    }
-
-    // basic jwt auth
-    // with authorization and acl
-    pub async fn basic_jwt(
-        &self,
-        gateway: &ProxyRouter,
-        cluster: &ClusterMetadata,
-        jwt: &Jwt,
-        session: &mut Session,
-        _ctx: &mut RouterCtx,
-    ) -> bool {
-        let req_header = session.req_header();
-        // get token from bearer headers
-        if let Some(token) = req_header
-            .headers
-            .get("Authorization")
-            .map(|v| v.as_bytes())
-        {
-            // parse token to str from utf8
-            if let Ok(parsed_token) = std::str::from_utf8(token) {
-                // remover the Bearer from the headers
-                let cleaned_token = parsed_token.trim_start_matches("Bearer ").trim();
-                // create the validator instance
-                let validation = Validation::new(Algorithm::HS256);
-                // get the payload as token_claim
-                let token_claim = decode::<Claims>(
-                    cleaned_token,
-                    &DecodingKey::from_secret(jwt.secret.as_ref()),
-                    &validation,
-                );
-                // checks if token is valid
-                match token_claim {
-                    Ok(claim) => {
-                        // the authentication ends here
-                        // the rest the code below will be the authorization process based on the defined consumers
-                        // get consumers if consumers is provided
-                        // if no consumers is provided, auth layer ends here
-                        if let Some(consumers) = cluster.get_consumers() {
-                            // checks if the consumer from token is allowed by the defined service consumer
-                            let (client_consumer_name, allowed_acl) = if let Some(consumer) =
-                                consumers.iter().find(|consumer| {
-                                    // Check if consumer token exists in the allowed list
-                                    let name = consumer.get_name();
-                                    claim.claims.consumer == *name
-                                }) {
-                                // If the consumer is found, return its name and ACL
-                                (consumer.get_name(), consumer.get_acl())
-                            } else {
-                                // If the consumer is not found, return a 403 response
-                                let _ = &self
-                                    .response_provider
-                                    .error_response(session, 403, "Unauthorized consumer", None)
-                                    .await;
-                                return true; // Indicate that the error response was handled
-                            };
-
-                            // checks if the gateway consumer data is provided
-                            if let Some(def_consumer) = &gateway.consumers {
-                                // if its provided, trying to query the acl by consumer name that we got previously
-                                let acl = if let Some(consumer) =
-                                    def_consumer.iter().find(|consumer| {
-                                        // Check if the consumer exists in the consumer gateway
-                                        let name = consumer.get_name();
-                                        client_consumer_name == name
-                                    }) {
-                                    // If the consumer is found, return the ACL
-                                    consumer.get_acl()
-                                } else {
-                                    // If the consumer is not found, return 502 and stop execution
-                                    let _ = &self
-                                        .response_provider
-                                        .error_response(session, 502, "Consumer not found", None)
-                                        .await;
-                                    return true;
-                                }; // we should return 502, because we are using consumer, but they are not defined on the gateway
-
-                                // checks if the acl we got from the consumer gateway config match the allowed acl in the service
-                                let authorized = acl.iter().any(|acl_item| {
-                                    // check if exist
-                                    allowed_acl
-                                        .iter()
-                                        .any(|allowed_acl_item| allowed_acl_item == acl_item)
-                                });
-                                // checks if the client is allowed by the acl
-                                if !authorized {
-                                    let _ = &self
-                                        .response_provider
-                                        .error_response(
-                                            session,
-                                            403,
-                                            "Unauthorized access control",
-                                            None,
-                                        )
-                                        .await;
-                                    true
-                                } else {
-                                    // when client is authorized, continue the request
-                                    println!("consumer is authorized");
-                                    false
-                                }
-                            } else {
-                                // return 502 because consumers is not defined in the gateway config
-                                let _ = &self
-                                    .response_provider
-                                    .error_response(session, 502, "Undefined consumer", None)
-                                    .await;
-                                true
-                            }
-                        } else {
-                            // continue the request when service disables authorization
-                            false
-                        }
-                    }
-                    Err(error) => {
-                        // return 403 due to invalid token
-                        let message = format!("Invalid Token: {}", error);
-                        let _ = &self
-                            .response_provider
-                            .error_response(session, 403, message.as_str(), None)
-                            .await;
-                        true
-                    }
-                }
-            } else {
-                // return 400 due to header parse error
-                let _ = &self
-                    .response_provider
-                    .error_response(session, 400, "Unable to parse token", None)
-                    .await;
-                true
-            }
-        } else {
-            // return 403 due to bearer does not exist in headers
-            let _ = &self
-                .response_provider
-                .error_response(session, 403, "Token is required", None)
-                .await;
-            true
-        }
-    }
-
-    // pub fn external() {
-    //
-    // }
-}
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+
+use pingora::http::RequestHeader;
+use pingora::prelude::Session;
+use pingora::Result as PingoraResult;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::HeaderValue;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::cluster::ClusterMetadata;
+use crate::credential::{constant_time_eq, BasicValidator, KeyLookup};
+use crate::def::{AuthType, Identity, Key, TokenSource};
+use crate::external::{ExternalAuthenticator, ExternalDecision};
+use crate::introspection::Introspector;
+use crate::jwt::JwtValidator;
+use crate::metrics;
+use crate::proxy::{ProxyRouter, RouterCtx};
+use crate::request::RequestProvider;
+use crate::response::ResponseProvider;
+use crate::signature::{BodyDigest, SignatureParams, SignatureValidator};
+
+// the challenges returned with the 401 responses
+const KEY_CHALLENGE: &str = "Key realm=\"glaive\"";
+const BEARER_CHALLENGE: &str = "Bearer";
+const HMAC_CHALLENGE: &str = "hmac";
+
+pub struct AuthProvider {
+    request_provider: RequestProvider,
+    response_provider: ResponseProvider,
+}
+
+impl AuthProvider {
+    // get auth provider
+    pub fn new() -> Self {
+        AuthProvider {
+            request_provider: RequestProvider::new(),
+            response_provider: ResponseProvider::new(),
+        }
+    }
+
+    // read the token from the first source providing it, the source is kept to strip the credential
+    // returns error when the token exists but unable to be parsed
+    fn get_token(
+        &self,
+        session: &Session,
+        ctx: &mut RouterCtx,
+        sources: &[TokenSource],
+    ) -> Option<Result<String, ()>> {
+        let req_header = session.req_header();
+        for source in sources {
+            let token = match source {
+                TokenSource::Header { header } => match req_header.headers.get(header.as_str()) {
+                    Some(value) => match value.to_str() {
+                        // remove the bearer scheme from the header
+                        Ok(value) => Some(
+                            value
+                                .strip_prefix("Bearer ")
+                                .or_else(|| value.strip_prefix("bearer "))
+                                .unwrap_or(value)
+                                .trim(),
+                        ),
+                        Err(_) => return Some(Err(())),
+                    },
+                    None => None,
+                },
+                TokenSource::Cookie { cookie } => {
+                    self.request_provider.get_cookie_value(session, cookie)
+                }
+                TokenSource::Query { query } => self
+                    .request_provider
+                    .get_origin_query_value(ctx.uri_origin.as_deref(), query),
+            };
+            if let Some(token) = token.filter(|token| !token.is_empty()) {
+                let token = token.to_string();
+                ctx.credential_source = Some(source.clone());
+                return Some(Ok(token));
+            }
+        }
+        None
+    }
+
+    // reject the unauthenticated request with the challenge of the auth type
+    async fn unauthorized(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+        reason: &str,
+        message: &str,
+        challenge: &str,
+    ) -> bool {
+        metrics::auth_failure(ctx, reason);
+        let mut challenge_header = HashMap::new();
+        challenge_header.insert("WWW-Authenticate", challenge);
+        let _ = &self
+            .response_provider
+            .error_response(session, ctx, 401, message, Some(challenge_header))
+            .await;
+        true
+    }
+
+    // reject the request of a cluster requiring an authenticated consumer
+    // the challenge is the one of the cluster auth type, the external auth has no standard scheme
+    pub async fn unauthenticated(
+        &self,
+        cluster: &ClusterMetadata,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        let challenge = match cluster.get_auth() {
+            Some(AuthType::Key { key: _ }) => Some(KEY_CHALLENGE.to_string()),
+            Some(AuthType::JWT { jwt: _ }) => Some(BEARER_CHALLENGE.to_string()),
+            Some(AuthType::OAuth2Introspection {
+                oauth2_introspection: _,
+            }) => Some(BEARER_CHALLENGE.to_string()),
+            Some(AuthType::Basic { basic: _ }) => cluster
+                .get_basic_validator()
+                .as_ref()
+                .map(|validator| validator.get_challenge()),
+            Some(AuthType::Hmac { hmac: _ }) => Some(HMAC_CHALLENGE.to_string()),
+            Some(AuthType::External { external: _ }) | None => None,
+        };
+        let message = "Authentication is required";
+        match challenge {
+            Some(challenge) => {
+                self.unauthorized(session, ctx, "unauthenticated", message, &challenge)
+                    .await
+            }
+            None => {
+                metrics::auth_failure(ctx, "unauthenticated");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 401, message, None)
+                    .await;
+                true
+            }
+        }
+    }
+
+    // basic key auth
+    // the key is bound to a consumer
+    pub async fn basic_key(
+        &self,
+        gateway: &ProxyRouter,
+        key: &Key,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // get key from the configured sources
+        if let Some(creds) = self.get_token(session, ctx, &key.get_sources()) {
+            // if key exist, checks if the key is parsed
+            if let Ok(cleaned_key) = creds {
+                // the legacy allowed keys are not bound to any consumer
+                // every allowed key is compared in constant time
+                if let Some(allowed) = &key.allowed {
+                    let matched = allowed.iter().fold(false, |matched, allowed| {
+                        constant_time_eq(allowed, &cleaned_key) | matched
+                    });
+                    if matched {
+                        return false;
+                    }
+                }
+                // find the consumer of the key
+                match gateway.consumers.get_keys().find(&cleaned_key).await {
+                    KeyLookup::Found(consumer_name) => {
+                        // keep the authenticated consumer for the access log and upstream
+                        ctx.consumer = Some(consumer_name.to_string());
+                        false
+                    }
+                    KeyLookup::Expired => {
+                        // return 401 because key is expired
+                        self.unauthorized(
+                            session,
+                            ctx,
+                            "expired_key",
+                            "Expired API Key",
+                            KEY_CHALLENGE,
+                        )
+                        .await
+                    }
+                    KeyLookup::NotFound => {
+                        // return 401 because key is not allowed
+                        self.unauthorized(
+                            session,
+                            ctx,
+                            "invalid_key",
+                            "Invalid API Key",
+                            KEY_CHALLENGE,
+                        )
+                        .await
+                    }
+                }
+            } else {
+                // return 400 due to header parse error
+                metrics::auth_failure(ctx, "malformed_key");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 400, "Unable to parse key", None)
+                    .await;
+                true
+            }
+        } else {
+            // return 401 because key does not exist in request
+            self.unauthorized(
+                session,
+                ctx,
+                "missing_key",
+                "Key is required",
+                KEY_CHALLENGE,
+            )
+            .await
+        }
+    }
+
+    // basic jwt auth
+    pub async fn basic_jwt(
+        &self,
+        validator: &JwtValidator,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // get token from the configured sources
+        if let Some(token) = self.get_token(session, ctx, validator.get_sources()) {
+            // checks if the token is parsed
+            if let Ok(cleaned_token) = token {
+                // validate the token signature and claims
+                let token_claim = validator.validate(&cleaned_token);
+                // checks if token is valid
+                match token_claim {
+                    Ok(claims) => {
+                        // get the consumer from the configured claim
+                        let consumer_name =
+                            validator.get_consumer(&claims).map(|name| name.to_string());
+                        // keep the authenticated consumer and claims for the access log and upstream
+                        ctx.consumer = consumer_name;
+                        ctx.claims = Some(claims);
+                        false
+                    }
+                    Err(error) => {
+                        // return 401 due to invalid token
+                        let message = format!("Invalid Token: {}", error);
+                        self.unauthorized(session, ctx, "invalid_token", &message, BEARER_CHALLENGE)
+                            .await
+                    }
+                }
+            } else {
+                // return 400 due to header parse error
+                metrics::auth_failure(ctx, "malformed_token");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 400, "Unable to parse token", None)
+                    .await;
+                true
+            }
+        } else {
+            // return 401 due to token does not exist in the request
+            self.unauthorized(
+                session,
+                ctx,
+                "missing_token",
+                "Token is required",
+                BEARER_CHALLENGE,
+            )
+            .await
+        }
+    }
+
+    // oauth 2.0 token introspection
+    pub async fn oauth2_introspection(
+        &self,
+        introspector: &Introspector,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // get token from the configured sources
+        let token = match self.get_token(session, ctx, introspector.get_sources()) {
+            Some(Ok(token)) => token,
+            Some(Err(_)) => {
+                // return 400 due to header parse error
+                metrics::auth_failure(ctx, "malformed_token");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 400, "Unable to parse token", None)
+                    .await;
+                return true;
+            }
+            None => {
+                // return 401 due to token does not exist in the request
+                return self
+                    .unauthorized(
+                        session,
+                        ctx,
+                        "missing_token",
+                        "Token is required",
+                        BEARER_CHALLENGE,
+                    )
+                    .await;
+            }
+        };
+        match introspector.introspect(&token).await {
+            Ok(Ok(claims)) => {
+                // keep the authenticated consumer and claims for the access log and upstream
+                ctx.consumer = introspector
+                    .get_consumer(&claims)
+                    .map(|name| name.to_string());
+                ctx.claims = Some(claims);
+                false
+            }
+            Ok(Err(error)) => {
+                // return 401 due to invalid token
+                let message = format!("Invalid Token: {}", error);
+                self.unauthorized(session, ctx, "invalid_token", &message, BEARER_CHALLENGE)
+                    .await
+            }
+            Err(e) => {
+                // return 502 because the introspection endpoint is not reachable
+                warn!("unable to introspect the token: {}", e);
+                metrics::auth_failure(ctx, "introspection_unavailable");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 502, "Unable to introspect token", None)
+                    .await;
+                true
+            }
+        }
+    }
+
+    // hmac request signature auth
+    // the body digest is verified once the body is read
+    pub async fn hmac_signature(
+        &self,
+        gateway: &ProxyRouter,
+        validator: &SignatureValidator,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // get the signature from the authorization header
+        let params = match session.req_header().headers.get("Authorization") {
+            Some(value) => value.to_str().ok().and_then(SignatureParams::parse),
+            None => {
+                // return 401 because signature does not exist in request
+                return self
+                    .unauthorized(
+                        session,
+                        ctx,
+                        "missing_signature",
+                        "Signature is required",
+                        HMAC_CHALLENGE,
+                    )
+                    .await;
+            }
+        };
+        // checks if the signature is parsed
+        let params = match params {
+            Some(params) => params,
+            None => {
+                // return 400 due to header parse error
+                metrics::auth_failure(ctx, "malformed_signature");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 400, "Unable to parse signature", None)
+                    .await;
+                return true;
+            }
+        };
+        // the signed path is the path requested by the client
+        let req_header = session.req_header();
+        let path = ctx
+            .uri_origin
+            .clone()
+            .unwrap_or_else(|| req_header.uri.to_string());
+        let verified = validator
+            .verify(gateway.consumers.get_secrets(), &params, req_header, &path)
+            .and_then(|consumer| {
+                // the digest header is compared with the body in the request body filter
+                match req_header.headers.get("digest") {
+                    Some(digest) => digest
+                        .to_str()
+                        .ok()
+                        .and_then(|digest| BodyDigest::parse(digest, validator.get_max_body_size()))
+                        .map(|digest| (consumer, Some(digest)))
+                        .ok_or_else(|| "unsupported digest".to_string()),
+                    None => Ok((consumer, None)),
+                }
+            });
+        match verified {
+            Ok((consumer_name, body_digest)) => {
+                // keep the authenticated consumer for the access log and upstream
+                ctx.consumer = Some(consumer_name);
+                ctx.body_digest = body_digest;
+                false
+            }
+            Err(error) => {
+                // return 401 due to invalid signature
+                let message = format!("Invalid Signature: {}", error);
+                self.unauthorized(session, ctx, "invalid_signature", &message, HMAC_CHALLENGE)
+                    .await
+            }
+        }
+    }
+
+    // http basic auth
+    pub async fn basic_auth(
+        &self,
+        gateway: &ProxyRouter,
+        validator: &BasicValidator,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // the challenge is returned on every 401 response
+        let challenge = validator.get_challenge();
+        // get the credentials from the authorization header
+        let credentials = match session.req_header().headers.get("Authorization") {
+            Some(value) => parse_basic_credentials(value),
+            None => {
+                // return 401 because credentials does not exist in request
+                return self
+                    .unauthorized(
+                        session,
+                        ctx,
+                        "missing_credentials",
+                        "Credentials are required",
+                        &challenge,
+                    )
+                    .await;
+            }
+        };
+        // checks if the credentials are parsed
+        let (username, password) = match credentials {
+            Some(credentials) => credentials,
+            None => {
+                // return 401 because the credentials are not basic or malformed
+                return self
+                    .unauthorized(
+                        session,
+                        ctx,
+                        "malformed_credentials",
+                        "Unable to parse credentials",
+                        &challenge,
+                    )
+                    .await;
+            }
+        };
+        // verify the credentials and get the consumer
+        match validator
+            .verify(gateway.consumers.get_passwords(), &username, &password)
+            .await
+        {
+            Some(consumer_name) => {
+                // keep the authenticated consumer for the access log and upstream
+                ctx.consumer = Some(consumer_name);
+                false
+            }
+            None => {
+                // return 401 due to invalid credentials
+                self.unauthorized(
+                    session,
+                    ctx,
+                    "invalid_credentials",
+                    "Invalid credentials",
+                    &challenge,
+                )
+                .await
+            }
+        }
+    }
+
+    // forward the authenticated identity to the upstream request
+    // the identity headers sent by the client are always removed to prevent spoofing
+    pub fn forward_identity(
+        &self,
+        identity: &Identity,
+        upstream_request: &mut RequestHeader,
+        ctx: &RouterCtx,
+    ) -> PingoraResult<()> {
+        let consumer_header = identity.get_consumer_header();
+        let groups_header = identity.get_groups_header();
+        upstream_request.remove_header(consumer_header);
+        upstream_request.remove_header(groups_header);
+        let claim_prefix = identity.get_claim_prefix().to_ascii_lowercase();
+        let spoofed_claims: Vec<String> = upstream_request
+            .headers
+            .keys()
+            .map(|name| name.as_str())
+            .filter(|name| name.starts_with(&claim_prefix))
+            .map(|name| name.to_string())
+            .collect();
+        for name in spoofed_claims {
+            upstream_request.remove_header(&name);
+        }
+        if let Some(consumer) = &ctx.consumer {
+            if let Ok(value) = HeaderValue::from_str(consumer) {
+                upstream_request.insert_header(consumer_header.to_string(), value)?;
+            }
+        }
+        if let Some(groups) = &ctx.consumer_groups {
+            if let Ok(value) = HeaderValue::from_str(&groups.join(",")) {
+                upstream_request.insert_header(groups_header.to_string(), value)?;
+            }
+        }
+        // forward the selected claims
+        if let Some(claims) = &identity.claims {
+            for claim in claims {
+                let header = format!("{}{}", identity.get_claim_prefix(), claim);
+                let value = match ctx.claims.as_ref().and_then(|claims| claims.get(claim)) {
+                    Some(value) => claim_to_header_value(value),
+                    None => continue,
+                };
+                // claims that are not a valid header value are skipped
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    upstream_request.insert_header(header, value)?;
+                }
+            }
+        }
+        // the upstream trusts the gateway instead of the client credentials
+        if identity.strip_authorization.unwrap_or(false) {
+            upstream_request.remove_header("Authorization");
+            self.strip_credential(upstream_request, ctx)?;
+        }
+        Ok(())
+    }
+
+    // remove the client credential from the header, cookie or query param it was read from
+    pub fn strip_credential(
+        &self,
+        upstream_request: &mut RequestHeader,
+        ctx: &RouterCtx,
+    ) -> PingoraResult<()> {
+        match &ctx.credential_source {
+            Some(TokenSource::Header { header }) => {
+                upstream_request.remove_header(header.as_str());
+            }
+            Some(TokenSource::Cookie { cookie }) => {
+                let cookies = upstream_request
+                    .headers
+                    .get("Cookie")
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| remove_cookie(value, cookie));
+                match cookies {
+                    Some(cookies) if !cookies.is_empty() => {
+                        upstream_request.insert_header("Cookie", cookies)?;
+                    }
+                    _ => {
+                        upstream_request.remove_header("Cookie");
+                    }
+                }
+            }
+            Some(TokenSource::Query { query }) => {
+                if let Some(params) = upstream_request.uri.query() {
+                    let params = remove_query_param(params, query);
+                    let path = upstream_request.uri.path();
+                    let uri = match params.is_empty() {
+                        true => path.to_string(),
+                        false => format!("{}?{}", path, params),
+                    };
+                    if let Ok(uri) = uri.parse::<http::Uri>() {
+                        upstream_request.set_uri(uri);
+                    }
+                }
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    // external forward auth
+    // the auth service decides, and may provide the consumer
+    pub async fn external(
+        &self,
+        authenticator: &ExternalAuthenticator,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // the headers copied from the auth service can not be sent by the client
+        for name in authenticator.get_upstream_headers() {
+            session.req_header_mut().remove_header(name);
+        }
+        let decision = authenticator
+            .check(
+                session.req_header(),
+                ctx.uri_origin.as_deref(),
+                ctx.client_address.as_deref(),
+            )
+            .await;
+        match decision.as_deref() {
+            Ok(ExternalDecision::Allow { headers, consumer }) => {
+                // copy the auth response headers to the upstream request
+                for (name, value) in headers {
+                    let _ = session
+                        .req_header_mut()
+                        .insert_header(name.clone(), value.clone());
+                }
+                // keep the authenticated consumer for the access log and upstream
+                ctx.consumer = consumer.clone();
+                false
+            }
+            Ok(ExternalDecision::Deny {
+                status,
+                headers,
+                body,
+            }) => {
+                // relay the auth service response to the client
+                metrics::auth_failure(ctx, "external_denied");
+                let _ = &self
+                    .response_provider
+                    .relay_response(session, ctx, *status, headers, body.clone())
+                    .await;
+                true
+            }
+            Err(e) => {
+                // return 502 because the auth service is not reachable
+                warn!("unable to reach the external auth service: {}", e);
+                metrics::auth_failure(ctx, "external_unavailable");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 502, "Unable to reach auth service", None)
+                    .await;
+                true
+            }
+        }
+    }
+}
+
+// remove the named cookie from the cookie header value
+pub fn remove_cookie(cookies: &str, name: &str) -> String {
+    cookies
+        .split(';')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .filter(|item| item.split_once('=').map_or(true, |(k, _)| k.trim() != name))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// remove the named param from the query string
+pub fn remove_query_param(query: &str, name: &str) -> String {
+    query
+        .split('&')
+        .filter(|item| !item.is_empty())
+        .filter(|item| item.split_once('=').map_or(*item, |(k, _)| k) != name)
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// format the claim value as a header value
+// arrays are joined by comma, objects are written as json
+pub fn claim_to_header_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(claim_to_header_value)
+            .collect::<Vec<_>>()
+            .join(","),
+        value => value.to_string(),
+    }
+}
+
+// parse the username and password of the basic authorization header
+fn parse_basic_credentials(value: &HeaderValue) -> Option<(String, String)> {
+    let value = value.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod auth_mod {
+    use super::*;
+
+    #[test]
+    fn strip_credential_test() {
+        let provider = AuthProvider::new();
+        let mut ctx = RouterCtx::new("x-request-id".to_string());
+        let mut req =
+            RequestHeader::build("GET", b"/product?page=2&access_token=abc", None).unwrap();
+        req.insert_header("Cookie", "theme=dark; session=abc")
+            .unwrap();
+        req.insert_header("X-Api-Key", "abc").unwrap();
+        // nothing is removed until a credential is read
+        provider.strip_credential(&mut req, &ctx).unwrap();
+        assert_eq!(req.uri.query(), Some("page=2&access_token=abc"));
+        ctx.credential_source = Some(TokenSource::Query {
+            query: "access_token".to_string(),
+        });
+        provider.strip_credential(&mut req, &ctx).unwrap();
+        assert_eq!(req.uri.to_string(), "/product?page=2");
+        ctx.credential_source = Some(TokenSource::Cookie {
+            cookie: "session".to_string(),
+        });
+        provider.strip_credential(&mut req, &ctx).unwrap();
+        assert_eq!(req.headers.get("Cookie").unwrap(), "theme=dark");
+        ctx.credential_source = Some(TokenSource::Header {
+            header: "X-Api-Key".to_string(),
+        });
+        provider.strip_credential(&mut req, &ctx).unwrap();
+        assert!(req.headers.get("X-Api-Key").is_none());
+    }
+
+    #[test]
+    fn basic_credentials_test() {
+        let value = HeaderValue::from_static("Basic dG9vbDpzZWNyZXQ6MQ==");
+        assert_eq!(
+            parse_basic_credentials(&value),
+            Some(("tool".to_string(), "secret:1".to_string()))
+        );
+        let value = HeaderValue::from_static("basic dG9vbDpzZWNyZXQ=");
+        assert!(parse_basic_credentials(&value).is_some());
+        // the other schemes and the malformed credentials are rejected
+        for value in [
+            "Bearer dG9vbDpzZWNyZXQ=",
+            "Basic",
+            "Basic !!!",
+            "Basic dG9vbA==",
+        ] {
+            assert!(parse_basic_credentials(&HeaderValue::from_static(value)).is_none());
+        }
+    }
+
+    #[test]
+    fn remove_param_test() {
+        assert_eq!(remove_cookie("session=abc", "session"), "");
+        assert_eq!(remove_cookie("a=1;session=abc; b=2", "session"), "a=1; b=2");
+        assert_eq!(remove_query_param("access_token=abc", "access_token"), "");
+        assert_eq!(
+            remove_query_param("a=1&access_token=abc&b", "access_token"),
+            "a=1&b"
+        );
+    }
+}
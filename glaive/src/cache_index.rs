@@ -0,0 +1,500 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use pingora::cache::key::{CacheHashKey, CacheKey, CompactCacheKey, HashBinary};
+use pingora::cache::storage::{HitHandler, MissHandler};
+use pingora::cache::trace::{Span, SpanHandle};
+use pingora::cache::{CacheMeta, PurgeType, Storage};
+use pingora::Result;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::bucket::CacheBucket;
+use crate::cache_policy::CachePolicy;
+use crate::cluster::ClusterMetadata;
+
+// the objects matched by a purge
+#[derive(Debug, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum PurgeTarget {
+    // the uri of the request, the query is normalized like the cache key
+    Url { url: String },
+    // every uri starting with the prefix
+    Prefix { prefix: String },
+    // every object with the surrogate key
+    Tag { tag: String },
+    // every object of the cluster
+    All {},
+}
+
+// an indexed cache object, the key is kept to find the object in the storage again
+struct IndexEntry {
+    key: CacheKey,
+    tags: Vec<String>,
+    // the insertion order, the oldest entries are dropped over the capacity
+    tick: u64,
+}
+
+#[derive(Default)]
+struct IndexState {
+    entries: HashMap<HashBinary, IndexEntry>,
+    tags: HashMap<String, HashSet<HashBinary>>,
+    order: BTreeMap<u64, HashBinary>,
+    tick: u64,
+    // an entry was dropped while its object may still be cached
+    overflowed: bool,
+}
+
+impl IndexState {
+    fn remove(&mut self, hash: &HashBinary) {
+        let Some(entry) = self.entries.remove(hash) else {
+            return;
+        };
+        self.order.remove(&entry.tick);
+        for tag in entry.tags {
+            if let Some(hashes) = self.tags.get_mut(&tag) {
+                hashes.remove(hash);
+                if hashes.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+// the index of the cached objects of a cluster by uri and surrogate key
+// the objects are indexed when they are written or found, and removed when they are purged
+// the index is kept in memory by every gateway, so it only knows the objects this instance has seen
+pub struct CacheIndex {
+    // the response header with the surrogate keys, e.g. Surrogate-Key or Cache-Tag
+    tag_header: Option<String>,
+    // the maximum number of entries, the oldest are dropped over it
+    capacity: usize,
+    // the storage keeps objects the index has not seen, e.g. written by other gateways or before a restart
+    persistent: bool,
+    state: Mutex<IndexState>,
+}
+
+impl CacheIndex {
+    pub fn new(tag_header: Option<String>, capacity: usize) -> Self {
+        CacheIndex {
+            tag_header,
+            capacity,
+            persistent: false,
+            state: Mutex::new(IndexState::default()),
+        }
+    }
+    // the storage is shared or kept across restarts
+    pub fn with_persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    // whether every cached object is indexed, otherwise a purge may miss some
+    fn is_complete(&self) -> bool {
+        !self.persistent && !self.state.lock().unwrap().overflowed
+    }
+
+    // the surrogate keys of the response, separated by space or comma
+    fn get_tags(&self, meta: &CacheMeta) -> Vec<String> {
+        let Some(tag_header) = &self.tag_header else {
+            return vec![];
+        };
+        meta.headers()
+            .get_all(tag_header.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split([' ', ',']))
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| tag.to_string())
+            .collect()
+    }
+
+    fn insert(&self, key: &CacheKey, meta: &CacheMeta) {
+        let hash = key.combined_bin();
+        let tags = self.get_tags(meta);
+        let mut state = self.state.lock().unwrap();
+        state.remove(&hash);
+        for tag in tags.iter() {
+            state.tags.entry(tag.clone()).or_default().insert(hash);
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.order.insert(tick, hash);
+        state.entries.insert(
+            hash,
+            IndexEntry {
+                key: key.clone(),
+                tags,
+                tick,
+            },
+        );
+        // the dropped objects stay cached until they expire or are evicted
+        while state.entries.len() > self.capacity {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+            state.overflowed = true;
+        }
+    }
+
+    fn contains(&self, key: &CacheKey) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&key.combined_bin())
+    }
+
+    fn remove(&self, hash: &HashBinary) {
+        self.state.lock().unwrap().remove(hash);
+    }
+
+    // the keys of the objects matching the target, the url is already normalized
+    fn find(&self, target: &PurgeTarget) -> Vec<CacheKey> {
+        let state = self.state.lock().unwrap();
+        // the uri is the first part of the primary key
+        let get_uri = |key: &CacheKey| -> String {
+            let primary = key.primary_key();
+            primary.split('\n').next().unwrap_or(primary).to_string()
+        };
+        match target {
+            PurgeTarget::Url { url } => state
+                .entries
+                .values()
+                .filter(|entry| &get_uri(&entry.key) == url)
+                .map(|entry| entry.key.clone())
+                .collect(),
+            PurgeTarget::Prefix { prefix } => state
+                .entries
+                .values()
+                .filter(|entry| get_uri(&entry.key).starts_with(prefix.as_str()))
+                .map(|entry| entry.key.clone())
+                .collect(),
+            PurgeTarget::Tag { tag } => state
+                .tags
+                .get(tag)
+                .into_iter()
+                .flatten()
+                .filter_map(|hash| state.entries.get(hash))
+                .map(|entry| entry.key.clone())
+                .collect(),
+            PurgeTarget::All {} => state
+                .entries
+                .values()
+                .map(|entry| entry.key.clone())
+                .collect(),
+        }
+    }
+}
+
+// the storage wrapper keeping the index of any cache storage
+pub struct IndexedStorage {
+    inner: &'static (dyn Storage + Sync),
+    index: &'static CacheIndex,
+}
+
+impl IndexedStorage {
+    pub fn new(inner: &'static (dyn Storage + Sync), index: &'static CacheIndex) -> Self {
+        IndexedStorage { inner, index }
+    }
+}
+
+#[async_trait]
+impl Storage for IndexedStorage {
+    // the objects found are indexed as well, e.g. the objects kept across restarts
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let hit = self.inner.lookup(key, trace).await?;
+        match &hit {
+            Some((meta, _)) if !self.index.contains(key) => self.index.insert(key, meta),
+            Some(_) => (),
+            None => self.index.remove(&key.combined_bin()),
+        }
+        Ok(hit)
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let handler = self.inner.get_miss_handler(key, meta, trace).await?;
+        self.index.insert(key, meta);
+        Ok(handler)
+    }
+
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        purge_type: PurgeType,
+        trace: &SpanHandle,
+    ) -> Result<bool> {
+        self.index.remove(&key.combined_bin());
+        self.inner.purge(key, purge_type, trace).await
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> Result<bool> {
+        self.inner.update_meta(key, meta, trace).await
+    }
+
+    fn support_streaming_partial_write(&self) -> bool {
+        self.inner.support_streaming_partial_write()
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+// the cache purge of a cluster, used by the admin api
+pub struct CachePurger {
+    cluster: String,
+    storage: &'static (dyn Storage + Sync),
+    index: &'static CacheIndex,
+    policy: Arc<CachePolicy>,
+}
+
+impl CachePurger {
+    pub fn new(cluster: &ClusterMetadata) -> Option<Self> {
+        Self::with_bucket(
+            cluster.get_name(),
+            (*cluster.get_cache_storage())?,
+            cluster.get_cache_policy().clone()?,
+        )
+    }
+
+    // the purge of an indexed cache bucket
+    pub fn with_bucket(
+        cluster: &str,
+        bucket: CacheBucket,
+        policy: Arc<CachePolicy>,
+    ) -> Option<Self> {
+        let CacheBucket { storage, index, .. } = bucket;
+        Some(CachePurger {
+            cluster: cluster.to_string(),
+            storage,
+            index: index?,
+            policy,
+        })
+    }
+
+    pub fn get_cluster(&self) -> &String {
+        &self.cluster
+    }
+
+    // purge the objects matching the target, a soft purge marks them stale instead
+    // returns the number of objects purged and whether the index held every cached object
+    pub async fn purge(&self, target: &PurgeTarget, soft: bool) -> Result<(usize, bool)> {
+        let target = match target {
+            PurgeTarget::Url { url } => PurgeTarget::Url {
+                url: self.policy.get_uri(url),
+            },
+            PurgeTarget::Prefix { prefix } => PurgeTarget::Prefix {
+                prefix: prefix.clone(),
+            },
+            PurgeTarget::Tag { tag } => PurgeTarget::Tag { tag: tag.clone() },
+            PurgeTarget::All {} => PurgeTarget::All {},
+        };
+        let trace = Span::inactive().handle();
+        let complete = self.index.is_complete();
+        let mut purged = 0;
+        for key in self.index.find(&target) {
+            let done = match soft {
+                true => self.expire(&key, &trace).await?,
+                false => {
+                    self.storage
+                        .purge(&key.to_compact(), PurgeType::Invalidation, &trace)
+                        .await?
+                }
+            };
+            if done {
+                purged += 1;
+            }
+        }
+        Ok((purged, complete))
+    }
+
+    // mark the object stale, it is then served stale or revalidated like any expired object
+    async fn expire(&self, key: &CacheKey, trace: &SpanHandle) -> Result<bool> {
+        let Some((meta, _)) = self.storage.lookup(key, trace).await? else {
+            return Ok(false);
+        };
+        let now = SystemTime::now();
+        if !meta.is_fresh(now) {
+            return Ok(true);
+        }
+        let mut stale = CacheMeta::new(
+            now - Duration::from_secs(1),
+            meta.created(),
+            meta.stale_while_revalidate_sec(),
+            meta.stale_if_error_sec(),
+            meta.response_header_copy(),
+        );
+        if let Some(variance) = meta.variance() {
+            stale.set_variance(variance);
+        }
+        self.storage.update_meta(key, &stale, trace).await
+    }
+}
+
+#[cfg(test)]
+mod cache_index_mod {
+    use super::*;
+    use crate::cache::MemoryStorage;
+    use pingora::http::ResponseHeader;
+
+    fn meta(tags: &str) -> CacheMeta {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header.insert_header("Surrogate-Key", tags).unwrap();
+        let now = SystemTime::now();
+        CacheMeta::new(now + Duration::from_secs(60), now, 0, 0, header)
+    }
+
+    async fn put(storage: &'static IndexedStorage, uri: &str, tags: &str) -> CacheKey {
+        let key = CacheKey::new("svc", uri, "");
+        let trace = Span::inactive().handle();
+        let mut handler = storage
+            .get_miss_handler(&key, &meta(tags), &trace)
+            .await
+            .unwrap();
+        handler.write_body("body".into(), true).await.unwrap();
+        handler.finish().await.unwrap();
+        key
+    }
+
+    #[tokio::test]
+    async fn purge_test() {
+        let memory: &'static MemoryStorage = Box::leak(Box::new(MemoryStorage::new()));
+        let index: &'static CacheIndex = Box::leak(Box::new(CacheIndex::new(
+            Some("Surrogate-Key".to_string()),
+            16,
+        )));
+        let storage: &'static IndexedStorage =
+            Box::leak(Box::new(IndexedStorage::new(memory, index)));
+        let config = serde_yaml::from_str(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\nkey:\n  query:\n    sort: true",
+        )
+        .unwrap();
+        let purger = CachePurger {
+            cluster: "svc".to_string(),
+            storage,
+            index,
+            policy: Arc::new(CachePolicy::new(&config, 10)),
+        };
+        let trace = Span::inactive().handle();
+        let first = put(storage, "/items/1?a=1&b=2", "catalog item-1").await;
+        let second = put(storage, "/items/2", "catalog, item-2").await;
+        let third = put(storage, "/users/1", "user-1").await;
+
+        // the url is normalized like the key
+        let target = serde_json::from_str(r#"{"url": "/items/1?b=2&a=1"}"#).unwrap();
+        assert_eq!(purger.purge(&target, false).await.unwrap(), (1, true));
+        assert!(storage.lookup(&first, &trace).await.unwrap().is_none());
+
+        // the soft purge keeps the object stale
+        let target = serde_json::from_str(r#"{"tag": "catalog"}"#).unwrap();
+        assert_eq!(purger.purge(&target, true).await.unwrap(), (1, true));
+        let (meta, _) = storage.lookup(&second, &trace).await.unwrap().unwrap();
+        assert!(!meta.is_fresh(SystemTime::now()));
+
+        let target = serde_json::from_str(r#"{"prefix": "/users/"}"#).unwrap();
+        assert_eq!(purger.purge(&target, false).await.unwrap(), (1, true));
+        assert!(storage.lookup(&third, &trace).await.unwrap().is_none());
+
+        // a typo does not purge everything
+        assert!(serde_json::from_str::<PurgeTarget>(r#"{"urls": "/items/2"}"#).is_err());
+        let target = serde_json::from_str("{}").unwrap();
+        assert_eq!(purger.purge(&target, false).await.unwrap(), (1, true));
+        assert!(storage.lookup(&second, &trace).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn capacity_test() {
+        let memory: &'static MemoryStorage = Box::leak(Box::new(MemoryStorage::new()));
+        let index: &'static CacheIndex = Box::leak(Box::new(CacheIndex::new(
+            Some("Surrogate-Key".to_string()),
+            2,
+        )));
+        let storage: &'static IndexedStorage =
+            Box::leak(Box::new(IndexedStorage::new(memory, index)));
+        let config = serde_yaml::from_str(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1",
+        )
+        .unwrap();
+        let purger = CachePurger {
+            cluster: "svc".to_string(),
+            storage,
+            index,
+            policy: Arc::new(CachePolicy::new(&config, 10)),
+        };
+        let trace = Span::inactive().handle();
+        put(storage, "/items/1", "catalog").await;
+        put(storage, "/items/2", "catalog").await;
+        assert_eq!(
+            purger
+                .purge(
+                    &PurgeTarget::Tag {
+                        tag: "catalog".to_string()
+                    },
+                    false
+                )
+                .await
+                .unwrap(),
+            (2, true)
+        );
+        // the oldest entry is dropped over the capacity, its object is still cached
+        let first = put(storage, "/items/1", "catalog").await;
+        put(storage, "/items/2", "catalog").await;
+        put(storage, "/items/3", "catalog").await;
+        assert_eq!(index.state.lock().unwrap().entries.len(), 2);
+        assert_eq!(
+            purger
+                .purge(
+                    &PurgeTarget::Tag {
+                        tag: "catalog".to_string()
+                    },
+                    false
+                )
+                .await
+                .unwrap(),
+            (2, false)
+        );
+        assert!(storage.lookup(&first, &trace).await.unwrap().is_some());
+        // the persistent storage may hold objects written elsewhere
+        let index = CacheIndex::new(None, 2).with_persistent(true);
+        assert!(!index.is_complete());
+    }
+}
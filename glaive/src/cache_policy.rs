@@ -0,0 +1,497 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::time::{Duration, SystemTime};
+
+use pingora::cache::cache_control::{CacheControl, Cacheable, InterpretCacheControl};
+use pingora::cache::filters::calculate_expires_header_time;
+use pingora::cache::key::HashBinary;
+use pingora::cache::{CacheKey, CacheMeta, NoCacheReason, RespCacheable, VarianceBuilder};
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::def;
+use crate::def::Cache;
+
+// the status codes cached by default, the heuristically cacheable codes of rfc 9111
+const DEFAULT_CACHE_STATUSES: [u16; 7] = [200, 203, 300, 301, 308, 404, 410];
+
+// the cacheability rules of a cluster cache
+#[derive(Debug)]
+pub struct CachePolicy {
+    // the freshness in seconds when the upstream sends no cache-control nor expires
+    default_ttl: u32,
+    statuses: Vec<u16>,
+    allow_set_cookie: bool,
+    allow_authorization: bool,
+    key: CacheKeyRule,
+    // the stale seconds when the upstream cache-control has none
+    stale_while_revalidate: u32,
+    stale_if_error: u32,
+}
+
+// the parts of the cache key besides the cluster name and the path
+#[derive(Debug, Default)]
+struct CacheKeyRule {
+    // the query params kept and dropped, a trailing * matches the name prefix
+    include: Option<Vec<String>>,
+    exclude: Vec<String>,
+    sort: bool,
+    // the lowercase request header names
+    headers: Vec<String>,
+    consumer: bool,
+}
+
+impl CacheKeyRule {
+    fn new(config: &def::CacheKey) -> Self {
+        let query = config.query.as_ref();
+        CacheKeyRule {
+            include: query.and_then(|query| query.include.clone()),
+            exclude: query
+                .and_then(|query| query.exclude.clone())
+                .unwrap_or_default(),
+            sort: query.and_then(|query| query.sort).unwrap_or(false),
+            headers: config
+                .headers
+                .iter()
+                .flatten()
+                .map(|name| name.to_lowercase())
+                .collect(),
+            consumer: config.consumer.unwrap_or(false),
+        }
+    }
+
+    // the query string with the params of the key
+    fn get_query(&self, query: &str) -> String {
+        let mut params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or_default();
+                let included = match &self.include {
+                    Some(include) => include.iter().any(|pattern| match_param(pattern, name)),
+                    None => true,
+                };
+                included
+                    && !self
+                        .exclude
+                        .iter()
+                        .any(|pattern| match_param(pattern, name))
+            })
+            .collect::<Vec<_>>();
+        if self.sort {
+            params.sort();
+        }
+        params.join("&")
+    }
+}
+
+// match the query param name with the exact name or the prefix ending with *
+fn match_param(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+impl CachePolicy {
+    pub fn new(config: &Cache, default_ttl: usize) -> Self {
+        CachePolicy {
+            default_ttl: default_ttl as u32,
+            statuses: config
+                .statuses
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CACHE_STATUSES.to_vec()),
+            allow_set_cookie: config.allow_set_cookie.unwrap_or(false),
+            allow_authorization: config.allow_authorization.unwrap_or(false),
+            key: config
+                .key
+                .as_ref()
+                .map(CacheKeyRule::new)
+                .unwrap_or_default(),
+            stale_while_revalidate: config.stale_while_revalidate.unwrap_or(0),
+            stale_if_error: config.stale_if_error.unwrap_or(0),
+        }
+    }
+
+    // the uri part of the cache key, with the query params of the key
+    pub fn get_uri(&self, uri: &str) -> String {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let query = self.key.get_query(query);
+        match query.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, query),
+        }
+    }
+
+    // build the cache key of the request, the uri is the one before any rewrite
+    pub fn get_key(
+        &self,
+        cluster: &str,
+        uri: &str,
+        req: &RequestHeader,
+        consumer: Option<&str>,
+    ) -> CacheKey {
+        let mut primary = self.get_uri(uri);
+        // the header values can not have a line break, so the parts are apart
+        for name in self.key.headers.iter() {
+            let value = req
+                .headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            primary.push_str(&format!("\n{}: {}", name, value));
+        }
+        if self.key.consumer {
+            primary.push_str(&format!("\nconsumer: {}", consumer.unwrap_or_default()));
+        }
+        CacheKey::new(cluster, primary, "")
+    }
+
+    // the response is private to the consumer when the consumer is part of the key
+    pub fn is_private(&self, consumer: Option<&str>) -> bool {
+        self.key.consumer && consumer.is_some()
+    }
+
+    // decide if the response is cacheable and until when it is fresh
+    // a private response is only served to the same consumer, so private and authorization are allowed
+    pub fn response_cacheable(
+        &self,
+        resp: &ResponseHeader,
+        authenticated: bool,
+        private: bool,
+    ) -> RespCacheable {
+        let uncacheable = RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+        if !self.statuses.contains(&resp.status.as_u16()) {
+            return uncacheable;
+        }
+        // the cookies are most likely personal
+        if !self.allow_set_cookie && resp.headers.contains_key(http::header::SET_COOKIE) {
+            return uncacheable;
+        }
+        let cc = CacheControl::from_resp_headers(resp);
+        // a response to an authorized request is only shared when the upstream says so
+        if authenticated
+            && !self.allow_authorization
+            && !private
+            && !cc
+                .as_ref()
+                .is_some_and(|cc| cc.allow_caching_authorized_req())
+        {
+            return uncacheable;
+        }
+        let cacheable = cc.as_ref().is_none_or(|cc| match private {
+            true => !cc.no_store(),
+            false => cc.is_cacheable() != Cacheable::No,
+        });
+        if !cacheable {
+            return uncacheable;
+        }
+        // the variants can not be told apart by any request header
+        if get_vary(resp).iter().any(|name| name == "*") {
+            return uncacheable;
+        }
+        // the ttl is taken from the cache-control, the expires and then the cluster default
+        let now = SystemTime::now();
+        let fresh_until = match cc.as_ref().and_then(|cc| cc.fresh_sec()) {
+            // always stale, the entry is revalidated before use
+            Some(0) => now - Duration::from_secs(1),
+            Some(ttl) => now + Duration::from_secs(ttl as u64),
+            None => calculate_expires_header_time(resp)
+                .unwrap_or_else(|| now + Duration::from_secs(self.default_ttl as u64)),
+        };
+        let stale_while_revalidate = cc
+            .as_ref()
+            .and_then(|cc| cc.serve_stale_while_revalidate_sec())
+            .unwrap_or(self.stale_while_revalidate);
+        let stale_if_error = cc
+            .as_ref()
+            .and_then(|cc| cc.serve_stale_if_error_sec())
+            .unwrap_or(self.stale_if_error);
+        let mut header = resp.clone();
+        if let Some(cc) = &cc {
+            cc.strip_private_headers(&mut header);
+        }
+        RespCacheable::Cacheable(CacheMeta::new(
+            fresh_until,
+            now,
+            stale_while_revalidate,
+            stale_if_error,
+            header,
+        ))
+    }
+}
+
+// the request is authorized by the authorization header, the credentials found by the gateway or a consumer
+pub fn request_authenticated(
+    req: &RequestHeader,
+    consumer: Option<&str>,
+    credentials: bool,
+) -> bool {
+    credentials || consumer.is_some() || req.headers.contains_key(http::header::AUTHORIZATION)
+}
+
+// the request asks not to use the cache at all
+pub fn request_no_store(req: &RequestHeader) -> bool {
+    CacheControl::from_req_headers(req).is_some_and(|cc| cc.no_store())
+}
+
+// the request asks to revalidate a fresh entry, by no-cache or a max-age below its age
+pub fn request_expired(req: &RequestHeader, meta: &CacheMeta) -> bool {
+    match CacheControl::from_req_headers(req) {
+        Some(cc) if cc.no_cache() => true,
+        Some(cc) => match cc.max_age() {
+            Ok(Some(max_age)) => meta.age() > Duration::from_secs(max_age as u64),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+// the secondary key of the response, built from the request headers named by its vary
+pub fn get_variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+    let names = get_vary(meta.response_header());
+    let mut variance = VarianceBuilder::new();
+    for name in names.iter() {
+        let value = req
+            .headers
+            .get(name.as_str())
+            .map(|value| value.as_bytes())
+            .unwrap_or_default();
+        variance.add_value(name, value);
+    }
+    variance.finalize()
+}
+
+// the lowercase header names of the vary response header
+fn get_vary(resp: &ResponseHeader) -> Vec<String> {
+    resp.headers
+        .get_all(http::header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod cache_policy_mod {
+    use super::*;
+    use pingora::cache::key::CacheHashKey;
+
+    fn build_policy(config: &str) -> CachePolicy {
+        let config: Cache = serde_yaml::from_str(config).unwrap();
+        CachePolicy::new(&config, 10)
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(status, None).unwrap();
+        for (name, value) in headers {
+            resp.insert_header(name.to_string(), value.to_string())
+                .unwrap();
+        }
+        resp
+    }
+
+    fn fresh_sec(cacheable: RespCacheable) -> Option<u64> {
+        match cacheable {
+            RespCacheable::Cacheable(meta) => Some(meta.fresh_sec()),
+            RespCacheable::Uncacheable(_) => None,
+        }
+    }
+
+    #[test]
+    fn response_test() {
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1",
+        );
+        // the cluster ttl is only the default
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&response(200, &[]), false, false)),
+            Some(10)
+        );
+        let resp = response(200, &[("Cache-Control", "max-age=60, s-maxage=30")]);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, false, false)),
+            Some(30)
+        );
+        let resp = response(200, &[("Cache-Control", "private, max-age=60")]);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, false, false)),
+            None
+        );
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&response(500, &[]), false, false)),
+            None
+        );
+        let resp = response(200, &[("Set-Cookie", "session=1")]);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, false, false)),
+            None
+        );
+        // the authorized requests are only cached by a public response
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&response(200, &[]), true, false)),
+            None
+        );
+        let resp = response(200, &[("Cache-Control", "public, max-age=60")]);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, true, false)),
+            Some(60)
+        );
+        let resp = response(200, &[("Vary", "*")]);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, false, false)),
+            None
+        );
+
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\nstatuses: [500]\nallow_set_cookie: true",
+        );
+        let resp = response(500, &[("Set-Cookie", "session=1")]);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, false, false)),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn authenticated_test() {
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1",
+        );
+        let req = RequestHeader::build("GET", b"/?api_key=secret", None).unwrap();
+        assert!(!request_authenticated(&req, None, false));
+        // the credentials from the query, a cookie or another header
+        assert!(request_authenticated(&req, None, true));
+        assert!(request_authenticated(&req, Some("alice"), false));
+        let mut authorized = RequestHeader::build("GET", b"/", None).unwrap();
+        authorized
+            .insert_header("Authorization", "Bearer token")
+            .unwrap();
+        assert!(request_authenticated(&authorized, None, false));
+        // the consumer response is not shared when the consumer is not in the key
+        let authenticated = request_authenticated(&req, Some("alice"), false);
+        let private = policy.is_private(Some("alice"));
+        assert!(!private);
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&response(200, &[]), authenticated, private)),
+            None
+        );
+        // it is only served to the same consumer otherwise
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\nkey:\n  consumer: true",
+        );
+        let private = policy.is_private(Some("alice"));
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&response(200, &[]), authenticated, private)),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn stale_test() {
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\nstale_while_revalidate: 30\nstale_if_error: 60",
+        );
+        let stale_sec = |resp: &ResponseHeader| match policy.response_cacheable(resp, false, false)
+        {
+            RespCacheable::Cacheable(meta) => {
+                (meta.stale_while_revalidate_sec(), meta.stale_if_error_sec())
+            }
+            RespCacheable::Uncacheable(_) => panic!("response not cacheable"),
+        };
+        // the cluster durations are the default
+        assert_eq!(stale_sec(&response(200, &[])), (30, 60));
+        let resp = response(
+            200,
+            &[("Cache-Control", "max-age=1, stale-while-revalidate=5")],
+        );
+        assert_eq!(stale_sec(&resp), (5, 60));
+        // the upstream forbids serving stale
+        let resp = response(200, &[("Cache-Control", "max-age=1, must-revalidate")]);
+        assert_eq!(stale_sec(&resp), (0, 0));
+    }
+
+    #[test]
+    fn variance_test() {
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1",
+        );
+        let resp = response(200, &[("Vary", "Accept-Encoding, Accept-Language")]);
+        let meta = match policy.response_cacheable(&resp, false, false) {
+            RespCacheable::Cacheable(meta) => meta,
+            RespCacheable::Uncacheable(_) => panic!("response not cacheable"),
+        };
+        let mut english = RequestHeader::build("GET", b"/", None).unwrap();
+        english.insert_header("Accept-Language", "en").unwrap();
+        let mut french = RequestHeader::build("GET", b"/", None).unwrap();
+        french.insert_header("Accept-Language", "fr").unwrap();
+        assert!(get_variance(&meta, &english).is_some());
+        assert_ne!(get_variance(&meta, &english), get_variance(&meta, &french));
+        // no variance without vary
+        let meta = match policy.response_cacheable(&response(200, &[]), false, false) {
+            RespCacheable::Cacheable(meta) => meta,
+            RespCacheable::Uncacheable(_) => panic!("response not cacheable"),
+        };
+        assert!(get_variance(&meta, &english).is_none());
+    }
+
+    #[test]
+    fn key_test() {
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\nkey:\n  query:\n    exclude: [\"utm_*\"]\n    sort: true\n  headers: [\"Accept-Language\"]\n  consumer: true",
+        );
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let key = |uri: &str, consumer: Option<&str>| {
+            policy.get_key("svc", uri, &req, consumer).combined()
+        };
+        // the params are sorted and the tracking params are dropped
+        assert_eq!(
+            key("/a?x=1&y=2", None),
+            key("/a?y=2&utm_source=mail&x=1", None)
+        );
+        assert_ne!(key("/a?x=1", None), key("/a?x=2", None));
+        assert_ne!(key("/a", Some("alice")), key("/a", Some("bob")));
+        let mut french = RequestHeader::build("GET", b"/", None).unwrap();
+        french.insert_header("Accept-Language", "fr").unwrap();
+        assert_ne!(
+            policy.get_key("svc", "/a", &french, None).combined(),
+            key("/a", None)
+        );
+        // the private responses are cached per consumer
+        let resp = response(200, &[("Cache-Control", "private, max-age=60")]);
+        assert!(policy.is_private(Some("alice")));
+        assert_eq!(
+            fresh_sec(policy.response_cacheable(&resp, true, true)),
+            Some(60)
+        );
+
+        let policy = build_policy(
+            "memory:\n  cache_ttl: 10\n  max_size: 1\n  max_cache: 1\n  lock_timeout: 1\nkey:\n  query:\n    include: [\"page\"]",
+        );
+        assert_eq!(
+            policy
+                .get_key("svc", "/a?page=1&q=x", &req, None)
+                .combined(),
+            policy.get_key("svc", "/a?page=1", &req, None).combined()
+        );
+        assert!(!policy.is_private(Some("alice")));
+    }
+}
@@ -0,0 +1,285 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use redis::RedisError;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::credential::sha256_hex;
+use crate::def::{Concurrency, FailureMode, LimitKey, MissingKey};
+use crate::redis::RedisConnection;
+
+// the semaphores of the keys, removed once every slot of the key is free
+type Semaphores = Arc<Mutex<HashMap<String, Arc<Semaphore>>>>;
+
+// the slots of a concurrency limiter
+enum ConcurrencyBackend {
+    // counted by this gateway only
+    Memory(Semaphores),
+    // counted by every gateway sharing the redis
+    Redis {
+        connection: Arc<RedisConnection>,
+        prefix: String,
+        lease: Duration,
+        failure_mode: FailureMode,
+    },
+}
+
+// a concurrency limiter instance, caps the in-flight requests of a cluster or a route
+pub struct ConcurrencyLimiter {
+    namespace: String,
+    limit: usize,
+    status: usize,
+    key: Option<Vec<LimitKey>>,
+    missing_key: MissingKey,
+    // the queue size and timeout, the requests over the limit are rejected without a queue
+    queue: Option<(usize, Duration)>,
+    waiting: AtomicUsize,
+    backend: ConcurrencyBackend,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(namespace: String, config: &Concurrency) -> Self {
+        let status = config.status.unwrap_or(503);
+        if status != 429 && status != 503 {
+            panic!("invalid concurrency status {}, expected 429 or 503", status);
+        }
+        let backend = match &config.redis {
+            Some(redis) => ConcurrencyBackend::Redis {
+                connection: Arc::new(RedisConnection::new(redis).unwrap_or_else(|e| {
+                    panic!("invalid concurrency redis url {}: {}", redis.url, e)
+                })),
+                prefix: redis.get_prefix(),
+                lease: Duration::from_secs(config.lease.unwrap_or(60)),
+                failure_mode: config.failure_mode.unwrap_or(FailureMode::Open),
+            },
+            None => ConcurrencyBackend::Memory(Arc::new(Mutex::new(HashMap::new()))),
+        };
+        ConcurrencyLimiter {
+            namespace,
+            limit: config.limit,
+            status,
+            key: config.key.clone(),
+            missing_key: config.missing_key.unwrap_or(MissingKey::Skip),
+            queue: config.queue.as_ref().map(|queue| {
+                (
+                    queue.size,
+                    Duration::from_millis(queue.timeout.unwrap_or(1000)),
+                )
+            }),
+            waiting: AtomicUsize::new(0),
+            backend,
+        }
+    }
+
+    // take a slot for the key, waiting in the queue when every slot is taken
+    // returns none when the request is over the limit
+    pub async fn acquire(&self, key: &str) -> Result<Option<ConcurrencyPermit>, RedisError> {
+        match &self.backend {
+            ConcurrencyBackend::Memory(semaphores) => {
+                let semaphore = semaphores
+                    .lock()
+                    .unwrap()
+                    .entry(key.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+                    .clone();
+                let permit = match semaphore.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => match self.enqueue() {
+                        Some(timeout) => {
+                            let permit =
+                                tokio::time::timeout(timeout, semaphore.acquire_owned()).await;
+                            self.waiting.fetch_sub(1, Ordering::Relaxed);
+                            permit.ok().and_then(|permit| permit.ok())
+                        }
+                        None => None,
+                    },
+                };
+                Ok(permit.map(|permit| ConcurrencyPermit {
+                    slot: Slot::Memory {
+                        permit: Some(permit),
+                        semaphores: semaphores.clone(),
+                        key: key.to_string(),
+                    },
+                }))
+            }
+            ConcurrencyBackend::Redis {
+                connection,
+                prefix,
+                lease,
+                ..
+            } => {
+                // the key is hashed, so the credentials are never stored in redis
+                let redis_key = format!(
+                    "{}:concurrency:{}:{}",
+                    prefix,
+                    self.namespace,
+                    sha256_hex(key)
+                );
+                let id = uuid::Uuid::now_v7().to_string();
+                let limit = self.limit as i64;
+                let mut acquired = connection
+                    .acquire_slot(&redis_key, limit, *lease, &id)
+                    .await?;
+                if !acquired {
+                    if let Some(timeout) = self.enqueue() {
+                        // the slots are polled until one is freed by any gateway
+                        let deadline = Instant::now() + timeout;
+                        let mut result = Ok(());
+                        while !acquired && Instant::now() < deadline {
+                            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+                            match connection
+                                .acquire_slot(&redis_key, limit, *lease, &id)
+                                .await
+                            {
+                                Ok(slot) => acquired = slot,
+                                Err(e) => {
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                        }
+                        self.waiting.fetch_sub(1, Ordering::Relaxed);
+                        result?;
+                    }
+                }
+                Ok(acquired.then(|| ConcurrencyPermit {
+                    slot: Slot::Redis {
+                        connection: connection.clone(),
+                        key: redis_key,
+                        id,
+                    },
+                }))
+            }
+        }
+    }
+
+    // join the queue, returns the queue timeout when the queue is not full
+    fn enqueue(&self) -> Option<Duration> {
+        let (size, timeout) = self.queue?;
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= size {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(timeout)
+    }
+
+    pub fn get_status(&self) -> usize {
+        self.status
+    }
+    pub fn get_key(&self) -> &Option<Vec<LimitKey>> {
+        &self.key
+    }
+    pub fn get_missing_key(&self) -> MissingKey {
+        self.missing_key
+    }
+    pub fn get_failure_mode(&self) -> FailureMode {
+        match &self.backend {
+            ConcurrencyBackend::Memory(_) => FailureMode::Open,
+            ConcurrencyBackend::Redis { failure_mode, .. } => *failure_mode,
+        }
+    }
+}
+
+// the interval the redis slots are polled while queued
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// the slot held by a request
+enum Slot {
+    Memory {
+        permit: Option<OwnedSemaphorePermit>,
+        semaphores: Semaphores,
+        key: String,
+    },
+    Redis {
+        connection: Arc<RedisConnection>,
+        key: String,
+        id: String,
+    },
+}
+
+// a taken slot, the slot is released when the permit is dropped
+pub struct ConcurrencyPermit {
+    slot: Slot,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        match &mut self.slot {
+            Slot::Memory {
+                permit,
+                semaphores,
+                key,
+            } => {
+                drop(permit.take());
+                // the semaphore is only referenced by the map once every slot is free
+                let mut semaphores = semaphores.lock().unwrap();
+                if semaphores
+                    .get(key.as_str())
+                    .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+                {
+                    semaphores.remove(key.as_str());
+                }
+            }
+            Slot::Redis {
+                connection,
+                key,
+                id,
+            } => {
+                let connection = connection.clone();
+                let key = std::mem::take(key);
+                let id = std::mem::take(id);
+                tokio::spawn(async move {
+                    if let Err(e) = connection.release_slot(&key, &id).await {
+                        // the slot is freed by the lease
+                        tracing::warn!("unable to release the concurrency slot: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod concurrency_mod {
+    use super::*;
+
+    #[tokio::test]
+    async fn acquire_test() {
+        let config: Concurrency =
+            serde_yaml::from_str("limit: 1\nqueue:\n  size: 1\n  timeout: 50").unwrap();
+        let limiter = ConcurrencyLimiter::new("test".to_string(), &config);
+        let permit = limiter.acquire("client").await.unwrap();
+        assert!(permit.is_some());
+        // the queued request times out while the slot is taken
+        assert!(limiter.acquire("client").await.unwrap().is_none());
+        // the other keys have their own slots
+        assert!(limiter.acquire("other").await.unwrap().is_some());
+        drop(permit);
+        assert!(limiter.acquire("client").await.unwrap().is_some());
+        // the semaphores of the free keys are removed
+        if let ConcurrencyBackend::Memory(semaphores) = &limiter.backend {
+            assert!(semaphores.lock().unwrap().is_empty());
+        }
+    }
+}
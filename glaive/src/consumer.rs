@@ -0,0 +1,174 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+
+use crate::credential::{KeyStore, PasswordStore, SecretStore};
+use crate::def::{Access, Consumer};
+
+// the result of the access evaluation
+#[derive(Debug, PartialEq)]
+pub enum AccessDecision {
+    Allowed,
+    // the consumer is not authenticated, the response is 401
+    Unauthenticated,
+    // the consumer is not allowed, the response is 403
+    Forbidden(&'static str),
+}
+
+// the single consumer registry of the gateway
+// the consumers are indexed by id, with the credentials of every auth type
+pub struct ConsumerRegistry {
+    consumers: HashMap<String, Consumer>,
+    keys: KeyStore,
+    passwords: PasswordStore,
+    secrets: SecretStore,
+}
+
+impl ConsumerRegistry {
+    pub fn new(consumers: Option<Vec<Consumer>>) -> ConsumerRegistry {
+        let consumers = consumers.unwrap_or_default();
+        let keys = KeyStore::new(&consumers);
+        let passwords = PasswordStore::from_consumers(&consumers);
+        let secrets = SecretStore::from_consumers(&consumers);
+        let mut registry = HashMap::new();
+        for consumer in consumers {
+            let id = consumer.get_id().clone();
+            if registry.insert(id.clone(), consumer).is_some() {
+                panic!("consumer {} is defined more than once", id);
+            }
+        }
+        ConsumerRegistry {
+            consumers: registry,
+            keys,
+            passwords,
+            secrets,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Consumer> {
+        self.consumers.get(id)
+    }
+    pub fn get_keys(&self) -> &KeyStore {
+        &self.keys
+    }
+    pub fn get_passwords(&self) -> &PasswordStore {
+        &self.passwords
+    }
+    pub fn get_secrets(&self) -> &SecretStore {
+        &self.secrets
+    }
+
+    // evaluate the access rules for the authenticated consumer
+    // the deny rules are checked first, then the consumer must match one of the allow rules
+    pub fn authorize(&self, access: &Access, consumer_id: Option<&str>) -> AccessDecision {
+        let consumer_id = match consumer_id {
+            Some(consumer_id) => consumer_id,
+            None => return AccessDecision::Unauthenticated,
+        };
+        let consumer = match self.get(consumer_id) {
+            Some(consumer) => consumer,
+            None => return AccessDecision::Forbidden("Unknown consumer"),
+        };
+        let denied = access
+            .deny
+            .iter()
+            .flatten()
+            .any(|rule| rule.matches(consumer));
+        if denied {
+            return AccessDecision::Forbidden("Access denied");
+        }
+        if let Some(allow) = &access.allow {
+            if !allow.iter().any(|rule| rule.matches(consumer)) {
+                return AccessDecision::Forbidden("Access denied");
+            }
+        }
+        AccessDecision::Allowed
+    }
+}
+
+#[cfg(test)]
+mod consumer_mod {
+    use super::*;
+    use crate::def::AccessRule;
+
+    fn consumer(id: &str, groups: &[&str], acl: &[&str]) -> Consumer {
+        Consumer {
+            id: id.to_string(),
+            groups: Some(groups.iter().map(|g| g.to_string()).collect()),
+            acl: Some(acl.iter().map(|a| a.to_string()).collect()),
+            metadata: None,
+            credentials: None,
+            quota: None,
+        }
+    }
+
+    #[test]
+    fn authorize_test() {
+        let registry = ConsumerRegistry::new(Some(vec![
+            consumer("alice", &["partners"], &["read"]),
+            consumer("bob", &["partners", "suspended"], &["read"]),
+            consumer("carol", &[], &["write"]),
+        ]));
+        let access = Access {
+            allow: Some(vec![
+                AccessRule::Group {
+                    group: "partners".to_string(),
+                },
+                AccessRule::Consumer {
+                    consumer: "carol".to_string(),
+                },
+            ]),
+            deny: Some(vec![AccessRule::Group {
+                group: "suspended".to_string(),
+            }]),
+        };
+        assert_eq!(
+            registry.authorize(&access, Some("alice")),
+            AccessDecision::Allowed
+        );
+        assert_eq!(
+            registry.authorize(&access, Some("carol")),
+            AccessDecision::Allowed
+        );
+        assert_eq!(
+            registry.authorize(&access, Some("bob")),
+            AccessDecision::Forbidden("Access denied")
+        );
+        assert_eq!(
+            registry.authorize(&access, Some("dave")),
+            AccessDecision::Forbidden("Unknown consumer")
+        );
+        assert_eq!(
+            registry.authorize(&access, None),
+            AccessDecision::Unauthenticated
+        );
+        // the acl tags are matched as well
+        let access = Access {
+            allow: Some(vec![AccessRule::Acl {
+                acl: "write".to_string(),
+            }]),
+            deny: None,
+        };
+        assert_eq!(
+            registry.authorize(&access, Some("alice")),
+            AccessDecision::Forbidden("Access denied")
+        );
+    }
+}
@@ -0,0 +1,580 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use crate::def::{Basic, Consumer};
+
+// the default time in seconds a verified basic credential is cached
+const DEFAULT_BASIC_CACHE_TTL: u64 = 60;
+
+// the time in seconds a verified argon2 key is cached
+const KEY_CACHE_TTL: u64 = 60;
+
+// the maximum entries of a credential cache
+const MAX_CREDENTIAL_CACHE: usize = 10000;
+
+// the result of the key lookup
+#[derive(Debug, PartialEq)]
+pub enum KeyLookup<'a> {
+    Found(&'a str),
+    Expired,
+    NotFound,
+}
+
+// the stored key of a consumer
+struct StoredKey {
+    consumer: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl StoredKey {
+    fn lookup(&self) -> KeyLookup<'_> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= Utc::now() => KeyLookup::Expired,
+            _ => KeyLookup::Found(&self.consumer),
+        }
+    }
+}
+
+// the api keys of every consumer
+// plain text and sha256 keys are indexed by the sha256 digest, so the plain key is never kept
+// argon2 keys are found by their public prefix, so a single hash is verified for every key
+pub struct KeyStore {
+    digests: HashMap<String, StoredKey>,
+    argon2: Vec<(String, String, StoredKey)>,
+    cache: CredentialCache<usize>,
+}
+
+impl KeyStore {
+    // build the key store from the consumers
+    pub fn new(consumers: &[Consumer]) -> KeyStore {
+        let mut store = KeyStore {
+            digests: HashMap::new(),
+            argon2: Vec::new(),
+            cache: CredentialCache::new(),
+        };
+        for consumer in consumers {
+            let keys = match consumer
+                .get_credentials()
+                .as_ref()
+                .and_then(|credentials| credentials.keys.as_ref())
+            {
+                Some(keys) => keys,
+                None => continue,
+            };
+            for api_key in keys {
+                // the key with an invalid expiration is ignored, rather than never expiring
+                let expires_at = match &api_key.expires_at {
+                    Some(expires_at) => match DateTime::parse_from_rfc3339(expires_at) {
+                        Ok(expires_at) => Some(expires_at.with_timezone(&Utc)),
+                        Err(e) => {
+                            warn!(
+                                "ignoring key of consumer {}, invalid expiration: {}",
+                                consumer.get_id(),
+                                e
+                            );
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let stored = StoredKey {
+                    consumer: consumer.get_id().clone(),
+                    expires_at,
+                };
+                if let Some(digest) = api_key.key.strip_prefix("sha256:") {
+                    store.digests.insert(digest.to_lowercase(), stored);
+                } else if api_key.key.starts_with("$argon2") {
+                    if PasswordHash::new(&api_key.key).is_err() {
+                        warn!(
+                            "ignoring key of consumer {}, invalid argon2 hash",
+                            consumer.get_id()
+                        );
+                        continue;
+                    }
+                    // the prefix selects the hash, so it must be unique
+                    let prefix = match api_key.prefix.as_deref().filter(|p| !p.is_empty()) {
+                        Some(prefix) => prefix,
+                        None => {
+                            warn!(
+                                "ignoring argon2 key of consumer {}, the prefix is required",
+                                consumer.get_id()
+                            );
+                            continue;
+                        }
+                    };
+                    let overlaps = store.argon2.iter().any(|(other, _, _)| {
+                        other.starts_with(prefix) || prefix.starts_with(other.as_str())
+                    });
+                    if overlaps {
+                        warn!(
+                            "ignoring argon2 key of consumer {}, the prefix {} is not unique",
+                            consumer.get_id(),
+                            prefix
+                        );
+                        continue;
+                    }
+                    store
+                        .argon2
+                        .push((prefix.to_string(), api_key.key.clone(), stored));
+                } else {
+                    store.digests.insert(sha256_hex(&api_key.key), stored);
+                }
+            }
+        }
+        store
+    }
+
+    // find the consumer of the key
+    pub async fn find(&self, key: &str) -> KeyLookup<'_> {
+        let digest = sha256_hex(key);
+        if let Some(stored) = self.digests.get(&digest) {
+            return stored.lookup();
+        }
+        // the argon2 key is selected by its prefix
+        let index = match self
+            .argon2
+            .iter()
+            .position(|(prefix, _, _)| key.starts_with(prefix.as_str()))
+        {
+            Some(index) => index,
+            None => return KeyLookup::NotFound,
+        };
+        let (_, hash, stored) = &self.argon2[index];
+        // the verified keys are cached, the expiration is still checked on every lookup
+        if self.cache.get(&digest) == Some(index) {
+            return stored.lookup();
+        }
+        // the hash is verified on the blocking pool, because argon2 is slow by design
+        let (key, hash) = (key.to_string(), hash.clone());
+        let verified = tokio::task::spawn_blocking(move || verify_password(&key, &hash))
+            .await
+            .unwrap_or(false);
+        if !verified {
+            return KeyLookup::NotFound;
+        }
+        self.cache
+            .insert(digest, index, Duration::from_secs(KEY_CACHE_TTL));
+        stored.lookup()
+    }
+}
+
+// the password hashes by username, with the consumer of every username
+#[derive(Default)]
+pub struct PasswordStore {
+    passwords: HashMap<String, (String, String)>,
+}
+
+impl PasswordStore {
+    // build the password store from the consumers
+    // the username is the consumer name, unless another username is defined
+    pub fn from_consumers(consumers: &[Consumer]) -> PasswordStore {
+        let mut store = PasswordStore::default();
+        for consumer in consumers {
+            let basic = match consumer
+                .get_credentials()
+                .as_ref()
+                .and_then(|credentials| credentials.basic.as_ref())
+            {
+                Some(basic) => basic,
+                None => continue,
+            };
+            if !is_supported_hash(&basic.password) {
+                warn!(
+                    "ignoring basic credential of consumer {}, unsupported password hash",
+                    consumer.get_id()
+                );
+                continue;
+            }
+            let username = basic
+                .username
+                .clone()
+                .unwrap_or_else(|| consumer.get_id().clone());
+            store.passwords.insert(
+                username,
+                (consumer.get_id().clone(), basic.password.clone()),
+            );
+        }
+        store
+    }
+
+    // build the password store from a htpasswd file, the username is the consumer name
+    pub fn from_htpasswd(path: &str) -> Result<PasswordStore, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut store = PasswordStore::default();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = match line.split_once(':') {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if !is_supported_hash(hash) {
+                warn!(
+                    "ignoring htpasswd user {}, unsupported password hash",
+                    username
+                );
+                continue;
+            }
+            store.passwords.insert(
+                username.to_string(),
+                (username.to_string(), hash.to_string()),
+            );
+        }
+        Ok(store)
+    }
+
+    // get the consumer and the password hash of the username
+    pub fn get(&self, username: &str) -> Option<&(String, String)> {
+        self.passwords.get(username)
+    }
+}
+
+// the hmac secrets by username, with the consumer of every username
+#[derive(Default)]
+pub struct SecretStore {
+    secrets: HashMap<String, (String, String)>,
+}
+
+impl SecretStore {
+    // build the secret store from the consumers
+    // the username is the consumer name, unless another username is defined
+    pub fn from_consumers(consumers: &[Consumer]) -> SecretStore {
+        let mut store = SecretStore::default();
+        for consumer in consumers {
+            let hmac = match consumer
+                .get_credentials()
+                .as_ref()
+                .and_then(|credentials| credentials.hmac.as_ref())
+            {
+                Some(hmac) => hmac,
+                None => continue,
+            };
+            let username = hmac
+                .username
+                .clone()
+                .unwrap_or_else(|| consumer.get_id().clone());
+            store
+                .secrets
+                .insert(username, (consumer.get_id().clone(), hmac.secret.clone()));
+        }
+        store
+    }
+
+    // get the consumer and the shared secret of the username
+    pub fn get(&self, username: &str) -> Option<&(String, String)> {
+        self.secrets.get(username)
+    }
+}
+
+// the bcrypt, sha and argon2 hashes are supported
+fn is_supported_hash(hash: &str) -> bool {
+    hash.starts_with("$2y$")
+        || hash.starts_with("$2b$")
+        || hash.starts_with("$2a$")
+        || hash.starts_with("{SHA}")
+        || PasswordHash::new(hash).is_ok_and(|hash| hash.algorithm.as_str().starts_with("argon2"))
+}
+
+// verify the password against the htpasswd compatible hash
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if let Some(digest) = hash.strip_prefix("{SHA}") {
+        constant_time_eq(&STANDARD.encode(Sha1::digest(password.as_bytes())), digest)
+    } else if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+// the basic auth validator
+// the verified credentials are cached, so the hash cost is not paid on every request
+pub struct BasicValidator {
+    realm: String,
+    htpasswd: Option<PasswordStore>,
+    cache_ttl: Duration,
+    cache: CredentialCache<String>,
+}
+
+impl BasicValidator {
+    pub fn new(config: &Basic) -> BasicValidator {
+        let htpasswd = config.htpasswd.as_ref().map(|path| {
+            PasswordStore::from_htpasswd(path)
+                .unwrap_or_else(|e| panic!("unable to read htpasswd file {}: {}", path, e))
+        });
+        BasicValidator {
+            realm: config.realm.clone().unwrap_or_else(|| "glaive".to_string()),
+            htpasswd,
+            cache_ttl: Duration::from_secs(config.cache_ttl.unwrap_or(DEFAULT_BASIC_CACHE_TTL)),
+            cache: CredentialCache::new(),
+        }
+    }
+
+    // the www-authenticate challenge
+    pub fn get_challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+
+    // verify the credentials and get the consumer
+    // the htpasswd file is checked before the consumer credentials
+    pub async fn verify(
+        &self,
+        consumers: &PasswordStore,
+        username: &str,
+        password: &str,
+    ) -> Option<String> {
+        let cache_key = sha256_hex(&format!("{}:{}", username, password));
+        if let Some(consumer) = self.cache.get(&cache_key) {
+            return Some(consumer);
+        }
+        let (consumer, hash) = self
+            .htpasswd
+            .as_ref()
+            .and_then(|htpasswd| htpasswd.get(username))
+            .or_else(|| consumers.get(username))?
+            .clone();
+        // the hash is verified on the blocking pool, because bcrypt and argon2 are slow by design
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+        if !verified {
+            return None;
+        }
+        self.cache
+            .insert(cache_key, consumer.clone(), self.cache_ttl);
+        Some(consumer)
+    }
+}
+
+// the in memory cache of the verified credentials and auth decisions
+// the entries expire after their own ttl
+pub struct CredentialCache<V> {
+    entries: Mutex<HashMap<String, (V, Instant)>>,
+}
+
+impl<V: Clone> CredentialCache<V> {
+    pub fn new() -> CredentialCache<V> {
+        CredentialCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // get the value when it is not expired
+    pub fn get(&self, key: &str) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > Instant::now() => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    // cache the value only when the key is not cached yet
+    // returns false when the key is already cached
+    pub fn insert_new(&self, key: String, value: V, ttl: Duration) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, expires_at)) = entries.get(&key) {
+            if *expires_at > Instant::now() {
+                return false;
+            }
+        }
+        Self::evict(&mut entries);
+        entries.insert(key, (value, Instant::now() + ttl));
+        true
+    }
+
+    // cache the value, the expired entries are removed when the cache is full
+    pub fn insert(&self, key: String, value: V, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        Self::evict(&mut entries);
+        entries.insert(key, (value, Instant::now() + ttl));
+    }
+
+    // remove the expired entries when the cache is full
+    fn evict(entries: &mut HashMap<String, (V, Instant)>) {
+        if entries.len() >= MAX_CREDENTIAL_CACHE {
+            let now = Instant::now();
+            entries.retain(|_, (_, expires_at)| *expires_at > now);
+            if entries.len() >= MAX_CREDENTIAL_CACHE {
+                entries.clear();
+            }
+        }
+    }
+}
+
+// compare the secrets in constant time
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+// the lowercase hex sha256 digest
+pub fn sha256_hex(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+#[cfg(test)]
+mod credential_mod {
+    use super::*;
+    use crate::def::{ApiKey, BasicCredential, Credentials};
+
+    fn consumer(name: &str, keys: Vec<(&str, Option<&str>)>) -> Consumer {
+        Consumer {
+            id: name.to_string(),
+            groups: None,
+            acl: None,
+            metadata: None,
+            credentials: Some(Credentials {
+                keys: Some(
+                    keys.into_iter()
+                        .map(|(key, expires_at)| ApiKey {
+                            key: key.to_string(),
+                            expires_at: expires_at.map(|e| e.to_string()),
+                            prefix: None,
+                        })
+                        .collect(),
+                ),
+                basic: None,
+                hmac: None,
+            }),
+            quota: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn key_store_test() {
+        let digest = format!("sha256:{}", sha256_hex("hashed-key"));
+        let store = KeyStore::new(&[
+            consumer("alice", vec![("plain-key", None), (&digest, None)]),
+            consumer("bob", vec![("old-key", Some("2020-01-01T00:00:00Z"))]),
+        ]);
+        assert_eq!(store.find("plain-key").await, KeyLookup::Found("alice"));
+        assert_eq!(store.find("hashed-key").await, KeyLookup::Found("alice"));
+        assert_eq!(store.find("old-key").await, KeyLookup::Expired);
+        assert_eq!(store.find("unknown").await, KeyLookup::NotFound);
+    }
+
+    #[tokio::test]
+    async fn argon2_key_test() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+        let salt = SaltString::encode_b64(b"glaive-key-salt").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"gk_3f9a.secret", &salt)
+            .unwrap()
+            .to_string();
+        let mut carol = consumer("carol", vec![(&hash, None)]);
+        let mut dave = consumer("dave", vec![(&hash, None), (&hash, None)]);
+        let keys = |consumer: &mut Consumer| {
+            let credentials = consumer.credentials.as_mut().unwrap();
+            credentials.keys.take().unwrap()
+        };
+        let mut carol_keys = keys(&mut carol);
+        carol_keys[0].prefix = Some("gk_3f9a".to_string());
+        carol.credentials.as_mut().unwrap().keys = Some(carol_keys);
+        // the key without a prefix and the key with an overlapping prefix are ignored
+        let mut dave_keys = keys(&mut dave);
+        dave_keys[1].prefix = Some("gk_3f".to_string());
+        dave.credentials.as_mut().unwrap().keys = Some(dave_keys);
+        let store = KeyStore::new(&[carol, dave]);
+        assert_eq!(store.argon2.len(), 1);
+        assert_eq!(
+            store.find("gk_3f9a.secret").await,
+            KeyLookup::Found("carol")
+        );
+        // the second lookup is served from the cache
+        assert_eq!(store.cache.get(&sha256_hex("gk_3f9a.secret")), Some(0));
+        assert_eq!(
+            store.find("gk_3f9a.secret").await,
+            KeyLookup::Found("carol")
+        );
+        assert_eq!(store.find("gk_3f9a.wrong").await, KeyLookup::NotFound);
+        // the key without a known prefix is never verified
+        assert_eq!(store.find("gk_0000.secret").await, KeyLookup::NotFound);
+    }
+
+    #[test]
+    fn constant_time_eq_test() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret-key"));
+    }
+
+    #[tokio::test]
+    async fn basic_validator_test() {
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_password("secret", &bcrypt_hash));
+        assert!(verify_password(
+            "secret",
+            "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="
+        ));
+        assert!(!verify_password(
+            "wrong",
+            "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="
+        ));
+        let mut admin = consumer("admin", vec![]);
+        admin.credentials = Some(Credentials {
+            keys: None,
+            basic: Some(BasicCredential {
+                username: Some("tool".to_string()),
+                password: bcrypt_hash,
+            }),
+            hmac: None,
+        });
+        let consumers = PasswordStore::from_consumers(&[admin]);
+        let validator = BasicValidator::new(&Basic {
+            realm: None,
+            htpasswd: None,
+            cache_ttl: None,
+        });
+        assert_eq!(
+            validator.verify(&consumers, "tool", "secret").await,
+            Some("admin".to_string())
+        );
+        assert_eq!(validator.verify(&consumers, "tool", "wrong").await, None);
+        assert_eq!(validator.verify(&consumers, "admin", "secret").await, None);
+        // the second verification is served from the cache
+        assert_eq!(
+            validator.verify(&consumers, "tool", "secret").await,
+            Some("admin".to_string())
+        );
+    }
+}
@@ -1,253 +1,1108 @@
-/**
- * Copyright (c) 2024-2025 Glaive, Inc.
- *
- * This file is part of Glaive Gateway
- *
- * This program is free software: you can redistribute it and/or modify
- * it under the terms of the GNU Affero General Public License as published by
- * the Free Software Foundation, either version 3 of the License, or
- * (at your option) any later version.
- *
- * This program is distributed in the hope that it will be useful
- * but WITHOUT ANY WARRANTY; without even the implied warranty of
- * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
- * GNU Affero General Public License for more details.
- *
- * You should have received a copy of the GNU Affero General Public License
- * along with this program.  If not, see <http://www.gnu.org/licenses/>.
- */
-
-use std::time::Duration;
-
-use pingora::cache::eviction::lru::Manager as LRUEvictionManager;
-use pingora::cache::lock::CacheLock;
-use pingora::lb::LoadBalancer;
-use pingora::prelude::RoundRobin;
-use pingora::services::background::GenBackgroundService;
-
-use serde::{Deserialize, Serialize};
-
-use crate::bucket::CacheBucket;
-use crate::cache::MemoryStorage;
-use crate::discovery::{Discovery, DiscoveryBackgroundService};
-
-// enum discovery type
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum DiscoveryType {
-    Consul { consul: ConsulType },
-    // DNS { dns: DNSType },
-    // Kubernetes { kubernetes: KubernetesType },
-}
-
-// hashicorp consul config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ConsulType {
-    // the service name is mandatory and used for querying to consul
-    pub name: String,
-    // the passing option determines if the discovery should return healthy/alive services
-    // the default is false. set passing to true if you want to get healthy services.
-    pub passing: bool,
-}
-
-impl ConsulType {
-    pub fn build_cluster(
-        &self,
-        discovery: &Discovery,
-        updater_bg_process: &mut Vec<GenBackgroundService<DiscoveryBackgroundService>>,
-    ) -> GenBackgroundService<LoadBalancer<RoundRobin>> {
-        // Build the cluster with discovery
-        let (cluster, updater) =
-            discovery.build_cluster_discovery(self.name.clone(), self.passing.clone());
-        updater_bg_process.push(updater);
-        cluster
-    }
-}
-
-// dns config
-#[derive(Debug, Deserialize, Serialize)]
-struct DNSType {}
-
-// kubernetes config
-#[derive(Debug, Deserialize, Serialize)]
-struct KubernetesType {}
-
-// enum auth type
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum AuthType {
-    Key { key: Key },
-    JWT { jwt: Jwt },
-    External { external: External },
-}
-
-// key auth config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Key {
-    pub allowed: Vec<String>,
-}
-
-// jwt auth config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Jwt {
-    pub secret: String,
-}
-
-// external auth config
-#[derive(Debug, Deserialize, Serialize)]
-struct External {}
-
-// ip whitelist
-#[derive(Debug, Deserialize, Serialize)]
-pub struct IpWhitelist {
-    pub whitelist: Vec<String>,
-}
-
-// enum cache type
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum CacheType {
-    Memory { memory: MemoryCache },
-    // Redis { redis: RedisCache },
-}
-
-// memory cache config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct MemoryCache {
-    pub cache_ttl: usize,
-    pub max_size: usize,
-    pub max_cache: usize,
-    pub lock_timeout: usize,
-}
-
-impl MemoryCache {
-    pub fn new_storage(&self) -> (Option<CacheBucket>, Option<usize>) {
-        let mega_byte: usize = 1024 * 1024;
-        let bucket = CacheBucket::new(
-            MemoryStorage::with_capacity(8192)
-                .with_reject_empty_body(true)
-                .with_max_file_size(Some(mega_byte * self.max_size)),
-        )
-        .with_eviction(LRUEvictionManager::<16>::with_capacity(
-            mega_byte * self.max_cache,
-            8192,
-        ))
-        .with_cache_lock(CacheLock::new(Duration::from_millis(
-            self.lock_timeout as u64,
-        )));
-        // return bucket and ttl
-        (Some(bucket), Some(self.cache_ttl))
-    }
-}
-
-// redis cache config
-#[derive(Debug, Deserialize, Serialize)]
-struct RedisCache {}
-
-// consumer config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Consumer {
-    // the allowed consumer name
-    pub name: String,
-    // the list of allowed access control
-    pub acl: Vec<String>,
-}
-
-impl Consumer {
-    pub fn get_name(&self) -> &String {
-        &self.name
-    }
-    pub fn get_acl(&self) -> &Vec<String> {
-        &self.acl
-    }
-}
-
-// the main limiter configuration
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Limiter {
-    global: Option<RatelimitType>,
-    client: Option<RatelimitType>,
-}
-
-impl Limiter {
-    pub fn get_global(&self) -> &Option<RatelimitType> {
-        &self.global
-    }
-    pub fn get_client(&self) -> &Option<RatelimitType> {
-        &self.client
-    }
-}
-
-// enum rate limit type
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum RatelimitType {
-    Basic { basic: BasicLimiter },
-    // Redis { redis: RedisLimiter },
-}
-
-// basic limiter config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct BasicLimiter {
-    pub limit: isize,
-}
-
-// redis limiter config
-#[derive(Debug, Deserialize, Serialize)]
-struct RedisLimiter {}
-
-// headers config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Headers {
-    // used for storing a list headers data to be inserted
-    pub insert: Option<Vec<InsertHeader>>,
-    // used for removing a list of headers
-    pub remove: Option<Vec<RemoveHeader>>,
-}
-
-impl Headers {
-    pub fn to_be_inserted(&self) -> &Option<Vec<InsertHeader>> {
-        &self.insert
-    }
-    pub fn to_be_removed(&self) -> &Option<Vec<RemoveHeader>> {
-        &self.remove
-    }
-}
-
-// insert header config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct InsertHeader {
-    // header key
-    pub key: String,
-    // header value
-    pub value: String,
-}
-
-// remove header config
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RemoveHeader {
-    // header key
-    pub key: String,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Request {
-    pub headers: Option<Headers>,
-}
-
-impl Request {
-    pub fn get_headers(&self) -> &Option<Headers> {
-        &self.headers
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Response {
-    pub headers: Option<Headers>,
-}
-
-impl Response {
-    pub fn get_headers(&self) -> &Option<Headers> {
-        &self.headers
-    }
-}
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pingora::cache::eviction::lru::Manager as LRUEvictionManager;
+use pingora::cache::lock::CacheLock;
+use pingora::lb::LoadBalancer;
+use pingora::prelude::RoundRobin;
+use pingora::services::background::GenBackgroundService;
+
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
+
+use crate::adaptive::AdaptiveLimiter;
+use crate::bucket::CacheBucket;
+use crate::cache::MemoryStorage;
+use crate::cache_index::CacheIndex;
+use crate::cache_policy::CachePolicy;
+use crate::concurrency::ConcurrencyLimiter;
+use crate::credential::BasicValidator;
+use crate::discovery::{Discovery, DiscoveryBackgroundService};
+use crate::disk_cache::{DiskStorage, TieredStorage};
+use crate::external::ExternalAuthenticator;
+use crate::introspection::Introspector;
+use crate::jwt::{JwksBackgroundService, JwtValidator};
+use crate::limiter::Limiters;
+use crate::redis::RedisConnection;
+use crate::redis_cache::RedisStorage;
+use crate::signature::SignatureValidator;
+
+// enum discovery type
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DiscoveryType {
+    Consul { consul: ConsulType },
+    // DNS { dns: DNSType },
+    // Kubernetes { kubernetes: KubernetesType },
+}
+
+// hashicorp consul config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConsulType {
+    // the service name is mandatory and used for querying to consul
+    pub name: String,
+    // the passing option determines if the discovery should return healthy/alive services
+    // the default is false. set passing to true if you want to get healthy services.
+    pub passing: bool,
+}
+
+impl ConsulType {
+    pub fn build_cluster(
+        &self,
+        discovery: &Discovery,
+        updater_bg_process: &mut Vec<GenBackgroundService<DiscoveryBackgroundService>>,
+    ) -> GenBackgroundService<LoadBalancer<RoundRobin>> {
+        // Build the cluster with discovery
+        let (cluster, updater) =
+            discovery.build_cluster_discovery(self.name.clone(), self.passing.clone());
+        updater_bg_process.push(updater);
+        cluster
+    }
+}
+
+// dns config
+#[derive(Debug, Deserialize, Serialize)]
+struct DNSType {}
+
+// kubernetes config
+#[derive(Debug, Deserialize, Serialize)]
+struct KubernetesType {}
+
+// enum auth type
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AuthType {
+    Key { key: Key },
+    JWT { jwt: Jwt },
+    Basic { basic: Basic },
+    OAuth2Introspection { oauth2_introspection: Introspection },
+    Hmac { hmac: Hmac },
+    External { external: External },
+}
+
+// key auth config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Key {
+    // the static keys, they are not bound to any consumer
+    pub allowed: Option<Vec<String>>,
+    // where the key is read from, the authorization header by default
+    pub sources: Option<Vec<TokenSource>>,
+}
+
+impl Key {
+    pub fn get_sources(&self) -> Vec<TokenSource> {
+        self.sources.clone().unwrap_or_else(|| {
+            vec![TokenSource::Header {
+                header: "Authorization".to_string(),
+            }]
+        })
+    }
+}
+
+// basic auth config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Basic {
+    // the realm of the www-authenticate challenge, glaive by default
+    pub realm: Option<String>,
+    // the htpasswd file, the credentials of the consumers are used otherwise
+    pub htpasswd: Option<String>,
+    // the time in seconds a verified credential is cached, 60 by default
+    pub cache_ttl: Option<u64>,
+}
+
+impl Basic {
+    // build the basic validator, the htpasswd file is loaded once
+    pub fn build_validator(&self) -> BasicValidator {
+        BasicValidator::new(self)
+    }
+}
+
+// hmac signature auth config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Hmac {
+    // the allowed algorithms, every algorithm by default
+    pub algorithms: Option<Vec<HmacAlgorithm>>,
+    // the components every signature must cover
+    // @method, @path and the lowercase header names, "@method @path date" by default
+    pub components: Option<Vec<String>>,
+    // the allowed difference in seconds between the date header and the gateway clock, 300 by default
+    pub clock_skew: Option<u64>,
+    // the header with the request nonce, the replayed nonces are rejected
+    pub nonce_header: Option<String>,
+    // a request with body must have a signed digest header, true by default
+    pub enforce_digest: Option<bool>,
+    // the maximum body size in bytes held until the digest is verified, 1MB by default
+    pub max_body_size: Option<usize>,
+}
+
+impl Hmac {
+    // build the signature validator, the nonces are kept by the validator
+    pub fn build_validator(&self) -> SignatureValidator {
+        SignatureValidator::new(self)
+    }
+}
+
+// enum hmac algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HmacAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+// oauth 2.0 token introspection config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Introspection {
+    // the rfc 7662 introspection endpoint
+    pub url: String,
+    // the client credentials used to call the endpoint
+    pub client_id: String,
+    pub client_secret: String,
+    // the scopes every token must have
+    pub scopes: Option<Vec<String>>,
+    // the token must have one of the audiences
+    pub audience: Option<Vec<String>>,
+    // the field with the consumer name, the sub with the client_id as fallback by default
+    pub consumer_field: Option<String>,
+    // where the token is read from, the authorization header by default
+    pub sources: Option<Vec<TokenSource>>,
+    // the request timeout in milliseconds, 2000 by default
+    pub timeout: Option<u64>,
+    // the maximum time in seconds a result is cached, it is never cached after the token exp
+    pub cache_ttl: Option<u64>,
+    // the results are shared with the other gateways through redis
+    pub redis: Option<Redis>,
+}
+
+impl Introspection {
+    // build the introspector, the client and the cache are shared by every request
+    pub fn build_introspector(&self) -> Introspector {
+        Introspector::new(self)
+    }
+}
+
+// redis connection config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Redis {
+    // the redis url, e.g. redis://127.0.0.1:6379
+    pub url: String,
+    // the key prefix, glaive by default
+    pub prefix: Option<String>,
+    // the connect and command timeout in milliseconds, 100 by default
+    pub timeout: Option<u64>,
+    // the number of multiplexed connections, 4 by default
+    pub pool_size: Option<usize>,
+}
+
+impl Redis {
+    pub fn get_prefix(&self) -> String {
+        self.prefix.clone().unwrap_or_else(|| "glaive".to_string())
+    }
+    pub fn get_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout.unwrap_or(100))
+    }
+    pub fn get_pool_size(&self) -> usize {
+        self.pool_size.unwrap_or(4).max(1)
+    }
+}
+
+// jwt auth config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Jwt {
+    // the shared secret for the hmac algorithms
+    pub secret: Option<String>,
+    // the path of the PEM public key for the RS, PS, ES and EdDSA algorithms
+    pub public_key: Option<String>,
+    // the algorithm of the secret or public key
+    // the default is HS256 for the secret and RS256 for the public key
+    pub algorithm: Option<Algorithm>,
+    // the remote key set, the key is selected by the token kid
+    pub jwks: Option<Jwks>,
+    // the accepted token issuers
+    pub issuer: Option<Vec<String>>,
+    // the accepted token audiences
+    pub audience: Option<Vec<String>>,
+    // the allowed clock skew in seconds for exp and nbf, 60 by default
+    pub leeway: Option<u64>,
+    // reject the token before its nbf time, enabled by default
+    pub validate_nbf: Option<bool>,
+    // the claim holding the consumer name, consumer by default
+    pub consumer_claim: Option<String>,
+    // where the token is read from, in order
+    // the default is the bearer token of the authorization header
+    pub sources: Option<Vec<TokenSource>>,
+}
+
+impl Jwt {
+    // build the jwt validator, the jwks is refreshed by a background process
+    pub fn build_validator(
+        &self,
+        jwks_bg_process: &mut Vec<GenBackgroundService<JwksBackgroundService>>,
+    ) -> JwtValidator {
+        let (validator, jwks_service) = JwtValidator::new(self);
+        if let Some(jwks_service) = jwks_service {
+            jwks_bg_process.push(jwks_service);
+        }
+        validator
+    }
+    pub fn get_consumer_claim(&self) -> String {
+        self.consumer_claim
+            .clone()
+            .unwrap_or_else(|| "consumer".to_string())
+    }
+}
+
+// jwks config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Jwks {
+    // the url of the json web key set
+    pub url: String,
+    // the refresh interval in seconds, 300 by default
+    pub refresh_interval: Option<u64>,
+}
+
+// enum token source
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TokenSource {
+    // the header name, the bearer prefix is removed
+    Header { header: String },
+    // the cookie name
+    Cookie { cookie: String },
+    // the query parameter name
+    Query { query: String },
+}
+
+// external auth config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct External {
+    // the url of the auth service
+    pub url: String,
+    // the request headers sent to the auth service, authorization and cookie by default
+    pub request_headers: Option<Vec<String>>,
+    // the auth response headers copied to the upstream request
+    pub upstream_headers: Option<Vec<String>>,
+    // the auth response header with the consumer name
+    pub consumer_header: Option<String>,
+    // the subrequest timeout in milliseconds, 2000 by default
+    pub timeout: Option<u64>,
+    // the time in seconds a decision is cached per credential, method and uri, disabled by default
+    pub cache_ttl: Option<u64>,
+}
+
+impl External {
+    // build the external authenticator, the client is shared by every request
+    pub fn build_authenticator(&self) -> ExternalAuthenticator {
+        ExternalAuthenticator::new(self)
+    }
+}
+
+// ip whitelist
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IpWhitelist {
+    pub whitelist: Vec<String>,
+}
+
+// cache config of the cluster
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cache {
+    // the cache storage, memory, redis or disk
+    #[serde(flatten)]
+    pub storage: CacheType,
+    // the status codes cached, 200, 203, 300, 301, 308, 404 and 410 by default
+    pub statuses: Option<Vec<u16>>,
+    // cache the responses with set-cookie, false by default
+    pub allow_set_cookie: Option<bool>,
+    // cache the responses to authenticated requests, false by default
+    pub allow_authorization: Option<bool>,
+    // the cache key, the cluster name and the full uri by default
+    pub key: Option<CacheKey>,
+    // the seconds an expired response is served while it is refreshed in the background
+    // used when the upstream cache-control has no stale-while-revalidate, 0 by default
+    pub stale_while_revalidate: Option<u32>,
+    // the seconds an expired response is served when the upstream fails
+    // used when the upstream cache-control has no stale-if-error, 0 by default
+    pub stale_if_error: Option<u32>,
+    // the response header with the surrogate keys used to purge, e.g. Surrogate-Key or Cache-Tag
+    pub tag_header: Option<String>,
+    // the maximum number of objects indexed for the purge by each gateway, 100000 by default
+    pub max_index: Option<usize>,
+}
+
+// cache key config, the cluster name and the path are always part of the key
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheKey {
+    // the query params of the key, every param in the request order by default
+    pub query: Option<CacheKeyQuery>,
+    // the request headers added to the key, e.g. Accept-Language
+    pub headers: Option<Vec<String>>,
+    // add the consumer to the key, the private responses are then cached per consumer
+    pub consumer: Option<bool>,
+}
+
+// query params of the cache key, a trailing * matches the param name prefix e.g. utm_*
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheKeyQuery {
+    // only these params are kept
+    pub include: Option<Vec<String>>,
+    // these params are dropped
+    pub exclude: Option<Vec<String>>,
+    // sort the params, so their order does not matter
+    pub sort: Option<bool>,
+}
+
+impl Cache {
+    // build the cache storage and the cacheability rules of the cluster
+    pub fn build_cache(&self) -> (Option<CacheBucket>, Option<Arc<CachePolicy>>) {
+        let (storage, ttl) = match &self.storage {
+            CacheType::Memory { memory } => memory.new_storage(),
+            CacheType::Redis { redis } => redis.new_storage(),
+            CacheType::Disk { disk } => disk.new_storage(),
+        };
+        // the cached objects are indexed for the purge
+        // the redis and disk objects may be written by other gateways or before a restart
+        let persistent = !matches!(self.storage, CacheType::Memory { .. });
+        let index = CacheIndex::new(self.tag_header.clone(), self.max_index.unwrap_or(100_000))
+            .with_persistent(persistent);
+        let storage = storage.map(|bucket| bucket.with_index(index));
+        let policy = CachePolicy::new(self, ttl.unwrap_or_default());
+        (storage, Some(Arc::new(policy)))
+    }
+}
+
+// enum cache type
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum CacheType {
+    Memory { memory: MemoryCache },
+    Redis { redis: RedisCache },
+    Disk { disk: DiskCache },
+}
+
+// memory cache config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MemoryCache {
+    pub cache_ttl: usize,
+    pub max_size: usize,
+    pub max_cache: usize,
+    pub lock_timeout: usize,
+}
+
+impl MemoryCache {
+    pub fn new_storage(&self) -> (Option<CacheBucket>, Option<usize>) {
+        let mega_byte: usize = 1024 * 1024;
+        let bucket = CacheBucket::new(
+            MemoryStorage::with_capacity(8192)
+                .with_reject_empty_body(true)
+                .with_max_file_size(Some(mega_byte * self.max_size)),
+        )
+        .with_eviction(LRUEvictionManager::<16>::with_capacity(
+            mega_byte * self.max_cache,
+            8192,
+        ))
+        .with_cache_lock(CacheLock::new(Duration::from_millis(
+            self.lock_timeout as u64,
+        )));
+        // return bucket and ttl
+        (Some(bucket), Some(self.cache_ttl))
+    }
+}
+
+// disk cache config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiskCache {
+    pub cache_ttl: usize,
+    // the cache directory, the objects found are kept across restarts
+    pub path: String,
+    // the maximum object size in megabytes, 50 by default
+    pub max_size: Option<usize>,
+    // the maximum size of the cache on disk in megabytes
+    pub max_cache: usize,
+    // the milliseconds a request waits for the same response being cached, 1000 by default
+    pub lock_timeout: Option<usize>,
+    // puts a memory tier in front of the disk
+    pub memory: Option<MemoryTier>,
+}
+
+impl DiskCache {
+    pub fn new_storage(&self) -> (Option<CacheBucket>, Option<usize>) {
+        let mega_byte: usize = 1024 * 1024;
+        let disk = DiskStorage::new(&self.path, (mega_byte * self.max_cache) as u64)
+            .with_reject_empty_body(true)
+            .with_max_file_size(Some(mega_byte * self.max_size.unwrap_or(50)));
+        // the disk evicts its own objects, the eviction manager only concerns the memory tier
+        let bucket = match &self.memory {
+            Some(memory) => {
+                // shared with the storage, the objects promoted from the disk are admitted to it
+                let eviction: &'static LRUEvictionManager<16> = Box::leak(Box::new(
+                    LRUEvictionManager::with_capacity(mega_byte * memory.max_cache, 8192),
+                ));
+                let mut bucket = CacheBucket::new(
+                    TieredStorage::new(
+                        MemoryStorage::with_capacity(8192)
+                            .with_reject_empty_body(true)
+                            .with_max_file_size(Some(mega_byte * memory.max_size)),
+                        disk,
+                    )
+                    .with_eviction(eviction),
+                );
+                bucket.eviction = Some(eviction);
+                bucket
+            }
+            None => CacheBucket::new(disk),
+        }
+        .with_cache_lock(CacheLock::new(Duration::from_millis(
+            self.lock_timeout.unwrap_or(1000) as u64,
+        )));
+        // return bucket and ttl
+        (Some(bucket), Some(self.cache_ttl))
+    }
+}
+
+// memory tier config of the disk cache
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MemoryTier {
+    // the maximum object size in megabytes kept in memory
+    pub max_size: usize,
+    // the maximum size of the memory tier in megabytes
+    pub max_cache: usize,
+}
+
+// redis cache config
+// the cache is shared by every gateway, the clusters sharing a redis are apart by the cache key
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RedisCache {
+    pub cache_ttl: usize,
+    // the maximum object size in megabytes, 1 by default
+    pub max_size: Option<usize>,
+    // the milliseconds a request waits for the same response being cached, 1000 by default
+    pub lock_timeout: Option<usize>,
+    #[serde(flatten)]
+    pub redis: Redis,
+}
+
+impl RedisCache {
+    pub fn new_storage(&self) -> (Option<CacheBucket>, Option<usize>) {
+        let mega_byte: usize = 1024 * 1024;
+        let connection = RedisConnection::new(&self.redis)
+            .unwrap_or_else(|e| panic!("invalid cache redis url {}: {}", self.redis.url, e));
+        // redis evicts the objects by itself, no eviction manager is needed
+        let bucket = CacheBucket::new(
+            RedisStorage::new(connection, self.redis.get_prefix())
+                .with_reject_empty_body(true)
+                .with_max_file_size(Some(mega_byte * self.max_size.unwrap_or(1))),
+        )
+        .with_cache_lock(CacheLock::new(Duration::from_millis(
+            self.lock_timeout.unwrap_or(1000) as u64,
+        )));
+        // return bucket and ttl
+        (Some(bucket), Some(self.cache_ttl))
+    }
+}
+
+// consumer config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Consumer {
+    // the unique consumer id, the former name is accepted as well
+    #[serde(alias = "name")]
+    pub id: String,
+    // the groups of the consumer
+    pub groups: Option<Vec<String>>,
+    // the access control tags of the consumer
+    pub acl: Option<Vec<String>>,
+    // free form metadata, e.g. the owner or the contract of the consumer
+    pub metadata: Option<HashMap<String, String>>,
+    // the credentials used to authenticate the consumer
+    pub credentials: Option<Credentials>,
+    // the request quota of the consumer, replaces the quota of its groups
+    pub quota: Option<Quota>,
+}
+
+impl Consumer {
+    pub fn get_id(&self) -> &String {
+        &self.id
+    }
+    pub fn get_groups(&self) -> &[String] {
+        self.groups.as_deref().unwrap_or_default()
+    }
+    pub fn get_acl(&self) -> &[String] {
+        self.acl.as_deref().unwrap_or_default()
+    }
+    pub fn get_credentials(&self) -> &Option<Credentials> {
+        &self.credentials
+    }
+    pub fn get_quota(&self) -> &Option<Quota> {
+        &self.quota
+    }
+}
+
+// consumer group config, the group settings apply to every consumer of the group
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConsumerGroup {
+    pub name: String,
+    // the request quota of every consumer of the group
+    // the lowest quota applies when a consumer is in more than one group
+    pub quota: Option<Quota>,
+}
+
+// request quota config, counted per consumer over the calendar periods in utc
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Quota {
+    // the requests per day, reset at midnight
+    pub daily: Option<u64>,
+    // the requests per month, reset on the first day of the month
+    pub monthly: Option<u64>,
+}
+
+// the access rules of a cluster or a route
+// the deny rules are checked first, then the consumer must match one of the allow rules
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Access {
+    pub allow: Option<Vec<AccessRule>>,
+    pub deny: Option<Vec<AccessRule>>,
+}
+
+// enum access rule
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AccessRule {
+    Consumer { consumer: String },
+    Group { group: String },
+    Acl { acl: String },
+}
+
+impl AccessRule {
+    // checks if the rule matches the consumer
+    pub fn matches(&self, consumer: &Consumer) -> bool {
+        match self {
+            AccessRule::Consumer { consumer: id } => consumer.get_id() == id,
+            AccessRule::Group { group } => consumer.get_groups().contains(group),
+            AccessRule::Acl { acl } => consumer.get_acl().contains(acl),
+        }
+    }
+}
+
+// consumer credentials config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Credentials {
+    // the api keys of the consumer
+    pub keys: Option<Vec<ApiKey>>,
+    // the basic auth credential of the consumer
+    pub basic: Option<BasicCredential>,
+    // the hmac signature credential of the consumer
+    pub hmac: Option<HmacCredential>,
+}
+
+// hmac credential config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HmacCredential {
+    // the username, the consumer name by default
+    pub username: Option<String>,
+    // the shared secret
+    pub secret: String,
+}
+
+// basic credential config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BasicCredential {
+    // the username, the consumer name by default
+    pub username: Option<String>,
+    // the bcrypt, sha or argon2 password hash, as written by htpasswd
+    pub password: String,
+}
+
+// api key config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiKey {
+    // the key in plain text, as "sha256:<hex digest>" or as an argon2 hash
+    pub key: String,
+    // the rfc3339 expiration time, the key never expires by default
+    pub expires_at: Option<String>,
+    // the public prefix of the presented key, required by the argon2 keys
+    pub prefix: Option<String>,
+}
+
+// the main limiter configuration
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Limiter {
+    global: Option<RatelimitType>,
+    client: Option<RatelimitType>,
+    // sends the x-rate-limit headers with the ratelimit headers, false by default
+    legacy_headers: Option<bool>,
+}
+
+impl Limiter {
+    // build the limiter instances, the keys are namespaced by the cluster or the route
+    pub fn build_limiters(&self, namespace: &str) -> Limiters {
+        Limiters::new(self, namespace)
+    }
+    pub fn get_global(&self) -> &Option<RatelimitType> {
+        &self.global
+    }
+    pub fn get_client(&self) -> &Option<RatelimitType> {
+        &self.client
+    }
+    pub fn get_legacy_headers(&self) -> bool {
+        self.legacy_headers.unwrap_or(false)
+    }
+}
+
+// enum rate limit type
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RatelimitType {
+    Basic { basic: BasicLimiter },
+    Redis { redis: RedisLimiter },
+}
+
+// basic limiter config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BasicLimiter {
+    pub limit: isize,
+    // the window of the limit, a minute by default
+    pub window: Option<LimitWindow>,
+    // the parts of the limit key, joined when more than one is given
+    // the global limiter counts every request together by default
+    // the client limiter uses the authorization header, then the client ip by default
+    pub key: Option<Vec<LimitKey>>,
+    // what to do with the requests missing a part of the key, skip by default
+    pub missing_key: Option<MissingKey>,
+}
+
+impl BasicLimiter {
+    pub fn get_window(&self) -> Duration {
+        self.window.unwrap_or(LimitWindow::Minute).get_duration()
+    }
+}
+
+// enum limit key part
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LimitKey {
+    Client(ClientKey),
+    Header { header: String },
+    Claim { claim: String },
+    Param { param: String },
+    Query { query: String },
+}
+
+// enum limit key of the client
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKey {
+    Ip,
+    Consumer,
+}
+
+// enum missing limit key policy
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingKey {
+    // the request is not limited
+    Skip,
+    // the request is rejected with 400
+    Reject,
+}
+
+// enum limit window
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitWindow {
+    Second,
+    Minute,
+    Hour,
+}
+
+impl LimitWindow {
+    pub fn get_duration(&self) -> Duration {
+        match self {
+            LimitWindow::Second => Duration::from_secs(1),
+            LimitWindow::Minute => Duration::from_secs(60),
+            LimitWindow::Hour => Duration::from_secs(3600),
+        }
+    }
+}
+
+// redis limiter config
+// the limit is shared by every gateway using the same redis
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RedisLimiter {
+    pub limit: isize,
+    // the window of the limit, a minute by default
+    pub window: Option<LimitWindow>,
+    // the algorithm counting the requests, fixed-window by default
+    pub algorithm: Option<RedisAlgorithm>,
+    // the parts of the limit key, the same as the basic limiter
+    pub key: Option<Vec<LimitKey>>,
+    // what to do with the requests missing a part of the key, skip by default
+    pub missing_key: Option<MissingKey>,
+    // what to do with the requests when redis is unreachable, open by default
+    pub failure_mode: Option<FailureMode>,
+    // the redis connection
+    #[serde(flatten)]
+    pub redis: Redis,
+}
+
+impl RedisLimiter {
+    pub fn get_window(&self) -> Duration {
+        self.window.unwrap_or(LimitWindow::Minute).get_duration()
+    }
+}
+
+// enum redis limiter algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedisAlgorithm {
+    FixedWindow,
+    SlidingWindow,
+    TokenBucket,
+    LeakyBucket,
+}
+
+// concurrency limit config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Concurrency {
+    // the maximum in-flight requests of a key
+    pub limit: usize,
+    // the parts of the concurrency key, every request is counted together by default
+    pub key: Option<Vec<LimitKey>>,
+    // what to do with the requests missing a part of the key, skip by default
+    pub missing_key: Option<MissingKey>,
+    // the status of the rejected requests, 503 or 429, 503 by default
+    pub status: Option<usize>,
+    // the requests over the limit wait for a free slot, rejected right away by default
+    pub queue: Option<ConcurrencyQueue>,
+    // shares the slots with every gateway through redis, counted in memory by default
+    pub redis: Option<Redis>,
+    // the seconds a redis slot is held at most, 60 by default
+    // frees the slots of a stopped gateway
+    pub lease: Option<u64>,
+    // what to do when redis is unreachable, open by default
+    pub failure_mode: Option<FailureMode>,
+}
+
+impl Concurrency {
+    // build the concurrency limiter, the keys are namespaced by the cluster or the route
+    pub fn build_limiter(&self, namespace: &str) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(namespace.to_string(), self)
+    }
+}
+
+// concurrency queue config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConcurrencyQueue {
+    // the maximum waiting requests
+    pub size: usize,
+    // the milliseconds a request waits at most, 1000 by default
+    pub timeout: Option<u64>,
+}
+
+// enum adaptive concurrency algorithm
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AdaptiveConcurrency {
+    Gradient { gradient: GradientLimit },
+    Aimd { aimd: AimdLimit },
+}
+
+impl AdaptiveConcurrency {
+    // build the adaptive limiter of the cluster
+    pub fn build_limiter(&self, cluster: &str) -> Arc<AdaptiveLimiter> {
+        Arc::new(AdaptiveLimiter::new(cluster.to_string(), self))
+    }
+}
+
+// the bounds of an adaptive limit
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdaptiveLimits {
+    // the limit before any sample, 20 by default
+    pub initial_limit: Option<usize>,
+    // 1 by default
+    pub min_limit: Option<usize>,
+    // 1000 by default
+    pub max_limit: Option<usize>,
+}
+
+impl AdaptiveLimits {
+    // returns the initial, min and max limits
+    pub fn get_limits(&self) -> (usize, usize, usize) {
+        let min_limit = self.min_limit.unwrap_or(1).max(1);
+        let max_limit = self.max_limit.unwrap_or(1000).max(min_limit);
+        let initial_limit = self.initial_limit.unwrap_or(20).clamp(min_limit, max_limit);
+        (initial_limit, min_limit, max_limit)
+    }
+}
+
+// aimd limit config
+// the limit grows by one with every fast response and is cut on the errors
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AimdLimit {
+    #[serde(flatten)]
+    pub limits: AdaptiveLimits,
+    // the ratio the limit is multiplied by on a drop, 0.9 by default
+    pub backoff_ratio: Option<f64>,
+    // the upstream latency in milliseconds counted as a drop, 1000 by default
+    pub timeout: Option<u64>,
+}
+
+// gradient limit config
+// the limit follows the ratio between the long term and the current upstream latency
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GradientLimit {
+    #[serde(flatten)]
+    pub limits: AdaptiveLimits,
+    // the weight of a new limit, 0.2 by default
+    pub smoothing: Option<f64>,
+    // the latency ratio over the long term latency that is tolerated, 1.5 by default
+    pub tolerance: Option<f64>,
+}
+
+// enum failure mode of the external dependencies
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    // the request continues
+    Open,
+    // the request is rejected with 503
+    Closed,
+}
+
+// headers config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Headers {
+    // used for storing a list headers data to be inserted
+    pub insert: Option<Vec<InsertHeader>>,
+    // used for removing a list of headers
+    pub remove: Option<Vec<RemoveHeader>>,
+}
+
+impl Headers {
+    pub fn to_be_inserted(&self) -> &Option<Vec<InsertHeader>> {
+        &self.insert
+    }
+    pub fn to_be_removed(&self) -> &Option<Vec<RemoveHeader>> {
+        &self.remove
+    }
+}
+
+// insert header config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InsertHeader {
+    // header key
+    pub key: String,
+    // header value
+    pub value: String,
+}
+
+// remove header config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RemoveHeader {
+    // header key
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub headers: Option<Headers>,
+}
+
+impl Request {
+    pub fn get_headers(&self) -> &Option<Headers> {
+        &self.headers
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Response {
+    pub headers: Option<Headers>,
+}
+
+impl Response {
+    pub fn get_headers(&self) -> &Option<Headers> {
+        &self.headers
+    }
+}
+
+// forwarded identity config
+// the identity of the authenticated consumer is forwarded as upstream headers
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Identity {
+    // the consumer name header, X-Consumer-Name by default
+    pub consumer_header: Option<String>,
+    // the consumer groups header, X-Consumer-Groups by default
+    pub groups_header: Option<String>,
+    // the jwt claims forwarded as headers, e.g. sub is forwarded as X-Claim-sub
+    pub claims: Option<Vec<String>>,
+    // the claim header prefix, X-Claim- by default
+    pub claim_prefix: Option<String>,
+    // remove the authorization header and the credential source, so the upstream only trusts the gateway
+    pub strip_authorization: Option<bool>,
+}
+
+impl Identity {
+    pub fn get_consumer_header(&self) -> &str {
+        self.consumer_header.as_deref().unwrap_or("X-Consumer-Name")
+    }
+    pub fn get_groups_header(&self) -> &str {
+        self.groups_header.as_deref().unwrap_or("X-Consumer-Groups")
+    }
+    pub fn get_claim_prefix(&self) -> &str {
+        self.claim_prefix.as_deref().unwrap_or("X-Claim-")
+    }
+}
+
+// request id config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RequestId {
+    // the header used for reading, forwarding and echoing the request id
+    // the default header is X-Request-Id
+    pub header: Option<String>,
+}
+
+impl RequestId {
+    pub fn get_header(&self) -> String {
+        self.header
+            .clone()
+            .unwrap_or_else(|| "X-Request-Id".to_string())
+    }
+}
+
+// access log config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccessLog {
+    // the log format, json by default
+    pub format: Option<LogFormat>,
+    // the text template used by the text format
+    // variables are written as $name, e.g. "$method $path $status"
+    pub template: Option<String>,
+    // the log file, the logs are written to stdout if not provided
+    pub file: Option<LogFile>,
+    // the maximum pending log entries, new entries are dropped when full
+    pub buffer: Option<usize>,
+}
+
+// enum log format
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+// log file config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogFile {
+    // the path of the active log file
+    pub path: String,
+    // rotate when the file exceeds the size in MB
+    pub max_size: Option<u64>,
+    // rotate on a time basis
+    pub rotation: Option<LogRotation>,
+    // the number of rotated files to keep, all files are kept by default
+    pub max_files: Option<usize>,
+}
+
+// enum log rotation
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+// admin api config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Admin {
+    // the admin listener address, e.g. 127.0.0.1:6189
+    pub address: String,
+    // the bearer token of the admin requests, the metrics are open
+    pub token: Option<String>,
+    // the client addresses allowed to send the admin requests, the metrics are open
+    pub whitelist: Option<Vec<String>>,
+}
+
+// quota counters config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Quotas {
+    // where the counters are kept, a file or redis
+    pub storage: QuotaStorage,
+    // the status of the over quota requests, 429 or 403, 429 by default
+    pub status: Option<usize>,
+    // the message of the over quota requests
+    pub message: Option<String>,
+    // what to do when redis is unreachable, open by default
+    pub failure_mode: Option<FailureMode>,
+}
+
+impl Quotas {
+    pub fn get_status(&self) -> usize {
+        self.status.unwrap_or(429)
+    }
+    pub fn get_message(&self) -> String {
+        self.message
+            .clone()
+            .unwrap_or_else(|| "Quota exceeded".to_string())
+    }
+}
+
+// enum quota counters storage
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum QuotaStorage {
+    Redis { redis: Redis },
+    File { file: QuotaFile },
+}
+
+// quota file config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QuotaFile {
+    // the path of the counters file
+    pub path: String,
+    // the seconds between the writes of the counters, 5 by default
+    pub flush_interval: Option<u64>,
+}
+
+// opentelemetry tracing config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Telemetry {
+    // the service name reported to the collector, glaive by default
+    pub service_name: Option<String>,
+    // the otlp collector endpoint, e.g. http://localhost:4317
+    pub endpoint: String,
+    // the otlp protocol, grpc by default
+    pub protocol: Option<OtlpProtocol>,
+    // the ratio of sampled traces between 0.0 and 1.0, every trace is sampled by default
+    // the sampling decision of the incoming traceparent is always respected
+    pub sample_ratio: Option<f64>,
+    // the export timeout in milliseconds
+    pub timeout: Option<u64>,
+}
+
+// enum otlp protocol
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    Grpc,
+    Http,
+}
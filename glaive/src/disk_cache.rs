@@ -0,0 +1,753 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use pingora::cache::eviction::EvictionManager;
+use pingora::cache::key::{CacheHashKey, CacheKey, CompactCacheKey};
+use pingora::cache::storage::{HandleHit, HandleMiss, HitHandler, MissHandler};
+use pingora::cache::trace::SpanHandle;
+use pingora::cache::{CacheMeta, PurgeType, Storage};
+use pingora::{Error, ErrorType, Result};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::warn;
+
+use crate::cache::MemoryStorage;
+
+// the serialized cache meta and its header
+type BinaryMeta = (Vec<u8>, Vec<u8>);
+
+// the size of the body chunks read from the disk
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// the least recently used order of the objects on disk
+#[derive(Default)]
+struct DiskIndex {
+    // the size and the last use of every object
+    entries: HashMap<String, (u64, u64)>,
+    // the objects by their last use
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    used: u64,
+}
+
+impl DiskIndex {
+    // mark the object as used
+    fn touch(&mut self, hash: &str) {
+        if let Some((_, last_use)) = self.entries.get_mut(hash) {
+            self.order.remove(last_use);
+            self.tick += 1;
+            *last_use = self.tick;
+            self.order.insert(self.tick, hash.to_string());
+        }
+    }
+
+    // add the object, returns the objects evicted to stay under the capacity
+    fn insert(&mut self, hash: &str, size: u64, capacity: u64) -> Vec<String> {
+        self.remove(hash);
+        self.tick += 1;
+        self.entries.insert(hash.to_string(), (size, self.tick));
+        self.order.insert(self.tick, hash.to_string());
+        self.used += size;
+        let mut evicted = vec![];
+        while self.used > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.entries.remove(&oldest) {
+                self.used -= size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, hash: &str) -> bool {
+        match self.entries.remove(hash) {
+            Some((size, last_use)) => {
+                self.order.remove(&last_use);
+                self.used -= size;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// the cache storage on a local directory
+// every object is a meta file and a body file named by the key hash
+pub struct DiskStorage {
+    directory: PathBuf,
+    // the maximum size of every object on disk in bytes
+    capacity: u64,
+    index: Mutex<DiskIndex>,
+    /// Maximum allowed body size for caching
+    max_file_size_bytes: Option<usize>,
+    /// Will reject cache admissions with empty body responses
+    reject_empty_body: bool,
+}
+
+impl DiskStorage {
+    // open the cache directory, the index is rebuilt from the files found
+    pub fn new(directory: &str, capacity: u64) -> Self {
+        let directory = PathBuf::from(directory);
+        std::fs::create_dir_all(&directory).unwrap_or_else(|e| {
+            panic!(
+                "unable to create the cache directory {:?}: {}",
+                directory, e
+            )
+        });
+        let storage = DiskStorage {
+            directory,
+            capacity,
+            index: Mutex::new(DiskIndex::default()),
+            max_file_size_bytes: None,
+            reject_empty_body: false,
+        };
+        storage.rebuild_index();
+        storage
+    }
+    // max file size config when cache occurs
+    pub fn with_max_file_size(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_file_size_bytes = max_bytes;
+        self
+    }
+    // reject empty cache config when cache occurs
+    pub fn with_reject_empty_body(mut self, should_error: bool) -> Self {
+        self.reject_empty_body = should_error;
+        self
+    }
+
+    // the path of an object file, the objects are spread over the directories by hash prefix
+    fn object_path(&self, hash: &str, extension: &str) -> PathBuf {
+        self.directory
+            .join(&hash[..2])
+            .join(format!("{}.{}", hash, extension))
+    }
+
+    // rebuild the index from the files, the least recently modified objects are evicted first
+    // the unfinished writes and the incomplete objects are removed
+    fn rebuild_index(&self) {
+        let mut objects: HashMap<String, (u64, bool, bool, std::time::SystemTime)> = HashMap::new();
+        let directories = std::fs::read_dir(&self.directory).into_iter().flatten();
+        for directory in directories.flatten() {
+            let files = std::fs::read_dir(directory.path()).into_iter().flatten();
+            for file in files.flatten() {
+                let path = file.path();
+                let (Some(hash), Some(extension)) = (
+                    path.file_stem().and_then(|stem| stem.to_str()),
+                    path.extension().and_then(|extension| extension.to_str()),
+                ) else {
+                    continue;
+                };
+                let Ok(metadata) = file.metadata() else {
+                    continue;
+                };
+                let object = objects.entry(hash.to_string()).or_insert((
+                    0,
+                    false,
+                    false,
+                    std::time::SystemTime::UNIX_EPOCH,
+                ));
+                match extension {
+                    "meta" => object.1 = true,
+                    "body" => object.2 = true,
+                    _ => {
+                        let _ = std::fs::remove_file(&path);
+                        continue;
+                    }
+                }
+                object.0 += metadata.len();
+                if let Ok(modified) = metadata.modified() {
+                    object.3 = object.3.max(modified);
+                }
+            }
+        }
+        let mut complete = vec![];
+        for (hash, (size, meta, body, modified)) in objects {
+            if meta && body {
+                complete.push((modified, hash, size));
+            } else {
+                let _ = std::fs::remove_file(self.object_path(&hash, "meta"));
+                let _ = std::fs::remove_file(self.object_path(&hash, "body"));
+            }
+        }
+        complete.sort();
+        let mut index = self.index.lock().unwrap();
+        for (_, hash, size) in complete {
+            for evicted in index.insert(&hash, size, self.capacity) {
+                let _ = std::fs::remove_file(self.object_path(&evicted, "meta"));
+                let _ = std::fs::remove_file(self.object_path(&evicted, "body"));
+            }
+        }
+    }
+
+    // remove the files of the objects
+    async fn remove_objects(&self, hashes: &[String]) {
+        for hash in hashes {
+            let _ = fs::remove_file(self.object_path(hash, "meta")).await;
+            let _ = fs::remove_file(self.object_path(hash, "body")).await;
+        }
+    }
+
+    // write the meta file, replaced at once so a reader never sees it half written
+    async fn write_meta(&self, hash: &str, (raw_meta, raw_header): &BinaryMeta) -> Result<u64> {
+        let mut content = Vec::with_capacity(4 + raw_meta.len() + raw_header.len());
+        content.extend_from_slice(&(raw_meta.len() as u32).to_le_bytes());
+        content.extend_from_slice(&raw_meta);
+        content.extend_from_slice(&raw_header);
+        let path = self.object_path(hash, "meta");
+        let temporary = temporary_path(&path);
+        fs::write(&temporary, &content)
+            .await
+            .map_err(storage_error)?;
+        fs::rename(&temporary, &path).await.map_err(storage_error)?;
+        Ok(content.len() as u64)
+    }
+}
+
+// a unique path next to the object file for the unfinished writes
+fn temporary_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{}.tmp", uuid::Uuid::now_v7().simple()))
+}
+
+// wrap the io error as a cache storage error
+fn storage_error(e: std::io::Error) -> Box<Error> {
+    Error::because(ErrorType::Custom("cache disk error"), "disk storage", e)
+}
+
+impl DiskStorage {
+    // find the meta and open the body of the object
+    async fn find(&self, key: &CacheKey) -> Result<Option<(CacheMeta, DiskHitHandler)>> {
+        let hash = key.combined();
+        let content = match fs::read(self.object_path(&hash, "meta")).await {
+            Ok(content) => content,
+            Err(_) => return Ok(None),
+        };
+        let body = match File::open(self.object_path(&hash, "body")).await {
+            Ok(body) => body,
+            Err(_) => return Ok(None),
+        };
+        let body_len = body.metadata().await.map_err(storage_error)?.len() as usize;
+        // the meta length prefix, then the meta and the header
+        let meta_len = content
+            .get(..4)
+            .map(|len| u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+            .filter(|len| 4 + len <= content.len())
+            .ok_or_else(|| {
+                Error::explain(ErrorType::Custom("cache disk error"), "corrupted meta")
+            })?;
+        let meta = CacheMeta::deserialize(&content[4..4 + meta_len], &content[4 + meta_len..])?;
+        self.index.lock().unwrap().touch(&hash);
+        Ok(Some((
+            meta,
+            DiskHitHandler {
+                file: body,
+                body_len,
+                position: 0,
+                range_end: body_len,
+                pending_seek: false,
+            },
+        )))
+    }
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    // cache lookup is responsible for finding the cache data
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let hit = self.find(key).await?;
+        Ok(hit.map(|(meta, hit)| (meta, Box::new(hit) as HitHandler)))
+    }
+
+    // get_miss_handler is executed when cache did not hit
+    // the body is streamed to a temporary file
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let hash = key.combined();
+        let path = self.object_path(&hash, "body");
+        fs::create_dir_all(self.directory.join(&hash[..2]))
+            .await
+            .map_err(storage_error)?;
+        let temporary = temporary_path(&path);
+        let file = File::create(&temporary).await.map_err(storage_error)?;
+        Ok(Box::new(DiskMissHandler {
+            meta: meta.serialize()?,
+            hash,
+            file: Some(file),
+            temporary,
+            written: 0,
+            inner: self,
+        }))
+    }
+
+    // purge is used to remove the object files
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let hash = key.combined();
+        let removed = self.index.lock().unwrap().remove(&hash);
+        self.remove_objects(&[hash]).await;
+        Ok(removed)
+    }
+
+    // update_meta is used to replace the meta file of an object
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let hash = key.combined();
+        if fs::metadata(self.object_path(&hash, "body")).await.is_err() {
+            return Err(Error::create(
+                ErrorType::Custom("No meta found for update_meta"),
+                pingora::ErrorSource::Internal,
+                Some(format!("key = {:?}", key).into()),
+                None,
+            ));
+        }
+        self.write_meta(&hash, &meta.serialize()?).await?;
+        self.index.lock().unwrap().touch(&hash);
+        Ok(true)
+    }
+
+    // currently not using partial write
+    fn support_streaming_partial_write(&self) -> bool {
+        false
+    }
+    // ignored
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+// hit handler streaming the body from the disk
+pub struct DiskHitHandler {
+    file: File,
+    body_len: usize,
+    position: usize,
+    range_end: usize,
+    // the file is moved to the position on the next read
+    pending_seek: bool,
+}
+
+#[async_trait]
+impl HandleHit for DiskHitHandler {
+    // read the next chunk of the body
+    async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        if self.pending_seek {
+            self.file
+                .seek(SeekFrom::Start(self.position as u64))
+                .await
+                .map_err(storage_error)?;
+            self.pending_seek = false;
+        }
+        if self.position >= self.range_end {
+            return Ok(None);
+        }
+        let len = (self.range_end - self.position).min(READ_CHUNK_SIZE);
+        let mut chunk = BytesMut::zeroed(len);
+        let read = self.file.read(&mut chunk).await.map_err(storage_error)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        chunk.truncate(read);
+        self.position += read;
+        Ok(Some(chunk.freeze()))
+    }
+
+    // when cache found, continue to the downstream
+    async fn finish(
+        self: Box<Self>,
+        _storage: &'static (dyn Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    // seek is responsible for validating the range of the body to be read
+    fn seek(&mut self, start: usize, end: Option<usize>) -> Result<()> {
+        if start >= self.body_len {
+            return Error::e_explain(
+                ErrorType::InternalError,
+                format!("seek start out of range {start} >= {}", self.body_len),
+            );
+        }
+        self.position = start;
+        self.range_end = end.map_or(self.body_len, |end| end.min(self.body_len));
+        self.pending_seek = true;
+        Ok(())
+    }
+
+    // ignored
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+// miss handler writing the body to a temporary file
+// the object is only visible once the whole body is written
+struct DiskMissHandler {
+    meta: BinaryMeta,
+    hash: String,
+    file: Option<File>,
+    temporary: PathBuf,
+    written: usize,
+    inner: &'static DiskStorage,
+}
+
+#[async_trait]
+impl HandleMiss for DiskMissHandler {
+    // the write body will validate if the response is cacheable by size
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        if let Some(max_file_size_bytes) = self.inner.max_file_size_bytes {
+            if self.written + data.len() > max_file_size_bytes {
+                return Error::e_explain(
+                    pingora::cache::max_file_size::ERR_RESPONSE_TOO_LARGE,
+                    format!(
+                        "writing data of size {} bytes would exceed max file size of {} bytes",
+                        data.len(),
+                        max_file_size_bytes
+                    ),
+                );
+            }
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(&data).await.map_err(storage_error)?;
+        }
+        self.written += data.len();
+        Ok(())
+    }
+
+    // move the body in place, then write the meta and evict the least recently used objects
+    async fn finish(mut self: Box<Self>) -> Result<usize> {
+        if self.written == 0 && self.inner.reject_empty_body {
+            let err = Error::create(
+                ErrorType::Custom("cache write error: empty body"),
+                pingora::ErrorSource::Internal,
+                None,
+                None,
+            );
+            return Err(err);
+        }
+        if let Some(mut file) = self.file.take() {
+            file.flush().await.map_err(storage_error)?;
+        }
+        let path = self.inner.object_path(&self.hash, "body");
+        fs::rename(&self.temporary, &path)
+            .await
+            .map_err(storage_error)?;
+        let meta_len = self.inner.write_meta(&self.hash, &self.meta).await?;
+        let size = self.written + meta_len as usize;
+        let evicted =
+            self.inner
+                .index
+                .lock()
+                .unwrap()
+                .insert(&self.hash, size as u64, self.inner.capacity);
+        self.inner.remove_objects(&evicted).await;
+        Ok(size)
+    }
+}
+
+impl Drop for DiskMissHandler {
+    // the unfinished body is removed
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            if let Err(e) = std::fs::remove_file(&self.temporary) {
+                warn!(
+                    "unable to remove the cache file {:?}: {}",
+                    self.temporary, e
+                );
+            }
+        }
+    }
+}
+
+// the memory tier in front of the disk tier
+// the responses are written to both tiers, the memory tier is evicted on its own
+pub struct TieredStorage {
+    memory: MemoryStorage,
+    disk: DiskStorage,
+    // the eviction manager of the memory tier, the promoted objects are admitted to it
+    eviction: Option<&'static (dyn EvictionManager + Sync)>,
+}
+
+impl TieredStorage {
+    pub fn new(memory: MemoryStorage, disk: DiskStorage) -> Self {
+        TieredStorage {
+            memory,
+            disk,
+            eviction: None,
+        }
+    }
+    // provide the eviction manager of the memory tier
+    pub fn with_eviction(mut self, eviction: &'static (dyn EvictionManager + Sync)) -> Self {
+        self.eviction = Some(eviction);
+        self
+    }
+    // whether a body of this length is accepted by the memory tier
+    fn fits_memory(&self, body_len: usize) -> bool {
+        if body_len == 0 && self.memory.reject_empty_body {
+            return false;
+        }
+        self.memory
+            .max_file_size_bytes
+            .is_none_or(|max_file_size_bytes| body_len <= max_file_size_bytes)
+    }
+    // copy the disk object to the memory tier and admit it to the eviction manager
+    async fn promote(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        mut hit: DiskHitHandler,
+        trace: &SpanHandle,
+    ) -> Result<()> {
+        let mut body = BytesMut::with_capacity(hit.body_len);
+        while let Some(chunk) = hit.read_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+        let mut miss = self.memory.get_miss_handler(key, meta, trace).await?;
+        miss.write_body(body.freeze(), true).await?;
+        let size = miss.finish().await?;
+        if let Some(eviction) = self.eviction {
+            let evicted = eviction.admit(key.to_compact(), size, meta.fresh_until());
+            for item in evicted {
+                let _ = self.memory.purge(&item, PurgeType::Eviction, trace).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    // the memory tier is looked up first
+    // the disk hits are promoted to the memory tier when they fit in it
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        if let Some(hit) = self.memory.lookup(key, trace).await? {
+            return Ok(Some(hit));
+        }
+        let (meta, hit) = match self.disk.find(key).await? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if !self.fits_memory(hit.body_len) {
+            return Ok(Some((meta, Box::new(hit))));
+        }
+        if let Err(e) = self.promote(key, &meta, hit, trace).await {
+            warn!("unable to promote the cache object to memory: {}", e);
+        }
+        // the promoted object may already be evicted from the memory tier
+        match self.memory.lookup(key, trace).await? {
+            Some(hit) => Ok(Some(hit)),
+            None => self.disk.lookup(key, trace).await,
+        }
+    }
+
+    // the response is written to both tiers
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        Ok(Box::new(TieredMissHandler {
+            memory: Some(self.memory.get_miss_handler(key, meta, trace).await?),
+            disk: self.disk.get_miss_handler(key, meta, trace).await?,
+        }))
+    }
+
+    // the evictions only concern the memory tier, the invalidations remove both tiers
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        purge_type: PurgeType,
+        trace: &SpanHandle,
+    ) -> Result<bool> {
+        let memory = self.memory.purge(key, purge_type, trace).await?;
+        if matches!(purge_type, PurgeType::Eviction) {
+            return Ok(memory);
+        }
+        let disk = self.disk.purge(key, purge_type, trace).await?;
+        Ok(memory || disk)
+    }
+
+    // the memory tier may not hold the object anymore
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> Result<bool> {
+        let _ = self.memory.update_meta(key, meta, trace).await;
+        self.disk.update_meta(key, meta, trace).await
+    }
+
+    // currently not using partial write
+    fn support_streaming_partial_write(&self) -> bool {
+        false
+    }
+    // ignored
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+// miss handler writing to both tiers
+// the memory tier is skipped once the response is too large for it
+struct TieredMissHandler {
+    memory: Option<MissHandler>,
+    disk: MissHandler,
+}
+
+#[async_trait]
+impl HandleMiss for TieredMissHandler {
+    async fn write_body(&mut self, data: Bytes, eof: bool) -> Result<()> {
+        if let Some(memory) = &mut self.memory {
+            if memory.write_body(data.clone(), eof).await.is_err() {
+                self.memory = None;
+            }
+        }
+        self.disk.write_body(data, eof).await
+    }
+
+    async fn finish(self: Box<Self>) -> Result<usize> {
+        if let Some(memory) = self.memory {
+            let _ = memory.finish().await;
+        }
+        self.disk.finish().await
+    }
+}
+
+#[cfg(test)]
+mod disk_cache_mod {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use pingora::cache::eviction::lru::Manager as LRUEvictionManager;
+    use pingora::cache::trace::Span;
+    use pingora::http::ResponseHeader;
+
+    fn storage(directory: &Path, capacity: u64) -> &'static DiskStorage {
+        Box::leak(Box::new(DiskStorage::new(
+            directory.to_str().unwrap(),
+            capacity,
+        )))
+    }
+
+    async fn write(storage: &'static DiskStorage, key: &CacheKey, body: &str) {
+        let now = SystemTime::now();
+        let meta = CacheMeta::new(
+            now + Duration::from_secs(60),
+            now,
+            0,
+            0,
+            ResponseHeader::build(200, None).unwrap(),
+        );
+        let span = Span::inactive().handle();
+        let mut miss = storage.get_miss_handler(key, &meta, &span).await.unwrap();
+        miss.write_body(Bytes::from(body.to_string()), true)
+            .await
+            .unwrap();
+        miss.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn storage_test() {
+        let directory = std::env::temp_dir().join(format!("cache-{}", uuid::Uuid::now_v7()));
+        let disk = storage(&directory, 1024);
+        let span = Span::inactive().handle();
+        let first = CacheKey::new("cluster", "/first", "");
+        write(disk, &first, "hello world").await;
+        let (_, mut hit) = disk.lookup(&first, &span).await.unwrap().unwrap();
+        hit.seek(6, None).unwrap();
+        assert_eq!(hit.read_body().await.unwrap().unwrap(), "world");
+        // the least recently used object is evicted over the capacity
+        let second = CacheKey::new("cluster", "/second", "");
+        write(disk, &second, &"a".repeat(900)).await;
+        assert!(disk.lookup(&first, &span).await.unwrap().is_none());
+        // the index is rebuilt from the files
+        let reopened = storage(&directory, 1024);
+        assert!(reopened.lookup(&second, &span).await.unwrap().is_some());
+        assert_eq!(reopened.index.lock().unwrap().entries.len(), 1);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn tiered_test() {
+        let directory = std::env::temp_dir().join(format!("cache-{}", uuid::Uuid::now_v7()));
+        let eviction: &'static LRUEvictionManager<1> =
+            Box::leak(Box::new(LRUEvictionManager::with_capacity(1024, 16)));
+        let tiered: &'static TieredStorage = Box::leak(Box::new(
+            TieredStorage::new(
+                MemoryStorage::with_capacity(16).with_max_file_size(Some(100)),
+                DiskStorage::new(directory.to_str().unwrap(), 1024 * 1024),
+            )
+            .with_eviction(eviction),
+        ));
+        let disk: &'static DiskStorage = &tiered.disk;
+        let span = Span::inactive().handle();
+        // the disk hit is promoted to the memory tier
+        let small = CacheKey::new("cluster", "/small", "");
+        write(disk, &small, "hello world").await;
+        assert!(tiered.memory.cache.get(&small.combined_bin()).is_none());
+        let (_, mut hit) = tiered.lookup(&small, &span).await.unwrap().unwrap();
+        assert_eq!(hit.read_body().await.unwrap().unwrap(), "hello world");
+        assert!(tiered.memory.cache.get(&small.combined_bin()).is_some());
+        assert!(eviction.peek(&small.to_compact()));
+        // the body over the memory size limit is served from the disk only
+        let large = CacheKey::new("cluster", "/large", "");
+        write(disk, &large, &"a".repeat(200)).await;
+        let (_, mut hit) = tiered.lookup(&large, &span).await.unwrap().unwrap();
+        assert_eq!(hit.read_body().await.unwrap().unwrap().len(), 200);
+        assert!(tiered.memory.cache.get(&large.combined_bin()).is_none());
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
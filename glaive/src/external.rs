@@ -0,0 +1,351 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use pingora::http::RequestHeader;
+
+use bytes::Bytes;
+use http::{header, HeaderName, HeaderValue};
+
+use crate::credential::{sha256_hex, CredentialCache};
+use crate::def::External;
+
+// the default subrequest timeout in milliseconds
+const DEFAULT_TIMEOUT: u64 = 2000;
+
+// the decision of the auth service
+pub enum ExternalDecision {
+    // the request is allowed, the headers are copied to the upstream request
+    Allow {
+        headers: Vec<(HeaderName, HeaderValue)>,
+        consumer: Option<String>,
+    },
+    // the request is denied, the response is relayed to the client
+    Deny {
+        status: u16,
+        headers: Vec<(HeaderName, HeaderValue)>,
+        body: Bytes,
+    },
+}
+
+// the forward auth client
+// the decisions are cached per request, by the method, uri, client and forwarded headers
+pub struct ExternalAuthenticator {
+    client: reqwest::Client,
+    url: String,
+    request_headers: Vec<HeaderName>,
+    upstream_headers: Vec<HeaderName>,
+    consumer_header: Option<HeaderName>,
+    cache_ttl: Duration,
+    cache: CredentialCache<Arc<ExternalDecision>>,
+}
+
+impl ExternalAuthenticator {
+    pub fn new(config: &External) -> ExternalAuthenticator {
+        let timeout = Duration::from_millis(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("unable to build the external auth client");
+        let request_headers = config
+            .request_headers
+            .clone()
+            .unwrap_or_else(|| vec!["Authorization".to_string(), "Cookie".to_string()]);
+        ExternalAuthenticator {
+            client,
+            url: config.url.clone(),
+            request_headers: parse_header_names(&request_headers),
+            upstream_headers: parse_header_names(
+                config.upstream_headers.as_deref().unwrap_or_default(),
+            ),
+            consumer_header: config
+                .consumer_header
+                .as_ref()
+                .map(|name| parse_header_name(name)),
+            cache_ttl: Duration::from_secs(config.cache_ttl.unwrap_or(0)),
+            cache: CredentialCache::new(),
+        }
+    }
+
+    // the auth response headers copied to the upstream request
+    pub fn get_upstream_headers(&self) -> &Vec<HeaderName> {
+        &self.upstream_headers
+    }
+
+    // ask the auth service for the decision of the request
+    // the original uri is the uri before the cluster prefix is removed
+    pub async fn check(
+        &self,
+        req: &RequestHeader,
+        original_uri: Option<&str>,
+        client_address: Option<&str>,
+    ) -> Result<Arc<ExternalDecision>, String> {
+        // the selected request headers are the credential
+        let forwarded: Vec<(&HeaderName, &HeaderValue)> = self
+            .request_headers
+            .iter()
+            .filter_map(|name| req.headers.get(name).map(|value| (name, value)))
+            .collect();
+        let forwarded_uri = original_uri.map_or_else(|| req.uri.to_string(), |uri| uri.to_string());
+        let host = req.headers.get(header::HOST);
+        // the decision is cached for everything sent to the auth service
+        let cache_key = self.cache_key(
+            req.method.as_str(),
+            &forwarded_uri,
+            host,
+            client_address,
+            &forwarded,
+        );
+        if let Some(decision) = self.cache.get(&cache_key) {
+            return Ok(decision);
+        }
+        // the subrequest has the original method, the original uri is sent as header
+        let mut subrequest = self.client.request(req.method.clone(), &self.url);
+        for (name, value) in forwarded {
+            subrequest = subrequest.header(name, value);
+        }
+        subrequest = subrequest
+            .header("X-Forwarded-Method", req.method.as_str())
+            .header("X-Forwarded-Uri", forwarded_uri);
+        if let Some(host) = host {
+            subrequest = subrequest.header("X-Forwarded-Host", host);
+        }
+        if let Some(client_address) = client_address {
+            subrequest = subrequest.header("X-Forwarded-For", client_address);
+        }
+        let response = subrequest.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        let decision = if status.is_success() {
+            let headers = self
+                .upstream_headers
+                .iter()
+                .filter_map(|name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .map(|value| (name.clone(), value.clone()))
+                })
+                .collect();
+            let consumer = self.consumer_header.as_ref().and_then(|name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string())
+            });
+            ExternalDecision::Allow { headers, consumer }
+        } else {
+            // the hop by hop and length headers are set by the gateway
+            let headers = response
+                .headers()
+                .iter()
+                .filter(|(name, _)| !is_hop_header(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            let body = response.bytes().await.map_err(|e| e.to_string())?;
+            ExternalDecision::Deny {
+                status: status.as_u16(),
+                headers,
+                body,
+            }
+        };
+        let decision = Arc::new(decision);
+        self.cache
+            .insert(cache_key, Arc::clone(&decision), self.cache_ttl);
+        Ok(decision)
+    }
+
+    // the cache key is the digest of the method, uri, host, client address and forwarded headers
+    // every part is length prefixed, so the parts never collide
+    fn cache_key(
+        &self,
+        method: &str,
+        uri: &str,
+        host: Option<&HeaderValue>,
+        client_address: Option<&str>,
+        forwarded: &[(&HeaderName, &HeaderValue)],
+    ) -> String {
+        let mut parts: Vec<&[u8]> = vec![
+            method.as_bytes(),
+            uri.as_bytes(),
+            host.map_or(b"", |host| host.as_bytes()),
+            client_address.map_or(b"", |address| address.as_bytes()),
+        ];
+        for (name, value) in forwarded {
+            parts.push(name.as_str().as_bytes());
+            parts.push(value.as_bytes());
+        }
+        let mut key = Vec::new();
+        for part in parts {
+            key.extend_from_slice(format!("{}:", part.len()).as_bytes());
+            key.extend_from_slice(part);
+        }
+        sha256_hex(&String::from_utf8_lossy(&key))
+    }
+}
+
+// parse the configured header name
+fn parse_header_name(name: &str) -> HeaderName {
+    HeaderName::from_bytes(name.as_bytes())
+        .unwrap_or_else(|_| panic!("invalid external auth header name: {}", name))
+}
+
+// parse the configured header names
+fn parse_header_names(names: &[String]) -> Vec<HeaderName> {
+    names.iter().map(|name| parse_header_name(name)).collect()
+}
+
+// the headers that are not relayed to the client
+fn is_hop_header(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection"
+            | "keep-alive"
+            | "transfer-encoding"
+            | "content-length"
+            | "proxy-connection"
+            | "upgrade"
+            | "te"
+            | "trailer"
+    )
+}
+
+#[cfg(test)]
+mod external_mod {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // a local auth service, the "allow" token is allowed and the others are denied
+    async fn mock_service(hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let mut buffer = vec![0; 4096];
+                let read = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..read]).to_lowercase();
+                let response = if request.contains("authorization: allow") {
+                    "HTTP/1.1 200 OK\r\nX-User: alice\r\nX-Internal: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                } else {
+                    "HTTP/1.1 403 Forbidden\r\nX-Reason: denied\r\nContent-Length: 6\r\nConnection: close\r\n\r\ndenied"
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/auth", address)
+    }
+
+    fn authenticator(url: String) -> ExternalAuthenticator {
+        ExternalAuthenticator::new(&External {
+            url,
+            request_headers: None,
+            upstream_headers: Some(vec!["X-User".to_string()]),
+            consumer_header: Some("X-User".to_string()),
+            timeout: Some(500),
+            cache_ttl: Some(60),
+        })
+    }
+
+    fn request(method: &str, uri: &str, authorization: &str) -> RequestHeader {
+        let mut req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+        req.insert_header("Authorization", authorization).unwrap();
+        req
+    }
+
+    #[test]
+    fn cache_key_test() {
+        let authenticator = authenticator("http://127.0.0.1:1/auth".to_string());
+        let name = HeaderName::from_static("authorization");
+        let value = HeaderValue::from_static("allow");
+        let forwarded = [(&name, &value)];
+        let key =
+            |method, uri, client| authenticator.cache_key(method, uri, None, client, &forwarded);
+        let public = key("GET", "/public", Some("10.0.0.1"));
+        assert_eq!(public, key("GET", "/public", Some("10.0.0.1")));
+        // the method, the uri and the client are part of the key
+        assert_ne!(public, key("DELETE", "/public", Some("10.0.0.1")));
+        assert_ne!(public, key("GET", "/admin/users", Some("10.0.0.1")));
+        assert_ne!(public, key("GET", "/public", Some("10.0.0.2")));
+        // the parts are length prefixed, so moving a separator changes the key
+        assert_ne!(
+            authenticator.cache_key("GET", "/a", None, Some("b"), &[]),
+            authenticator.cache_key("GET", "/ab", None, Some(""), &[])
+        );
+    }
+
+    #[tokio::test]
+    async fn decision_test() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let authenticator = authenticator(mock_service(hits.clone()).await);
+        let req = request("GET", "/public", "allow");
+        match authenticator
+            .check(&req, None, None)
+            .await
+            .unwrap()
+            .as_ref()
+        {
+            ExternalDecision::Allow { headers, consumer } => {
+                assert_eq!(consumer.as_deref(), Some("alice"));
+                assert_eq!(headers.len(), 1);
+                assert_eq!(headers[0].0, "x-user");
+            }
+            ExternalDecision::Deny { .. } => panic!("the request is allowed"),
+        }
+        // the same request is served from the cache
+        authenticator.check(&req, None, None).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // another method is asked again
+        let req = request("DELETE", "/public", "allow");
+        authenticator.check(&req, None, None).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        let req = request("GET", "/public", "deny");
+        match authenticator
+            .check(&req, None, None)
+            .await
+            .unwrap()
+            .as_ref()
+        {
+            ExternalDecision::Deny {
+                status,
+                headers,
+                body,
+            } => {
+                assert_eq!(*status, 403);
+                assert!(headers.iter().any(|(name, _)| name == "x-reason"));
+                assert!(!headers.iter().any(|(name, _)| name == "content-length"));
+                assert_eq!(body.as_ref(), b"denied");
+            }
+            ExternalDecision::Allow { .. } => panic!("the request is denied"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_test() {
+        // nothing listens on the discard port
+        let authenticator = authenticator("http://127.0.0.1:9/auth".to_string());
+        let req = request("GET", "/public", "allow");
+        assert!(authenticator.check(&req, None, None).await.is_err());
+    }
+}
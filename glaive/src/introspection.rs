@@ -0,0 +1,305 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use tracing::warn;
+
+use crate::credential::{sha256_hex, CredentialCache};
+use crate::def::{Introspection, TokenSource};
+use crate::jwt::Claims;
+use crate::redis::RedisConnection;
+
+// the default request timeout in milliseconds
+const DEFAULT_TIMEOUT: u64 = 2000;
+
+// the default maximum time in seconds a result is cached
+const DEFAULT_CACHE_TTL: u64 = 300;
+
+// the oauth 2.0 token introspection client
+// the active results are cached until the token exp, in memory and optionally in redis
+pub struct Introspector {
+    client: reqwest::Client,
+    url: String,
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    audience: Vec<String>,
+    consumer_field: String,
+    sources: Vec<TokenSource>,
+    cache_ttl: Duration,
+    cache: CredentialCache<Claims>,
+    redis: Option<(RedisConnection, String)>,
+}
+
+impl Introspector {
+    pub fn new(config: &Introspection) -> Introspector {
+        let timeout = Duration::from_millis(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("unable to build the introspection client");
+        let redis = config.redis.as_ref().map(|redis| {
+            let connection = RedisConnection::new(redis)
+                .unwrap_or_else(|e| panic!("invalid introspection redis url {}: {}", redis.url, e));
+            (connection, format!("{}:introspection:", redis.get_prefix()))
+        });
+        Introspector {
+            client,
+            url: config.url.clone(),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            scopes: config.scopes.clone().unwrap_or_default(),
+            audience: config.audience.clone().unwrap_or_default(),
+            consumer_field: config
+                .consumer_field
+                .clone()
+                .unwrap_or_else(|| "sub".to_string()),
+            sources: config.sources.clone().unwrap_or_else(|| {
+                vec![TokenSource::Header {
+                    header: "Authorization".to_string(),
+                }]
+            }),
+            cache_ttl: Duration::from_secs(config.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL)),
+            cache: CredentialCache::new(),
+            redis,
+        }
+    }
+
+    pub fn get_sources(&self) -> &Vec<TokenSource> {
+        &self.sources
+    }
+
+    // get the consumer name of the token, the client id is used when the field is missing
+    pub fn get_consumer<'a>(&self, claims: &'a Claims) -> Option<&'a str> {
+        claims
+            .get(&self.consumer_field)
+            .or_else(|| claims.get("client_id"))
+            .and_then(|value| value.as_str())
+    }
+
+    // introspect and validate the token
+    // returns the error when the token is not usable, the endpoint error is returned separately
+    pub async fn introspect(&self, token: &str) -> Result<Result<Claims, String>, String> {
+        let cache_key = sha256_hex(token);
+        let claims = match self.cached(&cache_key).await {
+            Some(claims) => claims,
+            None => {
+                let claims = self.request(token).await?;
+                if is_active(&claims) {
+                    self.store(cache_key, &claims).await;
+                }
+                claims
+            }
+        };
+        Ok(self.validate(&claims).map(|_| claims))
+    }
+
+    // call the introspection endpoint with the client credentials
+    async fn request(&self, token: &str) -> Result<Claims, String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "introspection endpoint returned {}",
+                response.status()
+            ));
+        }
+        response.json::<Claims>().await.map_err(|e| e.to_string())
+    }
+
+    // check the active flag, the expiration, the scopes and the audience
+    fn validate(&self, claims: &Claims) -> Result<(), String> {
+        if !is_active(claims) {
+            return Err("token is not active".to_string());
+        }
+        if let Some(exp) = claims.get("exp").and_then(|exp| exp.as_i64()) {
+            if exp <= now() {
+                return Err("token is expired".to_string());
+            }
+        }
+        let scopes: Vec<&str> = claims
+            .get("scope")
+            .and_then(|scope| scope.as_str())
+            .map(|scope| scope.split_whitespace().collect())
+            .unwrap_or_default();
+        if let Some(missing) = self
+            .scopes
+            .iter()
+            .find(|scope| !scopes.contains(&scope.as_str()))
+        {
+            return Err(format!("missing scope {}", missing));
+        }
+        if !self.audience.is_empty() {
+            let audience: Vec<&str> = match claims.get("aud") {
+                Some(Value::String(aud)) => vec![aud.as_str()],
+                Some(Value::Array(aud)) => aud.iter().filter_map(|aud| aud.as_str()).collect(),
+                _ => vec![],
+            };
+            if !self
+                .audience
+                .iter()
+                .any(|allowed| audience.contains(&allowed.as_str()))
+            {
+                return Err("invalid audience".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // get the cached result, the redis result is kept in memory afterward
+    async fn cached(&self, cache_key: &str) -> Option<Claims> {
+        if let Some(claims) = self.cache.get(cache_key) {
+            return Some(claims);
+        }
+        let (redis, prefix) = self.redis.as_ref()?;
+        let value = match redis.get(&format!("{}{}", prefix, cache_key)).await {
+            Ok(value) => value?,
+            Err(e) => {
+                warn!("unable to read the introspection cache: {}", e);
+                return None;
+            }
+        };
+        let claims: Claims = serde_json::from_str(&value).ok()?;
+        let ttl = self.ttl(&claims);
+        self.cache
+            .insert(cache_key.to_string(), claims.clone(), ttl);
+        Some(claims)
+    }
+
+    // cache the result until the token exp
+    async fn store(&self, cache_key: String, claims: &Claims) {
+        let ttl = self.ttl(claims);
+        if ttl.is_zero() {
+            return;
+        }
+        if let Some((redis, prefix)) = &self.redis {
+            let value = serde_json::to_string(claims).unwrap_or_default();
+            if let Err(e) = redis
+                .set_ex(&format!("{}{}", prefix, cache_key), &value, ttl.as_secs())
+                .await
+            {
+                warn!("unable to write the introspection cache: {}", e);
+            }
+        }
+        self.cache.insert(cache_key, claims.clone(), ttl);
+    }
+
+    // the cache ttl, limited by the token exp
+    fn ttl(&self, claims: &Claims) -> Duration {
+        match claims.get("exp").and_then(|exp| exp.as_i64()) {
+            Some(exp) => {
+                let remaining = Duration::from_secs((exp - now()).max(0) as u64);
+                remaining.min(self.cache_ttl)
+            }
+            None => self.cache_ttl,
+        }
+    }
+}
+
+// the token is active only when the endpoint says so
+fn is_active(claims: &Claims) -> bool {
+    claims.get("active").and_then(|active| active.as_bool()) == Some(true)
+}
+
+// the current unix time in seconds
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod introspection_mod {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // a local introspection endpoint, every token is active except "revoked"
+    async fn mock_endpoint(hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let mut buffer = vec![0; 4096];
+                let read = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+                let body = if request.contains("token=revoked") {
+                    r#"{"active":false}"#.to_string()
+                } else {
+                    format!(
+                        r#"{{"active":true,"sub":"user","scope":"read write","aud":["api"],"exp":{}}}"#,
+                        now() + 60
+                    )
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/introspect", address)
+    }
+
+    fn introspector(url: String, scopes: Vec<&str>) -> Introspector {
+        Introspector::new(&Introspection {
+            url,
+            client_id: "glaive".to_string(),
+            client_secret: "secret".to_string(),
+            scopes: Some(scopes.into_iter().map(|s| s.to_string()).collect()),
+            audience: Some(vec!["api".to_string()]),
+            consumer_field: None,
+            sources: None,
+            timeout: None,
+            cache_ttl: None,
+            redis: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn introspect_test() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_endpoint(Arc::clone(&hits)).await;
+        let introspector = introspector(url.clone(), vec!["read"]);
+        let claims = introspector.introspect("token").await.unwrap().unwrap();
+        assert_eq!(introspector.get_consumer(&claims), Some("user"));
+        // the active result is cached
+        introspector.introspect("token").await.unwrap().unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // the inactive result is rejected
+        assert!(introspector.introspect("revoked").await.unwrap().is_err());
+        // the required scope is missing
+        let introspector = self::introspector(url, vec!["admin"]);
+        let result = introspector.introspect("token").await.unwrap();
+        assert_eq!(result.unwrap_err(), "missing scope admin");
+    }
+}
@@ -1,127 +1,718 @@
-/**
- * Copyright (c) 2024-2025 Glaive, Inc.
- *
- * This file is part of Glaive Gateway
- *
- * This program is free software: you can redistribute it and/or modify
- * it under the terms of the GNU Affero General Public License as published by
- * the Free Software Foundation, either version 3 of the License, or
- * (at your option) any later version.
- *
- * This program is distributed in the hope that it will be useful
- * but WITHOUT ANY WARRANTY; without even the implied warranty of
- * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
- * GNU Affero General Public License for more details.
- *
- * You should have received a copy of the GNU Affero General Public License
- * along with this program.  If not, see <http://www.gnu.org/licenses/>.
- */
-use std::time::Duration;
-use std::collections::HashMap;
-
-use pingora::http::ResponseHeader;
-use pingora::prelude::Session;
-use pingora_limits::rate::Rate;
-
-use bytes::Bytes;
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-
-use crate::proxy::RouterCtx;
-use crate::response::ResponseProvider;
-
-// Global config limiter refresh duration
-// Value is 60 seconds by default
-static RATE_LIMITER: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(60)));
-
-// for rate limit exceed response body
-#[derive(Debug, Serialize, Deserialize)]
-pub struct TooManyRequest {
-    pub status_code: usize,
-    pub message: String,
-}
-
-pub struct LimiterProvider {
-    response_provider: ResponseProvider,
-}
-
-impl LimiterProvider {
-    pub fn new() -> Self {
-        LimiterProvider {
-            response_provider: ResponseProvider::new(),
-        }
-    }
-
-    // global limiter for service/route level
-    // this uses service identity as hash
-    pub async fn global_limiter(
-        &self,
-        max_req_limit: isize,
-        session: &mut Session,
-        ctx: &mut RouterCtx,
-    ) -> bool {
-        // make sure cluster identity exist
-        if let Some(service_id) = &ctx.cluster_identity {
-            println!("service_id: {}", service_id);
-            // retrieve the current window requests
-            let curr_window_requests = RATE_LIMITER.observe(&service_id, 1);
-            // if rate limit exceed
-            if curr_window_requests > max_req_limit {
-                let limit_str = max_req_limit.to_string();
-                // rate limited, return 429
-                let mut headers: HashMap<&str, &str> = HashMap::new();
-                headers.insert("X-Rate-Limit-Limit", limit_str.as_str());
-                headers.insert("X-Rate-Limit-Remaining", "0");
-                headers.insert("X-Rate-Limit-Reset", "1");
-                let _ = &self
-                    .response_provider
-                    .error_response(session, 429, "Too many request", Some(headers))
-                    .await;
-                return true;
-            }
-            // continue request
-            return false;
-        }
-        // todo here, either ignore or throw errors
-        false
-    }
-
-    // client limiter for service/route level
-    // this uses client credential as hash
-    // used credential token as hash, but for public routes, uses ip by default
-    pub async fn client_limiter(
-        &self,
-        max_req_limit: isize,
-        session: &mut Session,
-        ctx: &mut RouterCtx,
-    ) -> bool {
-        // Get client credential or address
-        let client_credential = ctx
-            .client_credentials
-            .as_ref()
-            .or(ctx.client_address.as_ref());
-        // check if credential exist
-        if let Some(credential) = client_credential {
-            // retrieve the current window requests
-            let curr_window_requests = RATE_LIMITER.observe(&credential, 1);
-            // if rate limit exceed
-            if curr_window_requests > max_req_limit {
-                let limit_str = max_req_limit.to_string();
-                // rate limited, return 429
-                let mut headers: HashMap<&str, &str> = HashMap::new();
-                headers.insert("X-Rate-Limit-Limit", limit_str.as_str());
-                headers.insert("X-Rate-Limit-Remaining", "0");
-                headers.insert("X-Rate-Limit-Reset", "1");
-                let _ = &self
-                    .response_provider
-                    .error_response(session, 429, "Too many request", Some(headers))
-                    .await;
-                return true;
-            }
-            // continue request
-            return false;
-        };
-        // todo here, either ignore or throw errors
-        false
-    }
-}
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use std::collections::HashMap;
+use std::time::Duration;
+
+use pingora::http::ResponseHeader;
+use pingora::prelude::Session;
+use pingora_limits::rate::Rate;
+
+use bytes::Bytes;
+use redis::RedisError;
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, warn, Instrument};
+
+use crate::auth::claim_to_header_value;
+use crate::concurrency::ConcurrencyLimiter;
+use crate::credential::sha256_hex;
+use crate::def::{
+    ClientKey, FailureMode, LimitKey, Limiter, MissingKey, RatelimitType, RedisAlgorithm,
+};
+use crate::metrics;
+use crate::proxy::RouterCtx;
+use crate::quota::QuotaManager;
+use crate::redis::RedisConnection;
+use crate::request::RequestProvider;
+use crate::response::ResponseProvider;
+
+// for rate limit exceed response body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TooManyRequest {
+    pub status_code: usize,
+    pub message: String,
+}
+
+// the quota of a limiter after counting the request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitState {
+    pub allowed: bool,
+    pub limit: isize,
+    pub remaining: isize,
+    // the seconds until the quota is reset
+    pub reset: u64,
+    // the x-rate-limit headers are sent as well
+    pub legacy_headers: bool,
+}
+
+impl LimitState {
+    // the ratelimit headers of the ietf draft, and the legacy headers when enabled
+    pub fn get_headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("RateLimit-Limit", self.limit.to_string()),
+            ("RateLimit-Remaining", self.remaining.to_string()),
+            ("RateLimit-Reset", self.reset.to_string()),
+        ];
+        if self.legacy_headers {
+            headers.push(("X-Rate-Limit-Limit", self.limit.to_string()));
+            headers.push(("X-Rate-Limit-Remaining", self.remaining.to_string()));
+            headers.push(("X-Rate-Limit-Reset", self.reset.to_string()));
+        }
+        headers
+    }
+}
+
+// the counters of a rate limiter
+enum LimiterBackend {
+    // counted by this gateway only
+    Memory(Rate),
+    // counted by every gateway sharing the redis
+    Redis {
+        connection: RedisConnection,
+        prefix: String,
+        algorithm: RedisAlgorithm,
+        failure_mode: FailureMode,
+    },
+}
+
+// a rate limiter instance
+// every cluster and route limiter counts its own window
+pub struct RateLimiter {
+    namespace: String,
+    limit: isize,
+    window: Duration,
+    backend: LimiterBackend,
+    key: Option<Vec<LimitKey>>,
+    missing_key: MissingKey,
+    legacy_headers: bool,
+}
+
+impl RateLimiter {
+    pub fn new(namespace: String, limiter_type: &RatelimitType, legacy_headers: bool) -> Self {
+        match limiter_type {
+            RatelimitType::Basic { basic } => RateLimiter {
+                namespace,
+                limit: basic.limit,
+                window: basic.get_window(),
+                backend: LimiterBackend::Memory(Rate::new(basic.get_window())),
+                key: basic.key.clone(),
+                missing_key: basic.missing_key.unwrap_or(MissingKey::Skip),
+                legacy_headers,
+            },
+            RatelimitType::Redis { redis } => {
+                let connection = RedisConnection::new(&redis.redis).unwrap_or_else(|e| {
+                    panic!("invalid limiter redis url {}: {}", redis.redis.url, e)
+                });
+                RateLimiter {
+                    namespace,
+                    limit: redis.limit,
+                    window: redis.get_window(),
+                    backend: LimiterBackend::Redis {
+                        connection,
+                        prefix: redis.redis.get_prefix(),
+                        algorithm: redis.algorithm.unwrap_or(RedisAlgorithm::FixedWindow),
+                        failure_mode: redis.failure_mode.unwrap_or(FailureMode::Open),
+                    },
+                    key: redis.key.clone(),
+                    missing_key: redis.missing_key.unwrap_or(MissingKey::Skip),
+                    legacy_headers,
+                }
+            }
+        }
+    }
+
+    // count the request, returns the quota of the key
+    // the key is namespaced, so the same client never collides across limiters
+    pub async fn acquire(&self, key: &str) -> Result<LimitState, RedisError> {
+        let (allowed, remaining, reset) = match &self.backend {
+            LimiterBackend::Memory(rate) => {
+                let key = (&self.namespace, key);
+                let count = rate.observe(&key, 1);
+                // the window is reset once the elapsed fraction of the interval is complete
+                let elapsed = rate.rate_with(&key, |rate| rate.current_interval_fraction);
+                let reset = self.window.mul_f64((1.0 - elapsed).clamp(0.0, 1.0));
+                (
+                    count <= self.limit,
+                    (self.limit - count) as i64,
+                    reset.as_millis() as u64,
+                )
+            }
+            LimiterBackend::Redis {
+                connection,
+                prefix,
+                algorithm,
+                ..
+            } => {
+                // the key is hashed, so the credentials are never stored in redis
+                let redis_key = format!(
+                    "{}:ratelimit:{}:{}",
+                    prefix,
+                    self.namespace,
+                    sha256_hex(key)
+                );
+                connection
+                    .rate_limit(*algorithm, &redis_key, self.limit as i64, self.window)
+                    .await?
+            }
+        };
+        Ok(LimitState {
+            allowed,
+            limit: self.limit,
+            remaining: remaining.max(0) as isize,
+            // rounded up, so the quota is always reset once the seconds have passed
+            reset: reset.div_ceil(1000),
+            legacy_headers: self.legacy_headers,
+        })
+    }
+    pub fn get_key(&self) -> &Option<Vec<LimitKey>> {
+        &self.key
+    }
+    // the pass of the limiter, after the authentication when the key needs the consumer or a claim
+    pub fn get_phase(&self) -> LimitPhase {
+        let authenticated = self.key.iter().flatten().any(|part| {
+            matches!(
+                part,
+                LimitKey::Client(ClientKey::Consumer) | LimitKey::Claim { .. }
+            )
+        });
+        match authenticated {
+            true => LimitPhase::AfterAuth,
+            false => LimitPhase::BeforeAuth,
+        }
+    }
+    pub fn get_failure_mode(&self) -> FailureMode {
+        match &self.backend {
+            LimiterBackend::Memory(_) => FailureMode::Open,
+            LimiterBackend::Redis { failure_mode, .. } => *failure_mode,
+        }
+    }
+}
+
+// the pass of the rate limiters
+// the limiters keyed by the consumer or a claim wait for the authentication,
+// the others run before it, so the unauthenticated floods are limited too
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitPhase {
+    BeforeAuth,
+    AfterAuth,
+}
+
+// the limiter instances of a cluster or a route
+pub struct Limiters {
+    global: Option<RateLimiter>,
+    client: Option<RateLimiter>,
+}
+
+impl Limiters {
+    pub fn new(limiter: &Limiter, namespace: &str) -> Self {
+        Limiters {
+            global: limiter.get_global().as_ref().map(|global| {
+                RateLimiter::new(
+                    format!("{}:global", namespace),
+                    global,
+                    limiter.get_legacy_headers(),
+                )
+            }),
+            client: limiter.get_client().as_ref().map(|client| {
+                RateLimiter::new(
+                    format!("{}:client", namespace),
+                    client,
+                    limiter.get_legacy_headers(),
+                )
+            }),
+        }
+    }
+}
+
+pub struct LimiterProvider {
+    request_provider: RequestProvider,
+    response_provider: ResponseProvider,
+}
+
+impl LimiterProvider {
+    pub fn new() -> Self {
+        LimiterProvider {
+            request_provider: RequestProvider::new(),
+            response_provider: ResponseProvider::new(),
+        }
+    }
+
+    // rate limit the request with the limiters of a cluster or a route in the given pass
+    // returns true when the request is limited
+    pub async fn limit(
+        &self,
+        limiters: &Limiters,
+        phase: LimitPhase,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        if let Some(limiter) = limiters.global.as_ref().filter(|l| l.get_phase() == phase) {
+            let limited = self
+                .global_limiter(limiter, session, ctx)
+                .instrument(info_span!("rate_limit", limiter = "global"))
+                .await;
+            if limited {
+                return true;
+            }
+        }
+        if let Some(limiter) = limiters.client.as_ref().filter(|l| l.get_phase() == phase) {
+            let limited = self
+                .client_limiter(limiter, session, ctx)
+                .instrument(info_span!("rate_limit", limiter = "client"))
+                .await;
+            if limited {
+                return true;
+            }
+        }
+        false
+    }
+
+    // cap the in-flight requests with the concurrency limiter of a cluster or a route
+    // the slot is kept in the context until the logging phase
+    // returns true when the request is limited
+    pub async fn limit_concurrency(
+        &self,
+        limiter: &ConcurrencyLimiter,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        let key = match limiter.get_key() {
+            Some(key) => self.resolve_key(key, session, ctx),
+            None => Some(String::new()),
+        };
+        // check if the key exist
+        let key = match key {
+            Some(key) => key,
+            None => {
+                if limiter.get_missing_key() == MissingKey::Skip {
+                    return false;
+                }
+                // return 400 because the key is missing from the request
+                metrics::rate_limit_rejection(ctx, "concurrency");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 400, "Missing concurrency key", None)
+                    .await;
+                return true;
+            }
+        };
+        let result = limiter
+            .acquire(&key)
+            .instrument(info_span!("concurrency"))
+            .await;
+        match result {
+            Ok(Some(permit)) => {
+                ctx.concurrency_permits.push(permit);
+                false
+            }
+            Ok(None) => {
+                // every slot is taken and the queue is full or timed out
+                metrics::rate_limit_rejection(ctx, "concurrency");
+                let _ = &self
+                    .response_provider
+                    .error_response(
+                        session,
+                        ctx,
+                        limiter.get_status(),
+                        "Too many concurrent requests",
+                        None,
+                    )
+                    .await;
+                true
+            }
+            Err(e) => {
+                warn!("unable to reach the concurrency redis: {}", e);
+                if limiter.get_failure_mode() == FailureMode::Open {
+                    return false;
+                }
+                // return 503 because the slots can not be checked
+                metrics::rate_limit_rejection(ctx, "concurrency");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 503, "Concurrency limit unavailable", None)
+                    .await;
+                true
+            }
+        }
+    }
+
+    // count the request on the quota of the authenticated consumer
+    // returns true when the consumer is over its quota
+    pub async fn quota(
+        &self,
+        manager: &QuotaManager,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        let Some(consumer) = ctx.consumer.clone() else {
+            return false;
+        };
+        let result = manager
+            .consume(&consumer)
+            .instrument(info_span!("quota"))
+            .await;
+        match result {
+            Ok(Some(state)) if state.allowed => {
+                ctx.quota = Some(state);
+                false
+            }
+            Ok(Some(state)) => {
+                // the consumer is told when the quota is reset
+                metrics::rate_limit_rejection(ctx, "quota");
+                let mut state_headers = state.get_headers();
+                if manager.get_status() == 429 {
+                    state_headers.push(("Retry-After", state.reset.max(1).to_string()));
+                }
+                let headers: HashMap<&str, &str> = state_headers
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect();
+                let _ = &self
+                    .response_provider
+                    .error_response(
+                        session,
+                        ctx,
+                        manager.get_status(),
+                        manager.get_message(),
+                        Some(headers),
+                    )
+                    .await;
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!("unable to reach the quota redis: {}", e);
+                if manager.get_failure_mode() == FailureMode::Open {
+                    return false;
+                }
+                // return 503 because the quota can not be checked
+                metrics::rate_limit_rejection(ctx, "quota");
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 503, "Quota unavailable", None)
+                    .await;
+                true
+            }
+        }
+    }
+
+    // global limiter for service/route level
+    // every request of the cluster or route shares the same counter, unless a key is given
+    async fn global_limiter(
+        &self,
+        limiter: &RateLimiter,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        let key = match limiter.get_key() {
+            Some(key) => self.resolve_key(key, session, ctx),
+            None => Some(String::new()),
+        };
+        self.limit_key(limiter, "global", key, session, ctx).await
+    }
+
+    // client limiter for service/route level
+    // this uses the configured key, or the client credential as hash
+    // used credential token as hash, but for public routes, uses ip by default
+    async fn client_limiter(
+        &self,
+        limiter: &RateLimiter,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        let key = match limiter.get_key() {
+            Some(key) => self.resolve_key(key, session, ctx),
+            // Get client credential or address
+            None => ctx
+                .client_credentials
+                .as_ref()
+                .or(ctx.client_address.as_ref())
+                .cloned(),
+        };
+        self.limit_key(limiter, "client", key, session, ctx).await
+    }
+
+    // resolve the limit key from the request
+    // returns none when a part of the key is missing
+    fn resolve_key(&self, key: &[LimitKey], session: &Session, ctx: &RouterCtx) -> Option<String> {
+        let mut parts = Vec::with_capacity(key.len());
+        for part in key {
+            let value = match part {
+                LimitKey::Client(ClientKey::Ip) => ctx.client_address.clone(),
+                LimitKey::Client(ClientKey::Consumer) => ctx.consumer.clone(),
+                LimitKey::Header { header } => self
+                    .request_provider
+                    .get_req_header_value(session, header)
+                    .map(|value| value.to_string()),
+                LimitKey::Claim { claim } => ctx
+                    .claims
+                    .as_ref()
+                    .and_then(|claims| claims.get(claim))
+                    .map(claim_to_header_value),
+                LimitKey::Param { param } => ctx.route_params.get(param).cloned(),
+                LimitKey::Query { query } => self
+                    .request_provider
+                    .get_origin_query_value(ctx.uri_origin.as_deref(), query)
+                    .map(|value| value.to_string()),
+            };
+            parts.push(value.filter(|value| !value.is_empty())?);
+        }
+        Some(join_key(&parts))
+    }
+
+    // count the request with the resolved key
+    // returns true when the request is limited or rejected
+    async fn limit_key(
+        &self,
+        limiter: &RateLimiter,
+        limiter_name: &str,
+        key: Option<String>,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> bool {
+        // check if the key exist
+        let key = match key {
+            Some(key) => key,
+            None => {
+                if limiter.missing_key == MissingKey::Skip {
+                    return false;
+                }
+                // return 400 because the key is missing from the request
+                metrics::rate_limit_rejection(ctx, limiter_name);
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 400, "Missing rate limit key", None)
+                    .await;
+                return true;
+            }
+        };
+        // count the request in the current window
+        match limiter.acquire(&key).await {
+            Ok(state) if state.allowed => {
+                // the most restrictive quota is sent with the response
+                let restrictive = match &ctx.rate_limit {
+                    Some(current) => state.remaining < current.remaining,
+                    None => true,
+                };
+                if restrictive {
+                    ctx.rate_limit = Some(state);
+                }
+                false
+            }
+            Ok(state) => {
+                // if rate limit exceed
+                metrics::rate_limit_rejection(ctx, limiter_name);
+                self.too_many_requests(&state, session, ctx).await;
+                true
+            }
+            Err(e) => {
+                warn!("unable to reach the rate limit redis: {}", e);
+                if limiter.get_failure_mode() == FailureMode::Open {
+                    return false;
+                }
+                // return 503 because the limit can not be checked
+                metrics::rate_limit_rejection(ctx, limiter_name);
+                let _ = &self
+                    .response_provider
+                    .error_response(session, ctx, 503, "Rate limit unavailable", None)
+                    .await;
+                true
+            }
+        }
+    }
+
+    // rate limited, return 429
+    // the client is told when to retry with the reset of the quota
+    async fn too_many_requests(
+        &self,
+        state: &LimitState,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) {
+        let mut state_headers = state.get_headers();
+        state_headers.push(("Retry-After", state.reset.max(1).to_string()));
+        let headers: HashMap<&str, &str> = state_headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        let _ = &self
+            .response_provider
+            .error_response(session, ctx, 429, "Too many request", Some(headers))
+            .await;
+    }
+}
+
+// join the key parts, every part is length prefixed so the parts never collide
+fn join_key(parts: &[String]) -> String {
+    parts
+        .iter()
+        .map(|part| format!("{}:{}", part.len(), part))
+        .collect()
+}
+
+#[cfg(test)]
+mod limiter_mod {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn namespace_test() {
+        let limiter: Limiter = serde_yaml::from_str("client:\n  basic:\n    limit: 1").unwrap();
+        let cluster = limiter.build_limiters("cluster");
+        let route = limiter.build_limiters("cluster:route");
+        let cluster = cluster.client.as_ref().unwrap();
+        let route = route.client.as_ref().unwrap();
+        let state = cluster.acquire("client").await.unwrap();
+        assert!(state.allowed);
+        assert_eq!(state.remaining, 0);
+        assert!(state.reset > 0 && state.reset <= 60);
+        assert!(!cluster.acquire("client").await.unwrap().allowed);
+        // the same client is counted apart by every limiter
+        assert!(route.acquire("client").await.unwrap().allowed);
+        assert!(cluster.acquire("other").await.unwrap().allowed);
+    }
+
+    #[test]
+    fn key_test() {
+        let parts =
+            |parts: &[&str]| join_key(&parts.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        assert_eq!(parts(&["alice", "acme"]), "5:alice4:acme");
+        // the separators in the values never make two keys equal
+        assert_ne!(parts(&["a|b", "c"]), parts(&["a", "b|c"]));
+        assert_ne!(parts(&["a1:b", "c"]), parts(&["a", "1:bc"]));
+    }
+
+    #[tokio::test]
+    async fn param_key_test() {
+        let provider = LimiterProvider::new();
+        let key: Vec<LimitKey> =
+            serde_yaml::from_str("- param: \"id\"\n- header: \"X-Tenant\"").unwrap();
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /users/42 HTTP/1.1\r\nX-Tenant: acme\r\n\r\n")
+            .await
+            .unwrap();
+        let mut session = Session::new_h1(Box::new(server));
+        session.downstream_session.read_request().await.unwrap();
+        let mut ctx = RouterCtx::new("x-request-id".to_string());
+        // the route path param is missing until the route is matched
+        assert!(provider.resolve_key(&key, &session, &ctx).is_none());
+        ctx.route_params = crate::path::match_route_path("/users/{id}", "/users/42").unwrap();
+        assert_eq!(
+            provider.resolve_key(&key, &session, &ctx).as_deref(),
+            Some("2:424:acme")
+        );
+    }
+
+    #[test]
+    fn phase_test() {
+        let limiter: Limiter = serde_yaml::from_str(
+            "global:\n  basic:\n    limit: 1\nclient:\n  basic:\n    limit: 1\n    key:\n      - \"ip\"\n      - claim: \"org\"",
+        )
+        .unwrap();
+        let limiters = limiter.build_limiters("phase");
+        // the limiter without key or with an ip key runs before the authentication
+        assert_eq!(
+            limiters.global.as_ref().unwrap().get_phase(),
+            LimitPhase::BeforeAuth
+        );
+        assert_eq!(
+            limiters.client.as_ref().unwrap().get_phase(),
+            LimitPhase::AfterAuth
+        );
+        let limiter: Limiter = serde_yaml::from_str(
+            "client:\n  basic:\n    limit: 1\n    key:\n      - header: \"X-Tenant\"",
+        )
+        .unwrap();
+        let limiters = limiter.build_limiters("phase");
+        assert_eq!(
+            limiters.client.as_ref().unwrap().get_phase(),
+            LimitPhase::BeforeAuth
+        );
+    }
+
+    fn redis_limiter(url: &str, algorithm: &str) -> Limiters {
+        let config = format!(
+            "client:\n  redis:\n    url: \"{}\"\n    limit: 2\n    algorithm: \"{}\"",
+            url, algorithm
+        );
+        let limiter: Limiter = serde_yaml::from_str(&config).unwrap();
+        limiter.build_limiters(&uuid::Uuid::now_v7().to_string())
+    }
+
+    #[tokio::test]
+    async fn redis_unreachable_test() {
+        let provider = LimiterProvider::new();
+        for (failure_mode, limited) in [("open", false), ("closed", true)] {
+            let config = format!(
+                "client:\n  redis:\n    url: \"redis://127.0.0.1:1\"\n    limit: 2\n    failure_mode: \"{}\"",
+                failure_mode
+            );
+            let limiter: Limiter = serde_yaml::from_str(&config).unwrap();
+            let limiters = limiter.build_limiters("unreachable");
+            let limiter = limiters.client.as_ref().unwrap();
+            assert!(limiter.acquire("client").await.is_err());
+            let (mut client, server) = tokio::io::duplex(4096);
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut session = Session::new_h1(Box::new(server));
+            session.downstream_session.read_request().await.unwrap();
+            let mut ctx = RouterCtx::new("x-request-id".to_string());
+            // the open mode lets the request through, the closed mode rejects it with 503
+            let result = provider
+                .limit_key(
+                    limiter,
+                    "client",
+                    Some("client".to_string()),
+                    &mut session,
+                    &mut ctx,
+                )
+                .await;
+            assert_eq!(result, limited, "{}", failure_mode);
+            if limited {
+                drop(session);
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a local redis-server"]
+    async fn redis_test() {
+        for algorithm in [
+            "fixed-window",
+            "sliding-window",
+            "token-bucket",
+            "leaky-bucket",
+        ] {
+            let limiters = redis_limiter("redis://127.0.0.1:6379", algorithm);
+            let limiter = limiters.client.as_ref().unwrap();
+            let state = limiter.acquire("client").await.unwrap();
+            assert!(state.allowed && state.remaining == 1, "{}", algorithm);
+            assert!(
+                limiter.acquire("client").await.unwrap().allowed,
+                "{}",
+                algorithm
+            );
+            let state = limiter.acquire("client").await.unwrap();
+            assert!(!state.allowed && state.remaining == 0, "{}", algorithm);
+            assert!(state.reset > 0 && state.reset <= 60, "{}", algorithm);
+        }
+    }
+}
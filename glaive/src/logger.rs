@@ -0,0 +1,427 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pingora::prelude::background_service;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::{BackgroundService, GenBackgroundService};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::warn;
+
+use crate::def::{AccessLog, LogFile, LogFormat, LogRotation};
+
+// the default template used by the text format
+const DEFAULT_TEMPLATE: &str = "$client_ip - $consumer [$timestamp] \"$method $path\" $status $bytes_out $latency_ms $request_id";
+
+// the default maximum pending log entries
+const DEFAULT_BUFFER: usize = 8192;
+
+// a single access log entry, written once the request is finished
+#[derive(Debug, Default, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: String,
+    pub request_id: Option<String>,
+    pub client_ip: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub cluster: Option<String>,
+    pub route: Option<String>,
+    pub upstream: Option<String>,
+    pub status: u16,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub latency_ms: f64,
+    pub upstream_connect_ms: Option<f64>,
+    pub upstream_response_ms: Option<f64>,
+    pub consumer: Option<String>,
+    pub cache_status: Option<String>,
+    pub error: Option<String>,
+}
+
+// the access logger is used by the proxy to queue the log entries
+// the entries are written by the background service, so logging never blocks the proxy
+pub struct AccessLogger {
+    format: LogFormat,
+    template: String,
+    sender: Sender<String>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl AccessLogger {
+    // build the logger and the background writer from the configuration
+    pub fn new(
+        config: AccessLog,
+    ) -> (
+        AccessLogger,
+        GenBackgroundService<AccessLogBackgroundService>,
+    ) {
+        let (sender, receiver) = channel(config.buffer.unwrap_or(DEFAULT_BUFFER));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let logger = AccessLogger {
+            format: config.format.unwrap_or(LogFormat::Json),
+            template: config
+                .template
+                .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+            sender,
+            dropped: Arc::clone(&dropped),
+        };
+        let writer = AccessLogBackgroundService {
+            file: config.file,
+            receiver: Mutex::new(Some(receiver)),
+            dropped,
+        };
+        (logger, background_service("access log writer", writer))
+    }
+
+    // format and queue the log entry
+    // when the queue is full, the entry is dropped instead of waiting
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let line = match self.format {
+            LogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+            LogFormat::Text => render_template(&self.template, entry),
+        };
+        if self.sender.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// render the text template by replacing every $name with the entry value
+fn render_template(template: &str, entry: &AccessLogEntry) -> String {
+    let values = serde_json::to_value(entry).unwrap_or(Value::Null);
+    let mut output = String::with_capacity(template.len() * 2);
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            output.push(c);
+            continue;
+        }
+        // read the variable name
+        let mut name = String::new();
+        while let Some(&next) = chars.peek() {
+            if next.is_ascii_alphanumeric() || next == '_' {
+                name.push(next);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if name.is_empty() {
+            output.push('$');
+            continue;
+        }
+        // missing or empty values are written as "-"
+        match values.get(&name) {
+            Some(Value::String(value)) => output.push_str(value),
+            Some(Value::Null) | None => output.push('-'),
+            Some(value) => output.push_str(&value.to_string()),
+        }
+    }
+    output
+}
+
+// the log destination, either stdout or a rotating file
+enum LogWriter {
+    Stdout(BufWriter<io::Stdout>),
+    File(RotatingFile),
+}
+
+impl LogWriter {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            LogWriter::Stdout(stdout) => writeln!(stdout, "{}", line),
+            LogWriter::File(file) => file.write_line(line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Stdout(stdout) => stdout.flush(),
+            LogWriter::File(file) => file.writer.flush(),
+        }
+    }
+}
+
+// the log file with size and time based rotation
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    rotation: LogRotation,
+    max_files: Option<usize>,
+    writer: BufWriter<File>,
+    size: u64,
+    period: String,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFile) -> io::Result<RotatingFile> {
+        let path = PathBuf::from(&config.path);
+        let rotation = config.rotation.unwrap_or(LogRotation::Never);
+        let (writer, size) = Self::open_file(&path)?;
+        let mut file = RotatingFile {
+            path,
+            max_bytes: config.max_size.map(|mb| mb * 1024 * 1024),
+            rotation,
+            max_files: config.max_files,
+            writer,
+            size,
+            period: Self::current_period(rotation),
+        };
+        // an existing file left from an earlier period or already full is rotated first
+        if file.size > 0 && (file.is_stale()? || file.max_bytes.is_some_and(|max| file.size >= max))
+        {
+            file.rotate()?;
+        }
+        Ok(file)
+    }
+
+    // check if the existing file was last written in an earlier period
+    fn is_stale(&self) -> io::Result<bool> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        let modified: DateTime<Local> = modified.into();
+        let period = match self.rotation {
+            LogRotation::Hourly => modified.format("%Y%m%d%H").to_string(),
+            LogRotation::Daily => modified.format("%Y%m%d").to_string(),
+            LogRotation::Never => String::new(),
+        };
+        Ok(period != self.period)
+    }
+
+    // open the active log file in append mode
+    fn open_file(path: &PathBuf) -> io::Result<(BufWriter<File>, u64)> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((BufWriter::new(file), size))
+    }
+
+    // the period key changes whenever a time based rotation is due
+    fn current_period(rotation: LogRotation) -> String {
+        match rotation {
+            LogRotation::Hourly => Local::now().format("%Y%m%d%H").to_string(),
+            LogRotation::Daily => Local::now().format("%Y%m%d").to_string(),
+            LogRotation::Never => String::new(),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line_size = line.len() as u64 + 1;
+        // check if the file should be rotated by size or time
+        let period = Self::current_period(self.rotation);
+        let size_exceeded = self
+            .max_bytes
+            .map_or(false, |max| self.size > 0 && self.size + line_size > max);
+        if size_exceeded || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.size += line_size;
+        Ok(())
+    }
+
+    // move the active file aside and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let suffix = Local::now().format("%Y%m%d-%H%M%S%.3f");
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), suffix));
+        // never overwrite a file rotated within the same millisecond
+        let mut index = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}.{}", self.path.display(), suffix, index));
+            index += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        let (writer, size) = Self::open_file(&self.path)?;
+        self.writer = writer;
+        self.size = size;
+        self.remove_old_files();
+        Ok(())
+    }
+
+    // remove the oldest rotated files when the limit is exceeded
+    fn remove_old_files(&self) {
+        let max_files = match self.max_files {
+            Some(max_files) => max_files,
+            None => return,
+        };
+        let (dir, name) = match (self.path.parent(), self.path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.to_string_lossy().to_string()),
+            _ => return,
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir.to_path_buf()
+        };
+        let prefix = format!("{}.", name);
+        let mut rotated: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+                .map(|entry| entry.path())
+                .collect(),
+            Err(_) => return,
+        };
+        // the timestamp suffix sorts the files from the oldest
+        rotated.sort();
+        while rotated.len() > max_files {
+            let oldest = rotated.remove(0);
+            let _ = fs::remove_file(oldest);
+        }
+    }
+}
+
+// background processing service that writes the queued log entries
+pub struct AccessLogBackgroundService {
+    file: Option<LogFile>,
+    receiver: Mutex<Option<Receiver<String>>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl AccessLogBackgroundService {
+    fn open_writer(&self) -> LogWriter {
+        if let Some(file) = &self.file {
+            match RotatingFile::open(file) {
+                Ok(file) => return LogWriter::File(file),
+                Err(e) => warn!("unable to open access log file {}: {}", file.path, e),
+            }
+        }
+        LogWriter::Stdout(BufWriter::new(io::stdout()))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AccessLogBackgroundService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut receiver = match self.receiver.lock().unwrap().take() {
+            Some(receiver) => receiver,
+            None => return,
+        };
+        let mut writer = self.open_writer();
+        // the buffered logs are flushed every second
+        let mut flush_interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                line = receiver.recv() => {
+                    match line {
+                        Some(line) => {
+                            if let Err(e) = writer.write_line(&line) {
+                                warn!("unable to write access log: {}", e);
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                _ = flush_interval.tick() => {
+                    let _ = writer.flush();
+                    let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        warn!("access log buffer is full, dropped {} entries", dropped);
+                    }
+                }
+                _ = shutdown.changed() => {
+                    break;
+                }
+            }
+        }
+        // write the remaining entries before exiting
+        while let Ok(line) = receiver.try_recv() {
+            let _ = writer.write_line(&line);
+        }
+        let _ = writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod logger_mod {
+    use super::*;
+
+    #[test]
+    fn template_test() {
+        let entry = AccessLogEntry {
+            method: "GET".to_string(),
+            path: "/service/product".to_string(),
+            status: 200,
+            bytes_out: 512,
+            client_ip: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let line = render_template(
+            "$client_ip $consumer \"$method $path\" $status $bytes_out $",
+            &entry,
+        );
+        assert_eq!(line, "127.0.0.1 - \"GET /service/product\" 200 512 $");
+    }
+
+    #[test]
+    fn rotate_on_open_test() {
+        let dir = std::env::temp_dir().join(format!("glaive-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let config = |max_size, rotation| LogFile {
+            path: path.to_string_lossy().to_string(),
+            max_size,
+            rotation,
+            max_files: None,
+        };
+        let rotated = || fs::read_dir(&dir).unwrap().count() - 1;
+        // a file within the size limit is appended to
+        fs::write(&path, "line\n").unwrap();
+        let file = RotatingFile::open(&config(Some(1), None)).unwrap();
+        assert_eq!(file.size, 5);
+        assert_eq!(rotated(), 0);
+        drop(file);
+        // a file already over the size limit is rotated
+        fs::write(&path, vec![b'a'; 1024 * 1024]).unwrap();
+        let file = RotatingFile::open(&config(Some(1), None)).unwrap();
+        assert_eq!(file.size, 0);
+        assert_eq!(rotated(), 1);
+        drop(file);
+        // a file written in an earlier period is rotated
+        fs::write(&path, "line\n").unwrap();
+        let modified = std::time::SystemTime::now() - Duration::from_secs(2 * 86400);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let file = RotatingFile::open(&config(None, Some(LogRotation::Daily))).unwrap();
+        assert_eq!(file.size, 0);
+        assert_eq!(rotated(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,122 +1,229 @@
-/**
- * Copyright (c) 2024-2025 Glaive, Inc.
- *
- * This file is part of Glaive Gateway
- *
- * This program is free software: you can redistribute it and/or modify
- * it under the terms of the GNU Affero General Public License as published by
- * the Free Software Foundation, either version 3 of the License, or
- * (at your option) any later version.
- *
- * This program is distributed in the hope that it will be useful
- * but WITHOUT ANY WARRANTY; without even the implied warranty of
- * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
- * GNU Affero General Public License for more details.
- *
- * You should have received a copy of the GNU Affero General Public License
- * along with this program.  If not, see <http://www.gnu.org/licenses/>.
- */
-
-mod auth;
-mod bucket;
-mod cache;
-mod cluster;
-mod config;
-mod def;
-mod default;
-mod discovery;
-mod gateway;
-mod limiter;
-mod path;
-mod proxy;
-mod request;
-mod response;
-mod redis;
-
-use std::env;
-
-use pingora::prelude::Opt;
-use pingora::proxy::http_proxy_service;
-use pingora::server::Server;
-
-use dotenv::dotenv;
-use tracing::info;
-use tracing_subscriber::fmt;
-
-use crate::cluster::build_cluster;
-use crate::config::load_config;
-use crate::default::DefaultProxy;
-use crate::gateway::Gateway;
-use crate::proxy::ProxyRouter;
-
-fn main() {
-    // logger config builder
-    let subscriber = fmt()
-        // Configure various options here
-        .with_target(false)
-        .with_level(true)
-        .with_thread_names(false)
-        .with_thread_ids(false)
-        .with_file(false)
-        .with_line_number(false)
-        .with_ansi(true)
-        // .compact() // .compact is for non-json option
-        // .json()
-        .finish();
-    // init logger
-    tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
-
-    // dotenv init
-    dotenv().ok();
-    // get port and build the address
-    let port = env::var("PORT").unwrap_or_else(|_| "6188".to_string());
-    let formatted_address = format!("0.0.0.0:{}", port);
-    let address = formatted_address.as_str();
-
-    // Setup a server
-    let opt = Opt::parse_args();
-    let mut server = Server::new(Some(opt)).unwrap();
-    // load configuration and merge to server configuration
-    let gateway_configuration = load_config(&mut server.configuration);
-    server.bootstrap(); // preparing
-    // Setup new gateway
-    let gateway_utils = Gateway::new();
-
-    // checks the cluster configuration existence and build the cluster
-    match gateway_configuration.clusters {
-        Some(cluster_config) => {
-            // build the entire cluster from the configuration
-            // the built cluster will return the main proxy router and the necessary background processing
-            let built_clusters = build_cluster(cluster_config);
-            // added every cluster background process to server
-            for (_idx, cluster_service) in built_clusters.cluster_bg_service.into_iter().enumerate()
-            {
-                server.add_service(cluster_service);
-            }
-            // added every updater background process to server
-            for (_idx, updater_process) in built_clusters.updater_bg_service.into_iter().enumerate()
-            {
-                server.add_service(updater_process);
-            }
-            // build the proxy service and listen
-            let proxy_router = ProxyRouter {
-                gateway: gateway_utils,
-                clusters: built_clusters.clusters,
-                prefix_map: built_clusters.prefix_map,
-                consumers: gateway_configuration.consumers,
-            };
-            let mut router = http_proxy_service(&server.configuration, proxy_router);
-            router.add_tcp(address);
-            server.add_service(router);
-        }
-        None => {
-            // this is the default proxy trait that runs when configuration does not exist
-            let mut default = http_proxy_service(&server.configuration, DefaultProxy {});
-            default.add_tcp(address);
-            server.add_service(default);
-        }
-    };
-    info!("Gateway is listening on {}", address);
-    // run the server forever.
-    server.run_forever();
-}
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod adaptive;
+mod admin;
+mod auth;
+mod bucket;
+mod cache;
+mod cache_index;
+mod cache_policy;
+mod cluster;
+mod concurrency;
+mod config;
+mod consumer;
+mod credential;
+mod def;
+mod default;
+mod discovery;
+mod disk_cache;
+mod external;
+mod gateway;
+mod introspection;
+mod jwt;
+mod limiter;
+mod logger;
+mod metrics;
+mod path;
+mod proxy;
+mod quota;
+mod redis;
+mod redis_cache;
+mod request;
+mod response;
+mod signature;
+mod telemetry;
+
+use std::env;
+
+use pingora::prelude::{background_service, Opt};
+use pingora::proxy::http_proxy_service;
+use pingora::server::Server;
+
+use dotenv::dotenv;
+use tracing::{error, info, warn};
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::admin::AdminApp;
+use crate::cache_index::CachePurger;
+use crate::cluster::build_cluster;
+use crate::config::load_config;
+use crate::consumer::ConsumerRegistry;
+use crate::def::RequestId;
+use crate::default::DefaultProxy;
+use crate::gateway::Gateway;
+use crate::logger::AccessLogger;
+use crate::metrics::BackendHealthBackgroundService;
+use crate::proxy::ProxyRouter;
+use crate::quota::{QuotaBackgroundService, QuotaManager};
+use crate::telemetry::{TelemetryTracer, TracingBackgroundService};
+
+fn main() {
+    // dotenv init
+    dotenv().ok();
+    // get port and build the address
+    let port = env::var("PORT").unwrap_or_else(|_| "6188".to_string());
+    let formatted_address = format!("0.0.0.0:{}", port);
+    let address = formatted_address.as_str();
+
+    // Setup a server
+    let opt = Opt::parse_args();
+    let mut server = Server::new(Some(opt)).unwrap();
+    // load configuration and merge to server configuration
+    let gateway_configuration = load_config(&mut server.configuration);
+    // build the otlp tracer if configured, its spans are exported by the tracing layer
+    let telemetry = gateway_configuration
+        .telemetry
+        .as_ref()
+        .map(TelemetryTracer::new);
+    let tracer = match &telemetry {
+        Some(Ok(tracer)) => Some(tracer),
+        _ => None,
+    };
+    // logger config builder
+    let subscriber = fmt()
+        // Configure various options here
+        .with_target(false)
+        .with_level(true)
+        .with_thread_names(false)
+        .with_thread_ids(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_ansi(true)
+        // .compact() // .compact is for non-json option
+        // .json()
+        .finish()
+        .with(tracer.map(|tracer| tracer.layer()));
+    // init logger
+    tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
+    match &telemetry {
+        Some(Err(e)) => error!("Unable to build the telemetry exporter: {}", e),
+        // the exporter threads are lost when the server forks to the background
+        Some(Ok(_)) if server.configuration.daemon => {
+            warn!("Telemetry spans are not exported when running as daemon")
+        }
+        _ => (),
+    }
+    server.bootstrap(); // preparing
+    // Setup new gateway
+    let gateway_utils = Gateway::new();
+    // request id config, fallback to the default header
+    let request_id = gateway_configuration
+        .request_id
+        .unwrap_or(RequestId { header: None });
+    // build the access logger and its background writer if configured
+    let access_logger = match gateway_configuration.access_log {
+        Some(access_log_config) => {
+            let (access_logger, writer) = AccessLogger::new(access_log_config);
+            server.add_service(writer);
+            Some(access_logger)
+        }
+        None => None,
+    };
+
+    // build the consumer quotas, the file counters are written in the background
+    let consumers = gateway_configuration.consumers.unwrap_or_default();
+    let quotas = QuotaManager::new(
+        gateway_configuration.quota,
+        &consumers,
+        gateway_configuration.consumer_groups,
+    );
+    if let Some(manager) = quotas.as_ref().filter(|manager| manager.is_file_store()) {
+        server.add_service(background_service(
+            "quota writer",
+            QuotaBackgroundService {
+                manager: manager.clone(),
+            },
+        ));
+    }
+
+    // the cache purge of every cluster, served by the admin api
+    let mut cache_purgers = Vec::new();
+    // checks the cluster configuration existence and build the cluster
+    match gateway_configuration.clusters {
+        Some(cluster_config) => {
+            // build the entire cluster from the configuration
+            // the built cluster will return the main proxy router and the necessary background processing
+            let built_clusters = build_cluster(cluster_config);
+            // added every cluster background process to server
+            for (_idx, cluster_service) in built_clusters.cluster_bg_service.into_iter().enumerate()
+            {
+                server.add_service(cluster_service);
+            }
+            // added every updater background process to server
+            for (_idx, updater_process) in built_clusters.updater_bg_service.into_iter().enumerate()
+            {
+                server.add_service(updater_process);
+            }
+            // added every jwks updater background process to server
+            for jwks_process in built_clusters.jwks_bg_service {
+                server.add_service(jwks_process);
+            }
+            // report the backend health of every cluster to the metrics
+            let health_reporter = BackendHealthBackgroundService {
+                clusters: built_clusters
+                    .clusters
+                    .iter()
+                    .map(|cluster| (cluster.get_name().clone(), cluster.get_upstream().clone()))
+                    .collect(),
+            };
+            server.add_service(background_service("backend health metrics", health_reporter));
+            cache_purgers = built_clusters
+                .clusters
+                .iter()
+                .filter_map(CachePurger::new)
+                .collect();
+            // build the proxy service and listen
+            let proxy_router = ProxyRouter {
+                gateway: gateway_utils,
+                clusters: built_clusters.clusters,
+                prefix_map: built_clusters.prefix_map,
+                consumers: ConsumerRegistry::new(Some(consumers)),
+                quotas: quotas.clone(),
+                request_id_header: request_id.get_header(),
+                access_logger,
+            };
+            let mut router = http_proxy_service(&server.configuration, proxy_router);
+            router.add_tcp(address);
+            server.add_service(router);
+        }
+        None => {
+            // this is the default proxy trait that runs when configuration does not exist
+            let mut default = http_proxy_service(&server.configuration, DefaultProxy {});
+            default.add_tcp(address);
+            server.add_service(default);
+        }
+    };
+    // the admin api serves the metrics on its own listener
+    if let Some(admin) = &gateway_configuration.admin {
+        server.add_service(AdminApp::new_service(admin, quotas, cache_purgers));
+        info!("Admin api is listening on {}", admin.address);
+    }
+    // flush the pending spans on shutdown
+    if let Some(Ok(tracer)) = telemetry {
+        server.add_service(background_service(
+            "telemetry exporter",
+            TracingBackgroundService { tracer },
+        ));
+    }
+    info!("Gateway is listening on {}", address);
+    // run the server forever.
+    server.run_forever();
+}
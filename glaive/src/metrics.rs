@@ -0,0 +1,302 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use pingora::lb::LoadBalancer;
+use pingora::prelude::RoundRobin;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+use crate::proxy::RouterCtx;
+
+// latency buckets in seconds, from 1ms up to 10s
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// the total of finished requests
+pub static REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "glaive_requests_total",
+        "Total number of requests",
+        &["cluster", "route", "status"]
+    )
+    .unwrap()
+});
+
+// the total request latency, from the first byte to the access log
+pub static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "glaive_request_duration_seconds",
+        "Request latency in seconds",
+        &["cluster", "route", "status"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+// the upstream latency, until the upstream response header is received
+pub static UPSTREAM_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "glaive_upstream_duration_seconds",
+        "Upstream response latency in seconds",
+        &["cluster", "route", "status"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+// the requests that are currently being processed
+pub static REQUESTS_IN_FLIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "glaive_requests_in_flight",
+        "Number of requests currently in flight",
+        &["cluster", "route"]
+    )
+    .unwrap()
+});
+
+// the failed upstream connections
+pub static UPSTREAM_CONNECT_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "glaive_upstream_connect_failures_total",
+        "Total number of failed upstream connections",
+        &["cluster", "route"]
+    )
+    .unwrap()
+});
+
+// the retried upstream connections
+pub static UPSTREAM_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "glaive_upstream_retries_total",
+        "Total number of retried upstream connections",
+        &["cluster", "route"]
+    )
+    .unwrap()
+});
+
+// the requests rejected by the rate limiter
+pub static RATE_LIMIT_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "glaive_rate_limit_rejections_total",
+        "Total number of requests rejected by the rate limiter",
+        &["cluster", "route", "limiter"]
+    )
+    .unwrap()
+});
+
+// the current limit of the adaptive concurrency limiters
+pub static ADAPTIVE_CONCURRENCY_LIMIT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "glaive_adaptive_concurrency_limit",
+        "Current limit of the adaptive concurrency limiter",
+        &["cluster"]
+    )
+    .unwrap()
+});
+
+// the failed authentications
+pub static AUTH_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "glaive_auth_failures_total",
+        "Total number of failed authentications",
+        &["cluster", "route", "reason"]
+    )
+    .unwrap()
+});
+
+// the cache lookups by cache status
+pub static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "glaive_cache_lookups_total",
+        "Total number of cache lookups by cache status",
+        &["cluster", "route", "cache_status"]
+    )
+    .unwrap()
+});
+
+// the health of every upstream backend, 1 is healthy
+pub static BACKEND_HEALTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "glaive_backend_healthy",
+        "Upstream backend health, 1 is healthy and 0 is unhealthy",
+        &["cluster", "backend"]
+    )
+    .unwrap()
+});
+
+// the cluster label of the request
+pub fn cluster_label(ctx: &RouterCtx) -> &str {
+    ctx.cluster_identity.as_deref().unwrap_or_default()
+}
+
+// the route label of the request
+pub fn route_label(ctx: &RouterCtx) -> &str {
+    ctx.route_name.as_deref().unwrap_or_default()
+}
+
+// group the status code by class, e.g. 404 is 4xx
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "unknown",
+    }
+}
+
+// record a failed authentication with the given reason
+pub fn auth_failure(ctx: &RouterCtx, reason: &str) {
+    AUTH_FAILURES
+        .with_label_values(&[cluster_label(ctx), route_label(ctx), reason])
+        .inc();
+}
+
+// record a rate limit rejection by the given limiter
+pub fn rate_limit_rejection(ctx: &RouterCtx, limiter: &str) {
+    RATE_LIMIT_REJECTIONS
+        .with_label_values(&[cluster_label(ctx), route_label(ctx), limiter])
+        .inc();
+}
+
+// encode every registered metric in the prometheus text format
+pub fn encode() -> (Vec<u8>, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    (buffer, encoder.format_type().to_string())
+}
+
+// background processing service that reports the backend health
+pub struct BackendHealthBackgroundService {
+    pub clusters: Vec<(String, Arc<LoadBalancer<RoundRobin>>)>,
+}
+
+impl BackendHealthBackgroundService {
+    fn report(&self) {
+        for (name, upstream) in &self.clusters {
+            let backends = upstream.backends();
+            for backend in backends.get_backend().iter() {
+                let healthy = if backends.ready(backend) { 1 } else { 0 };
+                BACKEND_HEALTH
+                    .with_label_values(&[name, &backend.addr.to_string()])
+                    .set(healthy);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for BackendHealthBackgroundService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        // the health is reported every 5 seconds
+        let report_interval = Duration::from_secs(5);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(report_interval) => {
+                    self.report();
+                }
+                _ = shutdown.changed() => {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod metrics_mod {
+    use super::*;
+
+    #[test]
+    fn status_class_test() {
+        assert_eq!(status_class(101), "1xx");
+        assert_eq!(status_class(204), "2xx");
+        assert_eq!(status_class(304), "3xx");
+        assert_eq!(status_class(404), "4xx");
+        assert_eq!(status_class(503), "5xx");
+        assert_eq!(status_class(600), "unknown");
+    }
+
+    #[test]
+    fn label_test() {
+        let mut ctx = RouterCtx::new("x-request-id".to_string());
+        // the labels are empty until the cluster and the route are matched
+        assert_eq!(cluster_label(&ctx), "");
+        assert_eq!(route_label(&ctx), "");
+        ctx.cluster_identity = Some("metrics-cluster".to_string());
+        ctx.route_name = Some("metrics-route".to_string());
+        assert_eq!(cluster_label(&ctx), "metrics-cluster");
+        assert_eq!(route_label(&ctx), "metrics-route");
+        // the retries are labeled by route like the connect failures
+        UPSTREAM_RETRIES
+            .with_label_values(&[cluster_label(&ctx), route_label(&ctx)])
+            .inc();
+        let retries = UPSTREAM_RETRIES.with_label_values(&["metrics-cluster", "metrics-route"]);
+        assert_eq!(retries.get(), 1);
+    }
+
+    #[test]
+    fn record_test() {
+        let mut ctx = RouterCtx::new("x-request-id".to_string());
+        ctx.cluster_identity = Some("record-cluster".to_string());
+        ctx.route_name = Some("record-route".to_string());
+        auth_failure(&ctx, "unauthenticated");
+        auth_failure(&ctx, "unauthenticated");
+        rate_limit_rejection(&ctx, "cluster");
+        let auth =
+            AUTH_FAILURES.with_label_values(&["record-cluster", "record-route", "unauthenticated"]);
+        assert_eq!(auth.get(), 2);
+        let limit =
+            RATE_LIMIT_REJECTIONS.with_label_values(&["record-cluster", "record-route", "cluster"]);
+        assert_eq!(limit.get(), 1);
+        // the in flight and the connect failures are labeled by route
+        REQUESTS_IN_FLIGHT
+            .with_label_values(&["record-cluster", "record-route"])
+            .inc();
+        UPSTREAM_CONNECT_FAILURES
+            .with_label_values(&["record-cluster", "record-route"])
+            .inc();
+        // the recorded metrics are encoded in the text format
+        let (buffer, format) = encode();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(format.starts_with("text/plain"));
+        assert!(text.contains(
+            "glaive_auth_failures_total{cluster=\"record-cluster\",reason=\"unauthenticated\",route=\"record-route\"} 2"
+        ));
+        assert!(text.contains(
+            "glaive_requests_in_flight{cluster=\"record-cluster\",route=\"record-route\"} 1"
+        ));
+        assert!(text.contains(
+            "glaive_upstream_connect_failures_total{cluster=\"record-cluster\",route=\"record-route\"} 1"
+        ));
+    }
+}
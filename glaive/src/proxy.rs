@@ -1,442 +1,1005 @@
-/**
- * Copyright (c) 2024-2025 Glaive, Inc.
- *
- * This file is part of Glaive Gateway
- *
- * This program is free software: you can redistribute it and/or modify
- * it under the terms of the GNU Affero General Public License as published by
- * the Free Software Foundation, either version 3 of the License, or
- * (at your option) any later version.
- *
- * This program is distributed in the hope that it will be useful
- * but WITHOUT ANY WARRANTY; without even the implied warranty of
- * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
- * GNU Affero General Public License for more details.
- *
- * You should have received a copy of the GNU Affero General Public License
- * along with this program.  If not, see <http://www.gnu.org/licenses/>.
- */
-
-use std::collections::HashMap;
-use std::str::FromStr;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-use pingora::cache::{CacheKey, CacheMeta, CachePhase, NoCacheReason, RespCacheable};
-use pingora::http::{RequestHeader, ResponseHeader};
-use pingora::prelude::HttpPeer;
-use pingora::proxy::{ProxyHttp, Session};
-use pingora::{Error as PingoraError, ErrorType, Result as PingoraResult};
-
-use async_trait::async_trait;
-use http::{HeaderName, HeaderValue};
-use serde::{Deserialize, Serialize};
-
-use crate::cluster::ClusterMetadata;
-use crate::def;
-use crate::gateway::Gateway;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    // sub: String,
-    exp: usize,
-    consumer: String,
-}
-
-// Main Struct as Router to implement ProxyHttp
-pub struct ProxyRouter {
-    pub gateway: Gateway,
-    pub clusters: Vec<ClusterMetadata>,
-    pub prefix_map: HashMap<String, usize>,
-    pub consumers: Option<Vec<def::Consumer>>,
-}
-
-// struct for proxy context
-pub struct RouterCtx {
-    pub cluster_identity: Option<String>,
-    pub cluster_address: usize,
-    pub proxy_retry: usize,
-    pub uri_origin: Option<String>,
-    pub client_address: Option<String>,
-    pub client_credentials: Option<String>,
-    pub enable_endpoint: bool,
-}
-
-#[async_trait]
-impl ProxyHttp for ProxyRouter {
-    // initialize ctx types
-    type CTX = RouterCtx;
-
-    // initial ctx values
-    fn new_ctx(&self) -> Self::CTX {
-        RouterCtx {
-            cluster_identity: None,
-            cluster_address: 0,
-            proxy_retry: 0,
-            uri_origin: None,
-            client_address: None,
-            client_credentials: None,
-            enable_endpoint: false,
-        }
-    }
-
-    // The upstream_peer phase executes after request_filter
-    // this lifecycle returns the http peer
-    async fn upstream_peer(
-        &self,
-        _session: &mut Session,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<Box<HttpPeer>> {
-        // Select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // Set up the upstream
-        let upstream = cluster.upstream.select(b"", 256).unwrap(); // Hash doesn't matter for round_robin
-        // Set SNI to the cluster's host
-        let mut peer = Box::new(HttpPeer::new(upstream, cluster.tls, cluster.host.clone()));
-        // given the proxy timeout
-        let timeout = cluster.timeout.unwrap_or(100);
-        peer.options.connection_timeout = Some(Duration::from_millis(timeout));
-        Ok(peer)
-    }
-
-    // The request_filter is the first phase that executes in the lifecycle
-    // if this lifecycle returns true = the proxy stops | false = continue to upper lifecycle
-    async fn request_filter(
-        &self,
-        session: &mut Session,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<bool>
-    where
-        Self::CTX: Send + Sync,
-    {
-        // Clone the original request header and get the URI path
-        let cloned_req_header = session.req_header().clone();
-        let original_uri = cloned_req_header.uri.path();
-        // set uri origin to ctx for cache key later
-        ctx.uri_origin = Some(cloned_req_header.uri.to_string());
-
-        // select the cluster based on prefix
-        let path_result = &self
-            .gateway
-            .resolver_provider
-            .select_cluster(&self.prefix_map, original_uri, session, ctx)
-            .await;
-        match path_result {
-            true => return Ok(true),
-            false => (),
-        }
-        // Select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // get cluster identity
-        ctx.cluster_identity = Some(cluster.get_name().clone());
-
-        // get client address as default identity
-        if let Some(address) = &self.gateway.request_provider.get_client_ip(session) {
-            println!("client ip address: {}", address);
-            ctx.client_address = Some(address.clone());
-            // check if ip restriction is enabled
-            if let Some(ip) = &cluster.get_ip() {
-                let allowed = ip.whitelist.iter().any(|allowed_ip| allowed_ip == address);
-                if !allowed {
-                    let _ = &self
-                        .gateway
-                        .response_provider
-                        .error_response(session, 403, "restricted ip address", None)
-                        .await;
-                    return Ok(true);
-                }
-            }
-        }
-        // get client credential as the primary identity
-        if let Some(credential) = &self
-            .gateway
-            .request_provider
-            .get_req_header_value(session, "Authorization")
-        {
-            ctx.client_credentials = Some(credential.to_string());
-        }
-
-        // check if rate limiter is enabled
-        if let Some(limiter) = cluster.get_rate_limit() {
-            // check if global limiter is provided for this cluster
-            if let Some(global_limiter_type) = limiter.get_global() {
-                match global_limiter_type {
-                    def::RatelimitType::Basic { basic } => {
-                        // rate limit incoming request
-                        let limiter_result = &self
-                            .gateway
-                            .limiter_provider
-                            .global_limiter(basic.limit, session, ctx)
-                            .await;
-                        match limiter_result {
-                            true => return Ok(true),
-                            false => (),
-                        }
-                    }
-                }
-            }
-            // check if client limiter is provided for this cluster
-            if let Some(client_limiter_type) = limiter.get_client() {
-                match client_limiter_type {
-                    def::RatelimitType::Basic { basic } => {
-                        // rate limit incoming request
-                        let limiter_result = &self
-                            .gateway
-                            .limiter_provider
-                            .client_limiter(basic.limit, session, ctx)
-                            .await;
-                        match limiter_result {
-                            true => return Ok(true),
-                            false => (),
-                        }
-                    }
-                }
-            }
-        }
-
-        // cluster authentication
-        if let Some(auth_type) = cluster.get_auth() {
-            match auth_type {
-                def::AuthType::Key { key } => {
-                    let access = &self
-                        .gateway
-                        .auth_provider
-                        .basic_key(&key, session, ctx)
-                        .await;
-                    match access {
-                        true => return Ok(true),
-                        false => (),
-                    }
-                }
-                def::AuthType::JWT { jwt } => {
-                    let access = &self
-                        .gateway
-                        .auth_provider
-                        .basic_jwt(&self, &cluster, &jwt, session, ctx)
-                        .await;
-                    match access {
-                        true => return Ok(true),
-                        false => (),
-                    }
-                }
-                _ => {}
-            }
-        }
-
-        // check if routes are declared in config
-        if let Some(routes) = cluster.get_routes() {
-            // get current path
-            let path = session.req_header().uri.path();
-            // check if the current uri matches any of the listed routes
-            // if match, enable endpoint
-            ctx.enable_endpoint = routes.iter().any(|route| {
-                // check if routes provide a path
-                if let Some(route_paths) = route.get_paths() {
-                    // find the current ui in the list of path
-                    route_paths.iter().any(|route_path| path == route_path)
-                } else {
-                    false
-                }
-            });
-        }
-        // if endpoint is enabled
-        if ctx.enable_endpoint {
-            println!("endpoint is enabled")
-        }
-        // continue the request
-        Ok(false)
-    }
-
-    // filter if response should be cached by enabling it
-    fn request_cache_filter(
-        &self,
-        session: &mut Session,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<()> {
-        // select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // get request method
-        let method = session.req_header().method.clone();
-        // filter if request method is GET and Storage exist
-        if method == "GET" {
-            if let Some(storage) = cluster.get_cache_storage() {
-                storage.enable(session)
-            }
-        }
-        Ok(())
-    }
-
-    // generate the cache key, if the filter says the response should be cache
-    fn cache_key_callback(
-        &self,
-        session: &Session,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<CacheKey> {
-        // select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // generate key based on the uri method
-        // this makes the cache meta unique and prevent cache conflict among other routes
-        let key = match ctx.uri_origin.clone() {
-            Some(origin) => CacheKey::new(cluster.get_name(), &origin, ""),
-            None => CacheKey::default(session.req_header()),
-        };
-        Ok(key)
-    }
-
-    // decide if the response is cacheable
-    fn response_cache_filter(
-        &self,
-        _session: &Session,
-        resp: &ResponseHeader,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<RespCacheable> {
-        // select the cluster to get the ttl
-        let cluster = &self.clusters[ctx.cluster_address];
-        let ttl = cluster.get_cache_ttl().unwrap_or(2) as u64;
-        // get current time and set expiry
-        let current_time = SystemTime::now();
-        let fresh_until = current_time + Duration::new(ttl, 0);
-        let meta = CacheMeta::new(fresh_until, current_time, 0, 0, resp.clone());
-        // cache response
-        Ok(RespCacheable::Cacheable(meta))
-    }
-
-    // the request filter used to insert or remove headers from client
-    async fn upstream_request_filter(
-        &self,
-        _session: &mut Session,
-        upstream_request: &mut RequestHeader,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<()> {
-        // select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // check if any request should be filtered
-        if let Some(request) = cluster.get_request() {
-            // check if headers is provided
-            if let Some(headers) = request.get_headers() {
-                // check if there is a header to insert
-                if let Some(insert) = headers.to_be_inserted() {
-                    for insertion in insert {
-                        let header_format = insertion.key.replace(' ', "-");
-                        let header_key = HeaderName::from_str(header_format.as_str()).unwrap();
-                        let header_value = HeaderValue::from_str(&insertion.value).unwrap();
-                        upstream_request.append_header(header_key, header_value)?;
-                    }
-                }
-                // check if there is a header to remove
-                if let Some(remove) = headers.to_be_removed() {
-                    for deletion in remove {
-                        let header_key = deletion.key.replace(' ', "-");
-                        upstream_request.remove_header(&header_key);
-                    }
-                }
-            }
-        }
-        // continue request
-        Ok(())
-    }
-
-    // the response filter is responsible for modifying response
-    async fn response_filter(
-        &self,
-        session: &mut Session,
-        upstream_response: &mut ResponseHeader,
-        ctx: &mut Self::CTX,
-    ) -> PingoraResult<()>
-    where
-        Self::CTX: Send + Sync,
-    {
-        // select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // check if any response should be filtered
-        if let Some(response) = cluster.get_response() {
-            // check if headers is provided
-            if let Some(headers) = response.get_headers() {
-                // check if there is a header to insert
-                if let Some(insert) = headers.to_be_inserted() {
-                    for insertion in insert {
-                        let header_format = insertion.key.replace(' ', "-");
-                        let header_key = HeaderName::from_str(header_format.as_str()).unwrap();
-                        let header_value = HeaderValue::from_str(&insertion.value).unwrap();
-                        upstream_response.append_header(header_key, header_value)?;
-                    }
-                }
-                // check if there is a header to remove
-                if let Some(remove) = headers.to_be_removed() {
-                    for deletion in remove {
-                        let header_key = deletion.key.replace(' ', "-");
-                        upstream_response.remove_header(&header_key);
-                    }
-                }
-            }
-        }
-        // default server identity in headers
-        upstream_response.insert_header("Server", "Glaive Gateway")?;
-        // insert header for cache status
-        if session.cache.enabled() {
-            match session.cache.phase() {
-                CachePhase::Hit => upstream_response.insert_header("x-cache-status", "hit")?,
-                CachePhase::Miss => upstream_response.insert_header("x-cache-status", "miss")?,
-                CachePhase::Stale => upstream_response.insert_header("x-cache-status", "stale")?,
-                CachePhase::Expired => {
-                    upstream_response.insert_header("x-cache-status", "expired")?
-                }
-                CachePhase::Revalidated | CachePhase::RevalidatedNoCache(_) => {
-                    upstream_response.insert_header("x-cache-status", "revalidated")?
-                }
-                _ => upstream_response.insert_header("x-cache-status", "invalid")?,
-            }
-        } else {
-            match session.cache.phase() {
-                CachePhase::Disabled(NoCacheReason::Deferred) => {
-                    upstream_response.insert_header("x-cache-status", "deferred")?
-                }
-                _ => upstream_response.insert_header("x-cache-status", "no-cache")?,
-            }
-        }
-        // cache lock duration
-        if let Some(d) = session.cache.lock_duration() {
-            upstream_response.insert_header("x-cache-lock-time-ms", format!("{}", d.as_millis()))?
-        }
-        // continue response
-        Ok(())
-    }
-
-    // The fail_to_connect phase executes when upstream_peer is unable to connect
-    // in this lifecycle it checks if request can be retryable or error
-    fn fail_to_connect(
-        &self,
-        _session: &mut Session,
-        _peer: &HttpPeer,
-        ctx: &mut Self::CTX,
-        mut e: Box<PingoraError>,
-    ) -> Box<PingoraError> {
-        // Select the cluster based on the selected index
-        let cluster = &self.clusters[ctx.cluster_address];
-        // check if retry reach limits
-        let max_try = cluster.get_retry().unwrap_or(1);
-        if ctx.proxy_retry > max_try {
-            return e;
-        }
-        // set to be retryable
-        ctx.proxy_retry += 1;
-        e.set_retry(true);
-        e
-    }
-
-    // async fn logging(
-    //     &self,
-    //     session: &mut Session,
-    //     _e: Option<&pingora_core::Error>,
-    //     ctx: &mut Self::CTX,
-    // ) {
-    //     let response_code = session
-    //         .response_written()
-    //         .map_or(0, |resp| resp.status.as_u16());
-    //     info!(
-    //         "{} response code: {response_code}",
-    //         self.request_summary(session, ctx)
-    //     );
-    //
-    //     self.req_metric.inc();
-    // }
-}
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pingora::cache::key::HashBinary;
+use pingora::cache::{CacheKey, CacheMeta, CachePhase, NoCacheReason, RespCacheable};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::HttpPeer;
+use pingora::protocols::Digest;
+use pingora::proxy::{ProxyHttp, Session};
+use pingora::upstreams::peer::Peer;
+use pingora::{Error as PingoraError, ErrorSource, ErrorType, Result as PingoraResult};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{HeaderName, HeaderValue};
+use tracing::field::Empty;
+use tracing::{debug, info_span, Instrument, Span};
+
+use crate::adaptive::AdaptivePermit;
+use crate::cache_policy;
+use crate::cluster::ClusterMetadata;
+use crate::concurrency::ConcurrencyPermit;
+use crate::consumer::{AccessDecision, ConsumerRegistry};
+use crate::def;
+use crate::gateway::Gateway;
+use crate::jwt::Claims;
+use crate::limiter::{LimitPhase, LimitState};
+use crate::logger::{AccessLogEntry, AccessLogger};
+use crate::metrics;
+use crate::path;
+use crate::quota::{QuotaManager, QuotaState};
+use crate::signature::BodyDigest;
+use crate::telemetry;
+
+// Main Struct as Router to implement ProxyHttp
+pub struct ProxyRouter {
+    pub gateway: Gateway,
+    pub clusters: Vec<ClusterMetadata>,
+    pub prefix_map: HashMap<String, usize>,
+    pub consumers: ConsumerRegistry,
+    pub quotas: Option<Arc<QuotaManager>>,
+    pub request_id_header: String,
+    pub access_logger: Option<AccessLogger>,
+}
+
+// struct for proxy context
+pub struct RouterCtx {
+    pub cluster_identity: Option<String>,
+    pub cluster_address: usize,
+    pub proxy_retry: usize,
+    pub uri_origin: Option<String>,
+    pub client_address: Option<String>,
+    pub client_credentials: Option<String>,
+    pub credential_source: Option<def::TokenSource>,
+    pub enable_endpoint: bool,
+    pub request_id: Option<String>,
+    pub request_id_header: String,
+    pub span: Span,
+    pub cache_span: Option<Span>,
+    pub connect_span: Option<Span>,
+    pub upstream_span: Option<Span>,
+    pub route_name: Option<String>,
+    pub route_params: HashMap<String, String>,
+    pub rate_limit: Option<LimitState>,
+    pub quota: Option<QuotaState>,
+    pub concurrency_permits: Vec<ConcurrencyPermit>,
+    pub adaptive_permit: Option<AdaptivePermit>,
+    pub upstream_address: Option<String>,
+    pub consumer: Option<String>,
+    pub consumer_groups: Option<Vec<String>>,
+    pub claims: Option<Claims>,
+    pub body_digest: Option<BodyDigest>,
+    pub cache_status: Option<String>,
+    pub request_start: Instant,
+    pub upstream_start: Option<Instant>,
+    pub upstream_connect_time: Option<Duration>,
+    pub upstream_response_time: Option<Duration>,
+    pub in_flight: bool,
+}
+
+impl RouterCtx {
+    // the initial ctx values of a request
+    pub fn new(request_id_header: String) -> RouterCtx {
+        RouterCtx {
+            cluster_identity: None,
+            cluster_address: 0,
+            proxy_retry: 0,
+            uri_origin: None,
+            client_address: None,
+            client_credentials: None,
+            credential_source: None,
+            enable_endpoint: false,
+            request_id: None,
+            request_id_header,
+            span: Span::none(),
+            cache_span: None,
+            connect_span: None,
+            upstream_span: None,
+            route_name: None,
+            route_params: HashMap::new(),
+            rate_limit: None,
+            quota: None,
+            concurrency_permits: Vec::new(),
+            adaptive_permit: None,
+            upstream_address: None,
+            consumer: None,
+            consumer_groups: None,
+            claims: None,
+            body_digest: None,
+            cache_status: None,
+            request_start: Instant::now(),
+            upstream_start: None,
+            upstream_connect_time: None,
+            upstream_response_time: None,
+            in_flight: false,
+        }
+    }
+}
+
+impl ProxyRouter {
+    // the main request filtering, executed within the request span
+    async fn filter_request(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterCtx,
+    ) -> PingoraResult<bool> {
+        // Clone the original request header and get the URI path
+        let cloned_req_header = session.req_header().clone();
+        let original_uri = cloned_req_header.uri.path();
+        // set uri origin to ctx for cache key later
+        ctx.uri_origin = Some(cloned_req_header.uri.to_string());
+
+        // select the cluster based on prefix
+        let path_result = &self
+            .gateway
+            .resolver_provider
+            .select_cluster(&self.prefix_map, original_uri, session, ctx)
+            .await;
+        match path_result {
+            true => return Ok(true),
+            false => (),
+        }
+        // Select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        // get cluster identity
+        ctx.cluster_identity = Some(cluster.get_name().clone());
+        // check if routes are declared in config
+        let mut route_access = None;
+        let mut route_limiters = None;
+        let mut route_concurrency = None;
+        if let Some(routes) = cluster.get_routes() {
+            // get current path
+            let path = session.req_header().uri.path();
+            // check if the current uri matches any of the listed routes
+            // if match, enable endpoint
+            let mut route_params = HashMap::new();
+            let matched_route = routes.iter().position(|route| {
+                // check if routes provide a path
+                if let Some(route_paths) = route.get_paths() {
+                    // find the current ui in the list of path, the path parameters are kept
+                    route_paths.iter().any(|route_path| {
+                        match path::match_route_path(route_path, path) {
+                            Some(params) => {
+                                route_params = params;
+                                true
+                            }
+                            None => false,
+                        }
+                    })
+                } else {
+                    false
+                }
+            });
+            if let Some(route_index) = matched_route {
+                let route = &routes[route_index];
+                ctx.route_params = route_params;
+                ctx.enable_endpoint = true;
+                ctx.route_name = route.get_name().clone();
+                route_access = route.get_access().as_ref();
+                route_limiters = cluster.get_route_limiters(route_index);
+                route_concurrency = cluster.get_route_concurrency(route_index);
+            }
+        }
+        // the request is in flight until the logging phase
+        metrics::REQUESTS_IN_FLIGHT
+            .with_label_values(&[metrics::cluster_label(ctx), metrics::route_label(ctx)])
+            .inc();
+        ctx.in_flight = true;
+
+        // get client address as default identity
+        if let Some(address) = &self.gateway.request_provider.get_client_ip(session) {
+            debug!("client ip address: {}", address);
+            ctx.client_address = Some(address.clone());
+            // check if ip restriction is enabled
+            if let Some(ip) = &cluster.get_ip() {
+                let allowed = ip.whitelist.iter().any(|allowed_ip| allowed_ip == address);
+                if !allowed {
+                    let _ = &self
+                        .gateway
+                        .response_provider
+                        .error_response(session, ctx, 403, "restricted ip address", None)
+                        .await;
+                    return Ok(true);
+                }
+            }
+        }
+        // get client credential as the primary identity
+        if let Some(credential) = &self
+            .gateway
+            .request_provider
+            .get_req_header_value(session, "Authorization")
+        {
+            ctx.client_credentials = Some(credential.to_string());
+        }
+        // rate limit the cluster then the matched route before the authentication
+        for limiters in [cluster.get_limiters().as_ref(), route_limiters]
+            .into_iter()
+            .flatten()
+        {
+            let limited = self
+                .gateway
+                .limiter_provider
+                .limit(limiters, LimitPhase::BeforeAuth, session, ctx)
+                .await;
+            if limited {
+                return Ok(true);
+            }
+        }
+
+        // cluster authentication
+        if let Some(auth_type) = cluster.get_auth() {
+            match auth_type {
+                def::AuthType::Key { key } => {
+                    let access = &self
+                        .gateway
+                        .auth_provider
+                        .basic_key(&self, &key, session, ctx)
+                        .instrument(info_span!("auth", auth = "key"))
+                        .await;
+                    match access {
+                        true => return Ok(true),
+                        false => (),
+                    }
+                }
+                def::AuthType::JWT { jwt: _ } => {
+                    // the validator is built with the cluster
+                    let validator = cluster
+                        .get_jwt_validator()
+                        .as_ref()
+                        .expect("jwt validator is not built");
+                    let access = &self
+                        .gateway
+                        .auth_provider
+                        .basic_jwt(validator, session, ctx)
+                        .instrument(info_span!("auth", auth = "jwt"))
+                        .await;
+                    match access {
+                        true => return Ok(true),
+                        false => (),
+                    }
+                }
+                def::AuthType::Basic { basic: _ } => {
+                    // the validator is built with the cluster
+                    let validator = cluster
+                        .get_basic_validator()
+                        .as_ref()
+                        .expect("basic validator is not built");
+                    let access = &self
+                        .gateway
+                        .auth_provider
+                        .basic_auth(&self, validator, session, ctx)
+                        .instrument(info_span!("auth", auth = "basic"))
+                        .await;
+                    match access {
+                        true => return Ok(true),
+                        false => (),
+                    }
+                }
+                def::AuthType::OAuth2Introspection {
+                    oauth2_introspection: _,
+                } => {
+                    // the introspector is built with the cluster
+                    let introspector = cluster
+                        .get_introspector()
+                        .as_ref()
+                        .expect("introspector is not built");
+                    let access = &self
+                        .gateway
+                        .auth_provider
+                        .oauth2_introspection(introspector, session, ctx)
+                        .instrument(info_span!("auth", auth = "oauth2_introspection"))
+                        .await;
+                    match access {
+                        true => return Ok(true),
+                        false => (),
+                    }
+                }
+                def::AuthType::Hmac { hmac: _ } => {
+                    // the validator is built with the cluster
+                    let validator = cluster
+                        .get_signature_validator()
+                        .as_ref()
+                        .expect("signature validator is not built");
+                    let access = &self
+                        .gateway
+                        .auth_provider
+                        .hmac_signature(&self, validator, session, ctx)
+                        .instrument(info_span!("auth", auth = "hmac"))
+                        .await;
+                    match access {
+                        true => return Ok(true),
+                        false => (),
+                    }
+                }
+                def::AuthType::External { external: _ } => {
+                    // the authenticator is built with the cluster
+                    let authenticator = cluster
+                        .get_external_authenticator()
+                        .as_ref()
+                        .expect("external authenticator is not built");
+                    let access = &self
+                        .gateway
+                        .auth_provider
+                        .external(authenticator, session, ctx)
+                        .instrument(info_span!("auth", auth = "external"))
+                        .await;
+                    match access {
+                        true => return Ok(true),
+                        false => (),
+                    }
+                }
+            }
+        }
+
+        // consumer authorization, the cluster access rules then the route access rules
+        for access in [cluster.get_access().as_ref(), route_access]
+            .into_iter()
+            .flatten()
+        {
+            match self.consumers.authorize(access, ctx.consumer.as_deref()) {
+                AccessDecision::Allowed => (),
+                AccessDecision::Unauthenticated => {
+                    // return 401 because the consumer is not authenticated
+                    return Ok(self
+                        .gateway
+                        .auth_provider
+                        .unauthenticated(cluster, session, ctx)
+                        .await);
+                }
+                AccessDecision::Forbidden(message) => {
+                    // return 403 because the consumer is not allowed
+                    metrics::auth_failure(ctx, "access_denied");
+                    let _ = &self
+                        .gateway
+                        .response_provider
+                        .error_response(session, ctx, 403, message, None)
+                        .await;
+                    return Ok(true);
+                }
+            }
+        }
+        // the limiters keyed by the consumer or a claim run once the consumer is authenticated
+        for limiters in [cluster.get_limiters().as_ref(), route_limiters]
+            .into_iter()
+            .flatten()
+        {
+            let limited = self
+                .gateway
+                .limiter_provider
+                .limit(limiters, LimitPhase::AfterAuth, session, ctx)
+                .await;
+            if limited {
+                return Ok(true);
+            }
+        }
+        // cap the in-flight requests of the cluster, then of the matched route
+        // the slots are released in the logging phase
+        for limiter in [cluster.get_concurrency().as_ref(), route_concurrency]
+            .into_iter()
+            .flatten()
+        {
+            let limited = self
+                .gateway
+                .limiter_provider
+                .limit_concurrency(limiter, session, ctx)
+                .await;
+            if limited {
+                return Ok(true);
+            }
+        }
+        // count the request on the quota of the consumer
+        if let Some(quotas) = &self.quotas {
+            let limited = self
+                .gateway
+                .limiter_provider
+                .quota(quotas, session, ctx)
+                .await;
+            if limited {
+                return Ok(true);
+            }
+        }
+        // shed the requests over the adaptive limit before they reach a struggling upstream
+        if let Some(limiter) = cluster.get_adaptive_limiter() {
+            match limiter.try_acquire() {
+                Some(permit) => ctx.adaptive_permit = Some(permit),
+                None => {
+                    metrics::rate_limit_rejection(ctx, "adaptive");
+                    let _ = &self
+                        .gateway
+                        .response_provider
+                        .error_response(session, ctx, 503, "Service overloaded", None)
+                        .await;
+                    return Ok(true);
+                }
+            }
+        }
+        // the groups of the registered consumer are forwarded with the identity
+        if let Some(consumer) = ctx
+            .consumer
+            .as_deref()
+            .and_then(|id| self.consumers.get(id))
+        {
+            ctx.consumer_groups = Some(consumer.get_groups().to_vec());
+        }
+        // if endpoint is enabled
+        if ctx.enable_endpoint {
+            debug!("endpoint is enabled")
+        }
+        // continue the request
+        Ok(false)
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for ProxyRouter {
+    // initialize ctx types
+    type CTX = RouterCtx;
+
+    // initial ctx values
+    fn new_ctx(&self) -> Self::CTX {
+        RouterCtx::new(self.request_id_header.clone())
+    }
+
+    // The upstream_peer phase executes after request_filter
+    // this lifecycle returns the http peer
+    async fn upstream_peer(
+        &self,
+        _session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<Box<HttpPeer>> {
+        // the logs of every phase are attached to the request span
+        let _entered = ctx.span.clone().entered();
+        // Select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        // Set up the upstream
+        let upstream = cluster.upstream.select(b"", 256).unwrap(); // Hash doesn't matter for round_robin
+        // Set SNI to the cluster's host
+        let mut peer = Box::new(HttpPeer::new(upstream, cluster.tls, cluster.host.clone()));
+        // given the proxy timeout
+        let timeout = cluster.timeout.unwrap_or(100);
+        peer.options.connection_timeout = Some(Duration::from_millis(timeout));
+        // keep the selected backend and the start time for the access log
+        ctx.upstream_address = Some(peer.address().to_string());
+        ctx.upstream_start = Some(Instant::now());
+        // the connect span lasts until the connection is established or failed
+        ctx.connect_span = Some(info_span!(
+            parent: &ctx.span,
+            "upstream_connect",
+            otel.kind = "client",
+            server.address = %peer.address(),
+            otel.status_code = Empty,
+        ));
+        Ok(peer)
+    }
+
+    // The request_filter is the first phase that executes in the lifecycle
+    // if this lifecycle returns true = the proxy stops | false = continue to upper lifecycle
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // resolve the request id, either from the incoming header or generated
+        let request_id = self
+            .gateway
+            .request_provider
+            .get_request_id(session, &ctx.request_id_header);
+        // every log line of this request is attached to the request span
+        // the span continues the trace of the incoming traceparent
+        let req_header = session.req_header();
+        ctx.span = info_span!(
+            "request",
+            request_id = %request_id,
+            otel.kind = "server",
+            http.request.method = %req_header.method,
+            url.path = req_header.uri.path(),
+            http.route = Empty,
+            http.response.status_code = Empty,
+            otel.status_code = Empty,
+        );
+        telemetry::extract_context(&ctx.span, req_header);
+        ctx.request_id = Some(request_id);
+        let span = ctx.span.clone();
+        self.filter_request(session, ctx).instrument(span).await
+    }
+
+    // filter if response should be cached by enabling it
+    fn request_cache_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()> {
+        let _entered = ctx.span.clone().entered();
+        // select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        // get request method
+        let method = session.req_header().method.clone();
+        // filter if request method is GET and Storage exist
+        // the request may ask not to use the cache with cache-control no-store
+        if method == "GET" && !cache_policy::request_no_store(session.req_header()) {
+            if let Some(storage) = cluster.get_cache_storage() {
+                storage.enable(session);
+                // the lookup span lasts until the cache hit or miss
+                ctx.cache_span = Some(info_span!(
+                    parent: &ctx.span,
+                    "cache_lookup",
+                    cache_status = Empty,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // generate the cache key, if the filter says the response should be cache
+    fn cache_key_callback(
+        &self,
+        session: &Session,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<CacheKey> {
+        let _entered = ctx.span.clone().entered();
+        // select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        // generate key based on the uri method
+        // this makes the cache meta unique and prevent cache conflict among other routes
+        let key = match (ctx.uri_origin.as_deref(), cluster.get_cache_policy()) {
+            (Some(origin), Some(policy)) => policy.get_key(
+                cluster.get_name(),
+                origin,
+                session.req_header(),
+                ctx.consumer.as_deref(),
+            ),
+            (Some(origin), None) => CacheKey::new(cluster.get_name(), origin, ""),
+            (None, _) => CacheKey::default(session.req_header()),
+        };
+        Ok(key)
+    }
+
+    // the cache_vary_filter builds the secondary key from the vary of the cached response
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        _ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        cache_policy::get_variance(meta, req)
+    }
+
+    // the cache_hit_filter phase executes when the cache lookup found the asset
+    async fn cache_hit_filter(
+        &self,
+        session: &Session,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        let _entered = ctx.span.clone().entered();
+        if let Some(span) = ctx.cache_span.take() {
+            span.record("cache_status", "hit");
+        }
+        // the fresh asset is revalidated when the request cache-control asks so
+        Ok(cache_policy::request_expired(session.req_header(), meta))
+    }
+
+    // the cache_miss phase executes when the cache lookup did not find the asset
+    fn cache_miss(&self, session: &mut Session, ctx: &mut Self::CTX) {
+        let _entered = ctx.span.clone().entered();
+        if let Some(span) = ctx.cache_span.take() {
+            span.record("cache_status", "miss");
+        }
+        session.cache.cache_miss();
+    }
+
+    // serve the expired asset while it is refreshed in the background or when the upstream fails
+    // the stale windows of the asset are checked before
+    fn should_serve_stale(
+        &self,
+        _session: &mut Session,
+        _ctx: &mut Self::CTX,
+        error: Option<&PingoraError>,
+    ) -> bool {
+        error.is_none_or(|e| e.esource() == &ErrorSource::Upstream)
+    }
+
+    // decide if the response is cacheable
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<RespCacheable> {
+        let _entered = ctx.span.clone().entered();
+        // select the cluster to get the cache policy
+        let cluster = &self.clusters[ctx.cluster_address];
+        let cacheable = match cluster.get_cache_policy() {
+            Some(policy) => {
+                let authenticated = cache_policy::request_authenticated(
+                    session.req_header(),
+                    ctx.consumer.as_deref(),
+                    ctx.client_credentials.is_some() || ctx.credential_source.is_some(),
+                );
+                let private = policy.is_private(ctx.consumer.as_deref());
+                policy.response_cacheable(resp, authenticated, private)
+            }
+            None => RespCacheable::Uncacheable(NoCacheReason::NeverEnabled),
+        };
+        Ok(cacheable)
+    }
+
+    // the request filter used to insert or remove headers from client
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()> {
+        let _entered = ctx.span.clone().entered();
+        // select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        // check if any request should be filtered
+        if let Some(request) = cluster.get_request() {
+            // check if headers is provided
+            if let Some(headers) = request.get_headers() {
+                // check if there is a header to insert
+                if let Some(insert) = headers.to_be_inserted() {
+                    for insertion in insert {
+                        let header_format = insertion.key.replace(' ', "-");
+                        let header_key = HeaderName::from_str(header_format.as_str()).unwrap();
+                        let header_value = HeaderValue::from_str(&insertion.value).unwrap();
+                        upstream_request.append_header(header_key, header_value)?;
+                    }
+                }
+                // check if there is a header to remove
+                if let Some(remove) = headers.to_be_removed() {
+                    for deletion in remove {
+                        let header_key = deletion.key.replace(' ', "-");
+                        upstream_request.remove_header(&header_key);
+                    }
+                }
+            }
+        }
+        // forward the authenticated identity to the upstream
+        if let Some(identity) = cluster.get_identity() {
+            self.gateway
+                .auth_provider
+                .forward_identity(identity, upstream_request, ctx)?;
+        }
+        // forward the request id to the upstream
+        if let Some(request_id) = &ctx.request_id {
+            upstream_request.insert_header(ctx.request_id_header.clone(), request_id.as_str())?;
+        }
+        // propagate the trace context, the upstream becomes a child of the upstream span
+        let span = ctx.upstream_span.as_ref().unwrap_or(&ctx.span);
+        telemetry::inject_context(span, upstream_request);
+        // continue request
+        Ok(())
+    }
+
+    // the request body filter verifies the signed body digest
+    // the digest is checked at the end of the body, so the upstream request is aborted on mismatch
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        let _entered = ctx.span.clone().entered();
+        // the body is held until the digest is verified, then forwarded at once
+        if let Some(digest) = ctx.body_digest.as_mut() {
+            if let Some(chunk) = body.take() {
+                if !digest.update(&chunk) {
+                    ctx.body_digest = None;
+                    metrics::auth_failure(ctx, "digest_body_too_large");
+                    return PingoraError::e_explain(
+                        ErrorType::HTTPStatus(413),
+                        "the body is too large to verify the digest",
+                    );
+                }
+            }
+            if !end_of_stream {
+                // an empty chunk is not written to the upstream
+                *body = Some(Bytes::new());
+                return Ok(());
+            }
+            match ctx.body_digest.take().and_then(|digest| digest.verify()) {
+                Some(verified) => *body = Some(verified),
+                None => {
+                    metrics::auth_failure(ctx, "digest_mismatch");
+                    return PingoraError::e_explain(
+                        ErrorType::HTTPStatus(400),
+                        "the body does not match the digest",
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the response filter is responsible for modifying response
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        let _entered = ctx.span.clone().entered();
+        // select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        // check if any response should be filtered
+        if let Some(response) = cluster.get_response() {
+            // check if headers is provided
+            if let Some(headers) = response.get_headers() {
+                // check if there is a header to insert
+                if let Some(insert) = headers.to_be_inserted() {
+                    for insertion in insert {
+                        let header_format = insertion.key.replace(' ', "-");
+                        let header_key = HeaderName::from_str(header_format.as_str()).unwrap();
+                        let header_value = HeaderValue::from_str(&insertion.value).unwrap();
+                        upstream_response.append_header(header_key, header_value)?;
+                    }
+                }
+                // check if there is a header to remove
+                if let Some(remove) = headers.to_be_removed() {
+                    for deletion in remove {
+                        let header_key = deletion.key.replace(' ', "-");
+                        upstream_response.remove_header(&header_key);
+                    }
+                }
+            }
+        }
+        // the quota of the most restrictive limiter
+        if let Some(rate_limit) = &ctx.rate_limit {
+            for (name, value) in rate_limit.get_headers() {
+                upstream_response.insert_header(name, value)?;
+            }
+        }
+        // the remaining quota of the consumer
+        if let Some(quota) = &ctx.quota {
+            for (name, value) in quota.get_headers() {
+                upstream_response.insert_header(name, value)?;
+            }
+        }
+        // default server identity in headers
+        upstream_response.insert_header("Server", "Glaive Gateway")?;
+        // echo the request id back to the client
+        if let Some(request_id) = &ctx.request_id {
+            upstream_response.insert_header(ctx.request_id_header.clone(), request_id.as_str())?;
+        }
+        // insert header for cache status
+        let cache_status = if session.cache.enabled() {
+            match session.cache.phase() {
+                CachePhase::Hit => "hit",
+                CachePhase::Miss => "miss",
+                CachePhase::Stale | CachePhase::StaleUpdating => "stale",
+                CachePhase::Expired => "expired",
+                CachePhase::Revalidated | CachePhase::RevalidatedNoCache(_) => "revalidated",
+                _ => "invalid",
+            }
+        } else {
+            match session.cache.phase() {
+                CachePhase::Disabled(NoCacheReason::Deferred) => "deferred",
+                _ => "no-cache",
+            }
+        };
+        upstream_response.insert_header("x-cache-status", cache_status)?;
+        ctx.cache_status = Some(cache_status.to_string());
+        if session.cache.enabled() {
+            metrics::CACHE_LOOKUPS
+                .with_label_values(&[
+                    metrics::cluster_label(ctx),
+                    metrics::route_label(ctx),
+                    cache_status,
+                ])
+                .inc();
+        }
+        // cache lock duration
+        if let Some(d) = session.cache.lock_duration() {
+            upstream_response.insert_header("x-cache-lock-time-ms", format!("{}", d.as_millis()))?
+        }
+        // continue response
+        Ok(())
+    }
+
+    // The fail_to_connect phase executes when upstream_peer is unable to connect
+    // in this lifecycle it checks if request can be retryable or error
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<PingoraError>,
+    ) -> Box<PingoraError> {
+        let _entered = ctx.span.clone().entered();
+        // Select the cluster based on the selected index
+        let cluster = &self.clusters[ctx.cluster_address];
+        metrics::UPSTREAM_CONNECT_FAILURES
+            .with_label_values(&[metrics::cluster_label(ctx), metrics::route_label(ctx)])
+            .inc();
+        if let Some(span) = ctx.connect_span.take() {
+            span.record("otel.status_code", "ERROR");
+        }
+        // the failed connection lowers the adaptive limit
+        if let Some(permit) = &mut ctx.adaptive_permit {
+            permit.record_drop();
+        }
+        // check if retry reach limits
+        let max_try = cluster.get_retry().unwrap_or(1);
+        if ctx.proxy_retry > max_try {
+            return e;
+        }
+        // set to be retryable
+        ctx.proxy_retry += 1;
+        metrics::UPSTREAM_RETRIES
+            .with_label_values(&[metrics::cluster_label(ctx), metrics::route_label(ctx)])
+            .inc();
+        e.set_retry(true);
+        e
+    }
+
+    // the connected_to_upstream phase executes when the upstream connection is established
+    // this lifecycle is used for measuring the upstream connect time
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        _reused: bool,
+        _peer: &HttpPeer,
+        _fd: std::os::unix::io::RawFd,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        let _entered = ctx.span.clone().entered();
+        ctx.upstream_connect_time = ctx.upstream_start.map(|start| start.elapsed());
+        // the connection is established, the response span lasts until the response header
+        ctx.connect_span = None;
+        ctx.upstream_span = Some(info_span!(
+            parent: &ctx.span,
+            "upstream_response",
+            otel.kind = "client",
+            http.response.status_code = Empty,
+        ));
+        Ok(())
+    }
+
+    // the upstream_response_filter phase executes when the upstream response header is received
+    // this lifecycle is used for measuring the upstream response time
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        let _entered = ctx.span.clone().entered();
+        ctx.upstream_response_time = ctx.upstream_start.map(|start| start.elapsed());
+        // the upstream latency and server errors adjust the adaptive limit
+        if let Some(permit) = &mut ctx.adaptive_permit {
+            if upstream_response.status.is_server_error() {
+                permit.record_drop();
+            } else if let Some(latency) = ctx.upstream_response_time {
+                permit.record_latency(latency);
+            }
+        }
+        if let Some(span) = ctx.upstream_span.take() {
+            span.record(
+                "http.response.status_code",
+                upstream_response.status.as_u16(),
+            );
+        }
+    }
+
+    // the logging phase is the last phase that executes in the lifecycle
+    // this lifecycle writes the access log, whatever the request outcome is
+    async fn logging(&self, session: &mut Session, e: Option<&PingoraError>, ctx: &mut Self::CTX) {
+        let _entered = ctx.span.clone().entered();
+        let status = session
+            .response_written()
+            .map_or(0, |resp| resp.status.as_u16());
+        // record the request metrics
+        let labels = [
+            metrics::cluster_label(ctx),
+            metrics::route_label(ctx),
+            metrics::status_class(status),
+        ];
+        metrics::REQUESTS_TOTAL.with_label_values(&labels).inc();
+        metrics::REQUEST_DURATION
+            .with_label_values(&labels)
+            .observe(ctx.request_start.elapsed().as_secs_f64());
+        if let Some(upstream_time) = ctx.upstream_response_time {
+            metrics::UPSTREAM_DURATION
+                .with_label_values(&labels)
+                .observe(upstream_time.as_secs_f64());
+        }
+        // close the request span with the outcome
+        ctx.cache_span = None;
+        ctx.connect_span = None;
+        ctx.upstream_span = None;
+        if let Some(route) = &ctx.route_name {
+            ctx.span.record("http.route", route.as_str());
+        }
+        ctx.span.record("http.response.status_code", status);
+        if status >= 500 || e.is_some() {
+            ctx.span.record("otel.status_code", "ERROR");
+        }
+        if ctx.in_flight {
+            metrics::REQUESTS_IN_FLIGHT
+                .with_label_values(&[metrics::cluster_label(ctx), metrics::route_label(ctx)])
+                .dec();
+            ctx.in_flight = false;
+        }
+        // release the concurrency slots whatever the outcome
+        ctx.concurrency_permits.clear();
+        // the upstream errors without a response, e.g. the read timeouts, are drops
+        if let Some(mut permit) = ctx.adaptive_permit.take() {
+            if e.is_some() && !permit.has_sample() {
+                permit.record_drop();
+            }
+        }
+        // write the access log
+        let access_logger = match &self.access_logger {
+            Some(access_logger) => access_logger,
+            None => return,
+        };
+        let req_header = session.req_header();
+        // log the original path, before the cluster prefix was removed
+        let path = match &ctx.uri_origin {
+            Some(origin) => origin.split('?').next().unwrap_or_default().to_string(),
+            None => req_header.uri.path().to_string(),
+        };
+        let as_millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let entry = AccessLogEntry {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            request_id: ctx.request_id.clone(),
+            client_ip: ctx.client_address.clone(),
+            method: req_header.method.to_string(),
+            path,
+            cluster: ctx.cluster_identity.clone(),
+            route: ctx.route_name.clone(),
+            upstream: ctx.upstream_address.clone(),
+            status,
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+            latency_ms: as_millis(ctx.request_start.elapsed()),
+            upstream_connect_ms: ctx.upstream_connect_time.map(as_millis),
+            upstream_response_ms: ctx.upstream_response_time.map(as_millis),
+            consumer: ctx.consumer.clone(),
+            cache_status: ctx.cache_status.clone(),
+            error: e.map(|e| e.to_string()),
+        };
+        access_logger.log(&entry);
+    }
+}
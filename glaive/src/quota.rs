@@ -0,0 +1,491 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use redis::RedisError;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::def::{Consumer, ConsumerGroup, FailureMode, Quota, QuotaStorage, Quotas};
+use crate::redis::RedisConnection;
+
+// enum calendar period of a quota
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    // the id of the period containing the time, the counters are keyed by it
+    fn get_id(&self, now: DateTime<Utc>) -> String {
+        match self {
+            QuotaPeriod::Daily => now.format("%Y-%m-%d").to_string(),
+            QuotaPeriod::Monthly => now.format("%Y-%m").to_string(),
+        }
+    }
+    // the start of the next period
+    fn get_reset(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive();
+        let next = match self {
+            QuotaPeriod::Daily => today.succ_opt(),
+            QuotaPeriod::Monthly => NaiveDate::from_ymd_opt(today.year(), today.month(), 1)
+                .and_then(|month| month.checked_add_months(Months::new(1))),
+        };
+        next.and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|date| date.and_utc())
+            .unwrap_or(now)
+    }
+}
+
+// the quota state of a consumer, the most restrictive period is reported
+#[derive(Debug, Clone)]
+pub struct QuotaState {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    // seconds until the reset of the period
+    pub reset: u64,
+}
+
+impl QuotaState {
+    // the quota response headers
+    pub fn get_headers(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("X-Quota-Limit", self.limit.to_string()),
+            ("X-Quota-Remaining", self.remaining.to_string()),
+            ("X-Quota-Reset", self.reset.to_string()),
+        ]
+    }
+}
+
+// the usage of a quota period, served by the admin api
+#[derive(Debug, Serialize)]
+pub struct QuotaUsage {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub reset: String,
+}
+
+// where the counters are kept
+enum QuotaStore {
+    // the counters are written to the file in the background
+    File {
+        path: String,
+        flush_interval: Duration,
+        counters: Mutex<HashMap<String, u64>>,
+        dirty: AtomicBool,
+    },
+    // the counters are shared by every gateway
+    Redis {
+        connection: RedisConnection,
+        prefix: String,
+    },
+}
+
+// the quotas of every consumer, counted over the calendar periods
+pub struct QuotaManager {
+    quotas: HashMap<String, Vec<(QuotaPeriod, u64)>>,
+    store: QuotaStore,
+    status: usize,
+    message: String,
+    failure_mode: FailureMode,
+}
+
+impl QuotaManager {
+    // build the quota manager, returns none when no consumer has a quota
+    pub fn new(
+        config: Option<Quotas>,
+        consumers: &[Consumer],
+        groups: Option<Vec<ConsumerGroup>>,
+    ) -> Option<Arc<Self>> {
+        let group_quotas: HashMap<String, Quota> = groups
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|group| group.quota.map(|quota| (group.name, quota)))
+            .collect();
+        let mut quotas = HashMap::new();
+        for consumer in consumers {
+            let periods = resolve_quota(consumer, &group_quotas);
+            if !periods.is_empty() {
+                quotas.insert(consumer.get_id().clone(), periods);
+            }
+        }
+        if quotas.is_empty() {
+            return None;
+        }
+        // the counters file is never written to an implicit location
+        let config = config.unwrap_or_else(|| {
+            panic!("the consumer quotas require the quota storage, a file path or redis")
+        });
+        let status = config.get_status();
+        if status != 429 && status != 403 {
+            panic!("invalid quota status {}, expected 429 or 403", status);
+        }
+        let store = match &config.storage {
+            QuotaStorage::Redis { redis } => QuotaStore::Redis {
+                connection: RedisConnection::new(redis)
+                    .unwrap_or_else(|e| panic!("invalid quota redis url {}: {}", redis.url, e)),
+                prefix: redis.get_prefix(),
+            },
+            QuotaStorage::File { file } => {
+                QuotaStore::new_file(file.path.clone(), file.flush_interval)
+            }
+        };
+        Some(Arc::new(QuotaManager {
+            quotas,
+            store,
+            status,
+            message: config.get_message(),
+            failure_mode: config.failure_mode.unwrap_or(FailureMode::Open),
+        }))
+    }
+
+    // count the request of the consumer
+    // returns none when the consumer has no quota, the over quota requests are not counted
+    pub async fn consume(&self, consumer: &str) -> Result<Option<QuotaState>, RedisError> {
+        let Some(periods) = self.quotas.get(consumer) else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let keys = counter_keys(consumer, periods, now);
+        let limits: Vec<u64> = periods.iter().map(|(_, limit)| *limit).collect();
+        let (allowed, counters) = match &self.store {
+            QuotaStore::File {
+                counters, dirty, ..
+            } => {
+                let mut counters = counters.lock().unwrap();
+                let current: Vec<u64> = keys
+                    .iter()
+                    .map(|key| counters.get(key).copied().unwrap_or(0))
+                    .collect();
+                let allowed = current
+                    .iter()
+                    .zip(&limits)
+                    .all(|(used, limit)| used < limit);
+                if !allowed {
+                    (false, current)
+                } else {
+                    for key in &keys {
+                        *counters.entry(key.clone()).or_insert(0) += 1;
+                    }
+                    dirty.store(true, Ordering::Relaxed);
+                    (true, current.iter().map(|used| used + 1).collect())
+                }
+            }
+            QuotaStore::Redis { connection, prefix } => {
+                let keys: Vec<String> = keys
+                    .iter()
+                    .map(|key| format!("{}:quota:{}", prefix, key))
+                    .collect();
+                let expire_at: Vec<i64> = periods
+                    .iter()
+                    .map(|(period, _)| period.get_reset(now).timestamp())
+                    .collect();
+                connection.consume_quota(&keys, &limits, &expire_at).await?
+            }
+        };
+        // the period with the least remaining requests is reported
+        let state = periods
+            .iter()
+            .zip(counters)
+            .map(|((period, limit), used)| QuotaState {
+                allowed,
+                limit: *limit,
+                remaining: limit.saturating_sub(used),
+                reset: (period.get_reset(now) - now).num_seconds().max(0) as u64,
+            })
+            .min_by_key(|state| state.remaining);
+        Ok(state)
+    }
+
+    // the usage of the current periods, returns none when the consumer has no quota
+    pub async fn get_usage(&self, consumer: &str) -> Result<Option<Vec<QuotaUsage>>, RedisError> {
+        let Some(periods) = self.quotas.get(consumer) else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let keys = counter_keys(consumer, periods, now);
+        let counters: Vec<u64> = match &self.store {
+            QuotaStore::File { counters, .. } => {
+                let counters = counters.lock().unwrap();
+                keys.iter()
+                    .map(|key| counters.get(key).copied().unwrap_or(0))
+                    .collect()
+            }
+            QuotaStore::Redis { connection, prefix } => {
+                let keys: Vec<String> = keys
+                    .iter()
+                    .map(|key| format!("{}:quota:{}", prefix, key))
+                    .collect();
+                connection.get_counters(&keys).await?
+            }
+        };
+        let usage = periods
+            .iter()
+            .zip(counters)
+            .map(|((period, limit), used)| QuotaUsage {
+                period: *period,
+                limit: *limit,
+                used,
+                remaining: limit.saturating_sub(used),
+                reset: period.get_reset(now).to_rfc3339(),
+            })
+            .collect();
+        Ok(Some(usage))
+    }
+
+    // reset the counters of the current periods, returns false when the consumer has no quota
+    pub async fn reset(&self, consumer: &str) -> Result<bool, RedisError> {
+        let Some(periods) = self.quotas.get(consumer) else {
+            return Ok(false);
+        };
+        let keys = counter_keys(consumer, periods, Utc::now());
+        match &self.store {
+            QuotaStore::File {
+                counters, dirty, ..
+            } => {
+                let mut counters = counters.lock().unwrap();
+                for key in &keys {
+                    counters.remove(key);
+                }
+                dirty.store(true, Ordering::Relaxed);
+            }
+            QuotaStore::Redis { connection, prefix } => {
+                let keys: Vec<String> = keys
+                    .iter()
+                    .map(|key| format!("{}:quota:{}", prefix, key))
+                    .collect();
+                connection.delete(&keys).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn get_status(&self) -> usize {
+        self.status
+    }
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+    pub fn get_failure_mode(&self) -> FailureMode {
+        self.failure_mode
+    }
+    // the file counters are written by the background service
+    pub fn is_file_store(&self) -> bool {
+        matches!(self.store, QuotaStore::File { .. })
+    }
+}
+
+impl QuotaStore {
+    // load the counters of the file, a missing file starts empty
+    fn new_file(path: String, flush_interval: Option<u64>) -> QuotaStore {
+        let counters = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Unable to parse the quota file {}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        QuotaStore::File {
+            path,
+            flush_interval: Duration::from_secs(flush_interval.unwrap_or(5).max(1)),
+            counters: Mutex::new(counters),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    // write the counters of the current periods to the file
+    fn flush(&self) {
+        let QuotaStore::File {
+            path,
+            counters,
+            dirty,
+            ..
+        } = self
+        else {
+            return;
+        };
+        if !dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let content = {
+            let mut counters = counters.lock().unwrap();
+            // the counters of the past periods are dropped
+            let now = Utc::now();
+            let current =
+                [QuotaPeriod::Daily, QuotaPeriod::Monthly].map(|period| period.get_id(now));
+            counters.retain(|key, _| {
+                key.rsplit(':')
+                    .next()
+                    .is_some_and(|id| current.iter().any(|current| current == id))
+            });
+            serde_json::to_string(&*counters).unwrap_or_default()
+        };
+        // the file is replaced at once, so a crash never leaves it half written
+        let temporary = format!("{}.tmp", path);
+        if let Err(e) = fs::write(&temporary, content).and_then(|_| fs::rename(&temporary, path)) {
+            warn!("Unable to write the quota file {}: {}", path, e);
+            dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// the periods of the consumer quota, or the lowest quota of its groups
+fn resolve_quota(
+    consumer: &Consumer,
+    group_quotas: &HashMap<String, Quota>,
+) -> Vec<(QuotaPeriod, u64)> {
+    let quotas: Vec<&Quota> = match consumer.get_quota() {
+        Some(quota) => vec![quota],
+        None => consumer
+            .get_groups()
+            .iter()
+            .filter_map(|group| group_quotas.get(group))
+            .collect(),
+    };
+    let lowest = |limits: Vec<u64>| limits.into_iter().min();
+    let daily = lowest(quotas.iter().filter_map(|quota| quota.daily).collect());
+    let monthly = lowest(quotas.iter().filter_map(|quota| quota.monthly).collect());
+    [(QuotaPeriod::Daily, daily), (QuotaPeriod::Monthly, monthly)]
+        .into_iter()
+        .filter_map(|(period, limit)| limit.map(|limit| (period, limit)))
+        .collect()
+}
+
+// the counter keys of the current periods, e.g. partner:daily:2024-01-31
+fn counter_keys(consumer: &str, periods: &[(QuotaPeriod, u64)], now: DateTime<Utc>) -> Vec<String> {
+    periods
+        .iter()
+        .map(|(period, _)| {
+            let name = match period {
+                QuotaPeriod::Daily => "daily",
+                QuotaPeriod::Monthly => "monthly",
+            };
+            format!("{}:{}:{}", consumer, name, period.get_id(now))
+        })
+        .collect()
+}
+
+// background processing service that writes the quota file
+pub struct QuotaBackgroundService {
+    pub manager: Arc<QuotaManager>,
+}
+
+#[async_trait]
+impl BackgroundService for QuotaBackgroundService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let QuotaStore::File { flush_interval, .. } = &self.manager.store else {
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(*flush_interval) => {
+                    self.manager.store.flush();
+                }
+                _ = shutdown.changed() => {
+                    // the last counters are kept for the next start
+                    self.manager.store.flush();
+                    info!("Quota counters are written");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod quota_mod {
+    use super::*;
+
+    #[test]
+    fn period_test() {
+        let now = DateTime::parse_from_rfc3339("2024-12-31T13:45:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(QuotaPeriod::Daily.get_id(now), "2024-12-31");
+        assert_eq!(QuotaPeriod::Monthly.get_id(now), "2024-12");
+        assert_eq!(
+            QuotaPeriod::Daily.get_reset(now).to_rfc3339(),
+            "2025-01-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            QuotaPeriod::Monthly.get_reset(now).to_rfc3339(),
+            "2025-01-01T00:00:00+00:00"
+        );
+    }
+
+    #[tokio::test]
+    async fn consume_test() {
+        let consumers: Vec<Consumer> = serde_yaml::from_str(
+            "- id: partner\n  groups: [free, paid]\n- id: ops\n  quota:\n    monthly: 5",
+        )
+        .unwrap();
+        let groups: Vec<ConsumerGroup> = serde_yaml::from_str(
+            "- name: free\n  quota:\n    daily: 2\n- name: paid\n  quota:\n    daily: 100\n    monthly: 1000",
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("quota-{}.json", uuid::Uuid::now_v7()));
+        let config: Quotas =
+            serde_yaml::from_str(&format!("storage:\n  file:\n    path: {:?}", path)).unwrap();
+        let manager = QuotaManager::new(Some(config), &consumers, Some(groups)).unwrap();
+        // the lowest daily quota of the groups applies
+        let state = manager.consume("partner").await.unwrap().unwrap();
+        assert!(state.allowed);
+        assert_eq!((state.limit, state.remaining), (2, 1));
+        manager.consume("partner").await.unwrap();
+        let state = manager.consume("partner").await.unwrap().unwrap();
+        assert!(!state.allowed);
+        // the rejected request is not counted
+        let usage = manager.get_usage("partner").await.unwrap().unwrap();
+        assert_eq!(usage[0].used, 2);
+        assert_eq!(usage[1].used, 2);
+        assert!(manager.consume("unknown").await.unwrap().is_none());
+        // the counters are kept across restarts
+        manager.store.flush();
+        let consumers: Vec<Consumer> =
+            serde_yaml::from_str("- id: ops\n  quota:\n    daily: 2").unwrap();
+        let config: Quotas =
+            serde_yaml::from_str(&format!("storage:\n  file:\n    path: {:?}", path)).unwrap();
+        let restarted = QuotaManager::new(Some(config), &consumers, None).unwrap();
+        assert!(manager.reset("partner").await.unwrap());
+        assert_eq!(
+            manager.get_usage("partner").await.unwrap().unwrap()[0].used,
+            0
+        );
+        match &restarted.store {
+            QuotaStore::File { counters, .. } => assert_eq!(counters.lock().unwrap().len(), 2),
+            QuotaStore::Redis { .. } => panic!("the file store is configured"),
+        }
+        let _ = fs::remove_file(path);
+    }
+}
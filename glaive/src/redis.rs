@@ -1,224 +1,453 @@
-/**
- * Copyright (c) 2024-2025 Glaive, Inc.
- *
- * This file is part of Glaive Gateway
- *
- * This program is free software: you can redistribute it and/or modify
- * it under the terms of the GNU Affero General Public License as published by
- * the Free Software Foundation, either version 3 of the License, or
- * (at your option) any later version.
- *
- * This program is distributed in the hope that it will be useful
- * but WITHOUT ANY WARRANTY; without even the implied warranty of
- * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
- * GNU Affero General Public License for more details.
- *
- * You should have received a copy of the GNU Affero General Public License
- * along with this program.  If not, see <http://www.gnu.org/licenses/>.
- */
-
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use redis::{Commands, Connection, RedisError};
-
-pub struct RedisClient {
-    client: Connection,
-}
-
-impl RedisClient {
-    pub fn new(host: &str, port: &str) -> Result<Self, RedisError> {
-        let address = format!("redis://{}:{}", host, port);
-        let client = redis::Client::open(address)?;
-        let client_conn = client.get_connection()?;
-        Ok(RedisClient {
-            client: client_conn,
-        })
-    }
-
-    // Set a cache entry with expiration (TTL)
-    pub fn set_with_expiration(
-        &mut self,
-        key: &str,
-        value: &str,
-        ttl_seconds: u64,
-    ) -> Result<(), RedisError> {
-        self.client.set_ex(key, value, ttl_seconds)?; // Set key with TTL
-        Ok(())
-    }
-
-    // Get cache value
-    pub fn get_cache(&mut self, key: &str) -> Result<Option<String>, RedisError> {
-        let result: Option<String> = self.client.get(key)?;
-        Ok(result)
-    }
-
-    pub fn fixed_window_rate_limit(
-        &mut self,
-        key: &str,
-        limit: i64,
-        period_seconds: i64,
-    ) -> Result<bool, RedisError> {
-        let current_count: i64 = self.client.incr(key, 1)?;
-        if current_count == 1 {
-            self.client.expire(key, period_seconds)?;
-        }
-        Ok(current_count <= limit)
-    }
-
-    pub fn sliding_window_log_rate_limit(
-        &mut self,
-        key: &str,
-        limit: usize,
-        window_seconds: i64,
-    ) -> Result<bool, RedisError> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs() as i64;
-        let window_start = now - window_seconds;
-
-        redis::pipe()
-            .zrevrangebyscore(key, "-inf", window_start)
-            .zadd(key, now, now)
-            .zcard(key)
-            .expire(key, window_seconds)
-            .query::<((), (), usize, ())>(&mut self.client)
-            .map(|(_, _, count, _)| count <= limit)
-    }
-
-    pub fn token_bucket_rate_limit(
-        &mut self,
-        key: &str,
-        capacity: i64,
-        refill_rate: f64,
-        period_seconds: usize,
-    ) -> Result<bool, RedisError> {
-        // initialize lua script for query
-        let script = redis::Script::new(
-            r"
-              local key = KEYS[1]
-              local capacity = tonumber(ARGV[1])
-              local refill_rate = tonumber(ARGV[2])
-              local period_seconds = tonumber(ARGV[3])
-              local now = tonumber(ARGV[4])
-  
-              local last_refill = redis.call('GET', key .. ':last_refill') or '0'
-              last_refill = tonumber(last_refill)
-  
-              local tokens = redis.call('GET', key) or tostring(capacity)
-              tokens = tonumber(tokens)
-  
-              local elapsed = now - last_refill
-              local refill = math.min(capacity, tokens + (elapsed * refill_rate))
-  
-              if refill >= 1 then
-                  redis.call('SET', key, tostring(refill - 1))
-                  redis.call('SET', key .. ':last_refill', tostring(now))
-                  redis.call('EXPIRE', key, period_seconds)
-                  redis.call('EXPIRE', key .. ':last_refill', period_seconds)
-                  return 1
-              else
-                  return 0
-              end
-          ",
-        );
-        // get current time
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        // execute script
-        let result: i32 = script
-            .key(key)
-            .arg(capacity)
-            .arg(refill_rate)
-            .arg(period_seconds)
-            .arg(now)
-            .invoke(&mut self.client)?;
-
-        Ok(result == 1)
-    }
-
-    pub fn leaky_bucket_rate_limit(
-        &mut self,
-        key: &str,
-        capacity: i64,
-        leak_rate: f64,
-    ) -> Result<bool, RedisError> {
-        // initialize lua script for query
-        let script = redis::Script::new(
-            r"
-              local key = KEYS[1]
-              local capacity = tonumber(ARGV[1])
-              local leak_rate = tonumber(ARGV[2])
-              local now = tonumber(ARGV[3])
-  
-              local last_check = redis.call('GET', key .. ':last_check') or tostring(now)
-              last_check = tonumber(last_check)
-  
-              local level = redis.call('GET', key) or '0'
-              level = tonumber(level)
-  
-              local elapsed = now - last_check
-              local leaked = math.max(0, level - (elapsed * leak_rate))
-  
-              if leaked < capacity then
-                  redis.call('SET', key, tostring(leaked + 1))
-                  redis.call('SET', key .. ':last_check', tostring(now))
-                  return 1
-              else
-                  redis.call('SET', key, tostring(capacity))
-                  redis.call('SET', key .. ':last_check', tostring(now))
-                  return 0
-              end
-          ",
-        );
-        // get current time
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        // execute script
-        let result: i32 = script
-            .key(key)
-            .arg(capacity)
-            .arg(leak_rate)
-            .arg(now)
-            .invoke(&mut self.client)?;
-
-        Ok(result == 1)
-    }
-
-    pub fn semaphore_rate_limit(
-        &mut self,
-        key: &str,
-        max_concurrency: i64,
-    ) -> Result<bool, RedisError> {
-        let script = redis::Script::new(
-            r"
-              local key = KEYS[1]
-              local max_concurrency = tonumber(ARGV[1])
-  
-              local current = redis.call('GET', key) or '0'
-              current = tonumber(current)
-  
-              if current < max_concurrency then
-                  redis.call('INCR', key)
-                  return 1
-              else
-                  return 0
-              end
-          ",
-        );
-
-        let result: i32 = script
-            .key(key)
-            .arg(max_concurrency)
-            .invoke(&mut self.client)?;
-
-        Ok(result == 1)
-    }
-
-    pub fn release_semaphore(&mut self, key: &str) -> Result<(), RedisError> {
-        self.client.decr(key, 1)
-    }
-}
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, AsyncConnectionConfig, RedisError, Script};
+use tokio::sync::Mutex;
+
+use crate::def::{Redis, RedisAlgorithm};
+
+// the serialized cache meta, its header and the body of a cached object
+pub type CachedObject = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+// the async redis connection pool shared by every request
+// the connections are opened on the first use, and opened again after a failure
+pub struct RedisConnection {
+    client: redis::Client,
+    pool: Vec<Mutex<Option<MultiplexedConnection>>>,
+    next: AtomicUsize,
+    config: AsyncConnectionConfig,
+}
+
+impl RedisConnection {
+    pub fn new(redis: &Redis) -> Result<Self, RedisError> {
+        // both the connect and the command are bounded by the timeout
+        let timeout = redis.get_timeout();
+        let config = AsyncConnectionConfig::new()
+            .set_connection_timeout(timeout)
+            .set_response_timeout(timeout);
+        Ok(RedisConnection {
+            client: redis::Client::open(redis.url.as_str())?,
+            pool: (0..redis.get_pool_size())
+                .map(|_| Mutex::new(None))
+                .collect(),
+            next: AtomicUsize::new(0),
+            config,
+        })
+    }
+
+    // get a multiplexed connection of the pool, it is cheap to clone
+    // returns the slot of the connection, used to reset it after a failure
+    pub async fn get_connection(&self) -> Result<(usize, MultiplexedConnection), RedisError> {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        let mut connection = self.pool[slot].lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok((slot, connection.clone()));
+        }
+        let opened = self
+            .client
+            .get_multiplexed_async_connection_with_config(&self.config)
+            .await?;
+        *connection = Some(opened.clone());
+        Ok((slot, opened))
+    }
+
+    // drop the connection after a failure, so the next call opens a new one
+    pub async fn reset(&self, slot: usize, error: &RedisError) {
+        if error.is_io_error() || error.is_connection_dropped() || error.is_timeout() {
+            *self.pool[slot].lock().await = None;
+        }
+    }
+
+    // get a value
+    pub async fn get(&self, key: &str) -> Result<Option<String>, RedisError> {
+        let (slot, mut connection) = self.get_connection().await?;
+        let result = connection.get(key).await;
+        if let Err(e) = &result {
+            self.reset(slot, e).await;
+        }
+        result
+    }
+
+    // set a value with expiration (TTL)
+    pub async fn set_ex(&self, key: &str, value: &str, ttl_seconds: u64) -> Result<(), RedisError> {
+        let (slot, mut connection) = self.get_connection().await?;
+        let result = connection.set_ex(key, value, ttl_seconds).await;
+        if let Err(e) = &result {
+            self.reset(slot, e).await;
+        }
+        result
+    }
+
+    // count the request with the given algorithm
+    // returns if it is allowed, the remaining requests and the milliseconds until the reset
+    // the scripts use the redis clock, so every gateway shares the same window
+    pub async fn rate_limit(
+        &self,
+        algorithm: RedisAlgorithm,
+        key: &str,
+        limit: i64,
+        window: Duration,
+    ) -> Result<(bool, i64, u64), RedisError> {
+        let window_ms = window.as_millis() as u64;
+        let mut invocation = match algorithm {
+            RedisAlgorithm::FixedWindow => FIXED_WINDOW.key(key),
+            RedisAlgorithm::SlidingWindow => SLIDING_WINDOW.key(key),
+            RedisAlgorithm::TokenBucket => TOKEN_BUCKET.key(key),
+            RedisAlgorithm::LeakyBucket => LEAKY_BUCKET.key(key),
+        };
+        invocation.arg(limit).arg(window_ms);
+        if algorithm == RedisAlgorithm::SlidingWindow {
+            // every request is a member of the log
+            invocation.arg(uuid::Uuid::now_v7().to_string());
+        }
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<(i64, i64, i64), RedisError> =
+            invocation.invoke_async(&mut connection).await;
+        match result {
+            Ok((allowed, remaining, reset)) => Ok((allowed == 1, remaining, reset.max(0) as u64)),
+            Err(e) => {
+                self.reset(slot, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // count a request on every quota counter, nothing is counted when a counter is over its limit
+    // returns if it is allowed and the counters
+    pub async fn consume_quota(
+        &self,
+        keys: &[String],
+        limits: &[u64],
+        expire_at: &[i64],
+    ) -> Result<(bool, Vec<u64>), RedisError> {
+        let mut invocation = CONSUME_QUOTA.prepare_invoke();
+        for ((key, limit), expire_at) in keys.iter().zip(limits).zip(expire_at) {
+            invocation.key(key).arg(*limit).arg(*expire_at);
+        }
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<(i64, Vec<u64>), RedisError> =
+            invocation.invoke_async(&mut connection).await;
+        match result {
+            Ok((allowed, counters)) => Ok((allowed == 1, counters)),
+            Err(e) => {
+                self.reset(slot, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // get the counters, the missing counters are zero
+    pub async fn get_counters(&self, keys: &[String]) -> Result<Vec<u64>, RedisError> {
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<Vec<Option<u64>>, RedisError> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut connection)
+            .await;
+        match result {
+            Ok(counters) => Ok(counters
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect()),
+            Err(e) => {
+                self.reset(slot, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // delete the keys, returns the number of deleted keys
+    pub async fn delete(&self, keys: &[String]) -> Result<usize, RedisError> {
+        let (slot, mut connection) = self.get_connection().await?;
+        let result = connection.del(keys).await;
+        if let Err(e) = &result {
+            self.reset(slot, e).await;
+        }
+        result
+    }
+
+    // get the meta and the body of a cached object
+    pub async fn cache_get(&self, key: &str) -> Result<Option<CachedObject>, RedisError> {
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<(Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>), RedisError> =
+            connection.hget(key, &["meta", "header", "body"]).await;
+        match result {
+            Ok((Some(meta), Some(header), Some(body))) => Ok(Some((meta, header, body))),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                self.reset(slot, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // store a cached object, the object expires with the ttl
+    pub async fn cache_set(
+        &self,
+        key: &str,
+        object: CachedObject,
+        ttl: Duration,
+    ) -> Result<(), RedisError> {
+        let (meta, header, body) = object;
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<(), RedisError> = redis::pipe()
+            .atomic()
+            .del(key)
+            .ignore()
+            .hset_multiple(key, &[("meta", meta), ("header", header), ("body", body)])
+            .ignore()
+            .pexpire(key, ttl.as_millis() as i64)
+            .ignore()
+            .query_async(&mut connection)
+            .await;
+        if let Err(e) = &result {
+            self.reset(slot, e).await;
+        }
+        result
+    }
+
+    // replace the meta of a cached object, returns false when the object does not exist
+    pub async fn cache_update_meta(
+        &self,
+        key: &str,
+        meta: Vec<u8>,
+        header: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, RedisError> {
+        let mut invocation = UPDATE_META.key(key);
+        invocation.arg(meta).arg(header).arg(ttl.as_millis() as u64);
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<i64, RedisError> = invocation.invoke_async(&mut connection).await;
+        match result {
+            Ok(updated) => Ok(updated == 1),
+            Err(e) => {
+                self.reset(slot, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // take a concurrency slot, the slot is held until released or until the lease expires
+    // returns if the slot is taken
+    pub async fn acquire_slot(
+        &self,
+        key: &str,
+        limit: i64,
+        lease: Duration,
+        id: &str,
+    ) -> Result<bool, RedisError> {
+        let mut invocation = ACQUIRE_SLOT.key(key);
+        invocation.arg(limit).arg(lease.as_millis() as u64).arg(id);
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<i64, RedisError> = invocation.invoke_async(&mut connection).await;
+        match result {
+            Ok(acquired) => Ok(acquired == 1),
+            Err(e) => {
+                self.reset(slot, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // release a concurrency slot taken with the id
+    pub async fn release_slot(&self, key: &str, id: &str) -> Result<(), RedisError> {
+        let (slot, mut connection) = self.get_connection().await?;
+        let result: Result<(), RedisError> = connection.zrem(key, id).await;
+        if let Err(e) = &result {
+            self.reset(slot, e).await;
+        }
+        result
+    }
+}
+
+// the current time in milliseconds from the redis clock
+const NOW: &str = r"
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+";
+
+// fixed window, the counter expires with the window
+static FIXED_WINDOW: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local limit = tonumber(ARGV[1])
+        local count = redis.call('INCR', KEYS[1])
+        if count == 1 then
+            redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        end
+        local allowed = 0
+        if count <= limit then
+            allowed = 1
+        end
+        local reset = redis.call('PTTL', KEYS[1])
+        return {allowed, math.max(0, limit - count), math.max(0, reset)}
+    ",
+    )
+});
+
+// sliding window log, every allowed request of the window is kept
+// the reset is when the oldest request leaves the window
+static SLIDING_WINDOW: Lazy<Script> = Lazy::new(|| {
+    Script::new(&format!(
+        r"
+        {}
+        local key = KEYS[1]
+        local limit = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+        local count = redis.call('ZCARD', key)
+        local allowed = 0
+        if count < limit then
+            redis.call('ZADD', key, now, ARGV[3])
+            redis.call('PEXPIRE', key, window)
+            count = count + 1
+            allowed = 1
+        end
+        local reset = window
+        local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+        if oldest[2] then
+            reset = tonumber(oldest[2]) + window - now
+        end
+        return {{allowed, math.max(0, limit - count), math.max(0, reset)}}
+    ",
+        NOW
+    ))
+});
+
+// token bucket, the bucket is refilled with the limit every window
+// the reset is when the bucket is full, or when the next token is added once empty
+static TOKEN_BUCKET: Lazy<Script> = Lazy::new(|| {
+    Script::new(&format!(
+        r"
+        {}
+        local key = KEYS[1]
+        local capacity = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local bucket = redis.call('HMGET', key, 'tokens', 'updated')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + (now - updated) * capacity / window)
+        local allowed = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+            allowed = 1
+        end
+        redis.call('HSET', key, 'tokens', tostring(tokens), 'updated', tostring(now))
+        redis.call('PEXPIRE', key, window)
+        local reset = (capacity - tokens) * window / capacity
+        if tokens < 1 then
+            reset = (1 - tokens) * window / capacity
+        end
+        return {{allowed, math.floor(tokens), math.ceil(reset)}}
+    ",
+        NOW
+    ))
+});
+
+// leaky bucket, the bucket leaks the limit every window
+// the reset is when the bucket is empty, or when the next request fits once full
+static LEAKY_BUCKET: Lazy<Script> = Lazy::new(|| {
+    Script::new(&format!(
+        r"
+        {}
+        local key = KEYS[1]
+        local capacity = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local bucket = redis.call('HMGET', key, 'level', 'updated')
+        local level = tonumber(bucket[1]) or 0
+        local updated = tonumber(bucket[2]) or now
+        level = math.max(0, level - (now - updated) * capacity / window)
+        local allowed = 0
+        if level + 1 <= capacity then
+            level = level + 1
+            allowed = 1
+        end
+        redis.call('HSET', key, 'level', tostring(level), 'updated', tostring(now))
+        redis.call('PEXPIRE', key, window)
+        local remaining = math.floor(capacity - level)
+        local reset = level * window / capacity
+        if remaining < 1 then
+            reset = (level + 1 - capacity) * window / capacity
+        end
+        return {{allowed, remaining, math.ceil(reset)}}
+    ",
+        NOW
+    ))
+});
+
+// concurrency slots, every slot is a member scored with its lease expiry
+static ACQUIRE_SLOT: Lazy<Script> = Lazy::new(|| {
+    Script::new(&format!(
+        r"
+        {}
+        local key = KEYS[1]
+        local limit = tonumber(ARGV[1])
+        local lease = tonumber(ARGV[2])
+        redis.call('ZREMRANGEBYSCORE', key, '-inf', now)
+        if redis.call('ZCARD', key) >= limit then
+            return 0
+        end
+        redis.call('ZADD', key, now + lease, ARGV[3])
+        redis.call('PEXPIRE', key, lease)
+        return 1
+    ",
+        NOW
+    ))
+});
+
+// quota counters, the counters expire at the end of their period
+static CONSUME_QUOTA: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local counters = {}
+        local allowed = 1
+        for index, key in ipairs(KEYS) do
+            counters[index] = tonumber(redis.call('GET', key)) or 0
+            if counters[index] >= tonumber(ARGV[index * 2 - 1]) then
+                allowed = 0
+            end
+        end
+        if allowed == 1 then
+            for index, key in ipairs(KEYS) do
+                counters[index] = redis.call('INCR', key)
+                redis.call('EXPIREAT', key, ARGV[index * 2])
+            end
+        end
+        return {allowed, counters}
+    ",
+    )
+});
+
+// cache meta update, the meta is only replaced when the object exists
+static UPDATE_META: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], 'meta', ARGV[1], 'header', ARGV[2])
+        redis.call('PEXPIRE', KEYS[1], ARGV[3])
+        return 1
+    ",
+    )
+});
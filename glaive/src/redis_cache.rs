@@ -0,0 +1,300 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::any::Any;
+use std::time::{Duration, SystemTime};
+
+use pingora::cache::key::{CacheHashKey, CacheKey, CompactCacheKey};
+use pingora::cache::storage::{HandleMiss, HitHandler, MissHandler};
+use pingora::cache::trace::SpanHandle;
+use pingora::cache::{CacheMeta, PurgeType, Storage};
+use pingora::{Error, ErrorType, Result};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use redis::RedisError;
+
+use crate::cache::{CacheHitHandler, SccCacheObject};
+use crate::redis::{CachedObject, RedisConnection};
+
+// the cache storage shared by every gateway through redis
+// the objects are stored under the key hash and expire with the ttl of their meta
+pub struct RedisStorage {
+    connection: RedisConnection,
+    prefix: String,
+    /// Maximum allowed body size for caching
+    max_file_size_bytes: Option<usize>,
+    /// Will reject cache admissions with empty body responses
+    reject_empty_body: bool,
+}
+
+impl RedisStorage {
+    pub fn new(connection: RedisConnection, prefix: String) -> Self {
+        RedisStorage {
+            connection,
+            prefix,
+            max_file_size_bytes: None,
+            reject_empty_body: false,
+        }
+    }
+    // max file size config when cache occurs
+    pub fn with_max_file_size(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_file_size_bytes = max_bytes;
+        self
+    }
+    // reject empty cache config when cache occurs
+    pub fn with_reject_empty_body(mut self, should_error: bool) -> Self {
+        self.reject_empty_body = should_error;
+        self
+    }
+
+    // the redis key of the cache key hash
+    fn redis_key(&self, key: &impl CacheHashKey) -> String {
+        format!("{}:cache:{}", self.prefix, key.combined())
+    }
+}
+
+// the redis ttl of an object, the stale objects are kept until they can not be served anymore
+fn object_ttl(meta: &CacheMeta) -> Duration {
+    let stale = meta
+        .stale_while_revalidate_sec()
+        .max(meta.stale_if_error_sec());
+    let fresh = meta
+        .fresh_until()
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    (fresh + Duration::from_secs(stale as u64)).max(Duration::from_secs(1))
+}
+
+// decode the meta and the body of a redis object
+fn decode_object(object: CachedObject) -> Result<(CacheMeta, SccCacheObject)> {
+    let (meta, header, body) = object;
+    let cache_meta = CacheMeta::deserialize(&meta, &header)?;
+    let cache_object =
+        SccCacheObject::new((Bytes::from(meta), Bytes::from(header)), Bytes::from(body));
+    Ok((cache_meta, cache_object))
+}
+
+// wrap the redis error as a cache storage error
+fn storage_error(e: RedisError) -> Box<Error> {
+    Error::because(ErrorType::Custom("cache redis error"), "redis storage", e)
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    // cache lookup is responsible for finding the cache data
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let object = self
+            .connection
+            .cache_get(&self.redis_key(key))
+            .await
+            .map_err(storage_error)?;
+        let Some(object) = object else {
+            return Ok(None);
+        };
+        // the expired meta is kept, the cache decides if it is still served
+        let (cache_meta, cache_object) = decode_object(object)?;
+        Ok(Some((
+            cache_meta,
+            Box::new(CacheHitHandler::new(cache_object)),
+        )))
+    }
+
+    // get_miss_handler is executed when cache did not hit
+    // return the miss handler to cache the new response
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let (raw_meta, raw_header) = meta.serialize()?;
+        let miss_handler = RedisMissHandler {
+            meta: raw_meta,
+            header: raw_header,
+            ttl: object_ttl(meta),
+            key: self.redis_key(key),
+            body_buf: BytesMut::new(),
+            inner: self,
+        };
+        Ok(Box::new(miss_handler))
+    }
+
+    // purge is used to remove the cache from redis
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let deleted = self
+            .connection
+            .delete(&[self.redis_key(key)])
+            .await
+            .map_err(storage_error)?;
+        Ok(deleted > 0)
+    }
+
+    // update_meta is used to update the current meta in redis
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let (raw_meta, raw_header) = meta.serialize()?;
+        let updated = self
+            .connection
+            .cache_update_meta(&self.redis_key(key), raw_meta, raw_header, object_ttl(meta))
+            .await
+            .map_err(storage_error)?;
+        if updated {
+            Ok(true)
+        } else {
+            Err(Error::create(
+                ErrorType::Custom("No meta found for update_meta"),
+                pingora::ErrorSource::Internal,
+                Some(format!("key = {:?}", key).into()),
+                None,
+            ))
+        }
+    }
+
+    // currently not using partial write
+    fn support_streaming_partial_write(&self) -> bool {
+        false
+    }
+    // ignored
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+// miss handler when cache did not hit
+struct RedisMissHandler {
+    meta: Vec<u8>,
+    header: Vec<u8>,
+    ttl: Duration,
+    key: String,
+    body_buf: BytesMut,
+    inner: &'static RedisStorage,
+}
+
+#[async_trait]
+impl HandleMiss for RedisMissHandler {
+    // the write body will validate if the response is cacheable by size
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        if let Some(max_file_size_bytes) = self.inner.max_file_size_bytes {
+            if self.body_buf.len() + data.len() > max_file_size_bytes {
+                return Error::e_explain(
+                    pingora::cache::max_file_size::ERR_RESPONSE_TOO_LARGE,
+                    format!(
+                        "writing data of size {} bytes would exceed max file size of {} bytes",
+                        data.len(),
+                        max_file_size_bytes
+                    ),
+                );
+            }
+        }
+        self.body_buf.extend_from_slice(&data);
+        Ok(())
+    }
+
+    // write the object to redis once the whole body is received
+    async fn finish(self: Box<Self>) -> Result<usize> {
+        let body_len = self.body_buf.len();
+        if body_len == 0 && self.inner.reject_empty_body {
+            let err = Error::create(
+                ErrorType::Custom("cache write error: empty body"),
+                pingora::ErrorSource::Internal,
+                None,
+                None,
+            );
+            return Err(err);
+        }
+        let size = body_len + self.meta.len() + self.header.len();
+        self.inner
+            .connection
+            .cache_set(
+                &self.key,
+                (self.meta, self.header, self.body_buf.to_vec()),
+                self.ttl,
+            )
+            .await
+            .map_err(storage_error)?;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod redis_cache_mod {
+    use super::*;
+    use pingora::http::ResponseHeader;
+
+    fn meta(fresh_sec: u64, stale_while_revalidate: u32, stale_if_error: u32) -> CacheMeta {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header
+            .insert_header("Content-Type", "application/json")
+            .unwrap();
+        let now = SystemTime::now();
+        CacheMeta::new(
+            now + Duration::from_secs(fresh_sec),
+            now,
+            stale_while_revalidate,
+            stale_if_error,
+            header,
+        )
+    }
+
+    #[test]
+    fn object_ttl_test() {
+        // the fresh time, rounded down by the elapsed time
+        let ttl = object_ttl(&meta(60, 0, 0));
+        assert!(ttl > Duration::from_secs(58) && ttl <= Duration::from_secs(60));
+        // the object is kept for the longest stale time
+        let ttl = object_ttl(&meta(60, 30, 300));
+        assert!(ttl > Duration::from_secs(358) && ttl <= Duration::from_secs(360));
+        let ttl = object_ttl(&meta(0, 30, 0));
+        assert!(ttl > Duration::from_secs(28) && ttl <= Duration::from_secs(30));
+        // the expired object is kept for a second at least
+        assert_eq!(object_ttl(&meta(0, 0, 0)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn object_test() {
+        let original = meta(60, 30, 300);
+        let (raw_meta, raw_header) = original.serialize().unwrap();
+        let (decoded, _) = decode_object((raw_meta, raw_header, b"body".to_vec())).unwrap();
+        assert_eq!(decoded.fresh_until(), original.fresh_until());
+        assert_eq!(decoded.created(), original.created());
+        assert_eq!(decoded.stale_while_revalidate_sec(), 30);
+        assert_eq!(decoded.stale_if_error_sec(), 300);
+        assert_eq!(decoded.response_header().status, 200);
+        assert_eq!(
+            decoded.headers().get("Content-Type").unwrap(),
+            "application/json"
+        );
+        // the corrupted meta is an error rather than a hit
+        assert!(decode_object((b"meta".to_vec(), b"header".to_vec(), vec![])).is_err());
+    }
+}
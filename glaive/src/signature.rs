@@ -0,0 +1,406 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use pingora::http::RequestHeader;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::header;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::credential::{CredentialCache, SecretStore};
+use crate::def::{Hmac as HmacConfig, HmacAlgorithm};
+
+// the default allowed clock skew in seconds
+const DEFAULT_CLOCK_SKEW: u64 = 300;
+
+// the default maximum body size held until the digest is verified
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// the signature parameters of the authorization header
+// hmac username="alice", algorithm="hmac-sha256", headers="@method @path date", signature="..."
+#[derive(Debug, PartialEq)]
+pub struct SignatureParams {
+    pub username: String,
+    pub algorithm: HmacAlgorithm,
+    pub headers: Vec<String>,
+    pub signature: Vec<u8>,
+}
+
+impl SignatureParams {
+    // parse the authorization header, returns none when the header is not a hmac signature
+    pub fn parse(value: &str) -> Option<SignatureParams> {
+        let (scheme, params) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("hmac") {
+            return None;
+        }
+        let params: HashMap<String, String> = params
+            .split(',')
+            .filter_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                Some((
+                    key.trim().to_ascii_lowercase(),
+                    value.trim().trim_matches('"').to_string(),
+                ))
+            })
+            .collect();
+        let algorithm = match params.get("algorithm")?.to_ascii_lowercase().as_str() {
+            "hmac-sha256" => HmacAlgorithm::HmacSha256,
+            "hmac-sha512" => HmacAlgorithm::HmacSha512,
+            _ => return None,
+        };
+        Some(SignatureParams {
+            username: params.get("username")?.clone(),
+            algorithm,
+            headers: params
+                .get("headers")?
+                .split_whitespace()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            signature: STANDARD.decode(params.get("signature")?).ok()?,
+        })
+    }
+}
+
+// the hasher of the digest algorithm
+enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+// the request body hash, compared with the digest header once the body is read
+// the body is held until it is verified, so no unverified byte is forwarded to the upstream
+pub struct BodyDigest {
+    hasher: DigestHasher,
+    expected: Vec<u8>,
+    body: BytesMut,
+    max_body_size: usize,
+}
+
+impl BodyDigest {
+    // parse the rfc 3230 digest header, the first supported algorithm is used
+    pub fn parse(value: &str, max_body_size: usize) -> Option<BodyDigest> {
+        value.split(',').find_map(|digest| {
+            let (algorithm, encoded) = digest.trim().split_once('=')?;
+            let expected = STANDARD.decode(encoded.trim()).ok()?;
+            let hasher = match algorithm.to_ascii_uppercase().as_str() {
+                "SHA-256" => DigestHasher::Sha256(Sha256::new()),
+                "SHA-512" => DigestHasher::Sha512(Sha512::new()),
+                _ => return None,
+            };
+            Some(BodyDigest {
+                hasher,
+                expected,
+                body: BytesMut::new(),
+                max_body_size,
+            })
+        })
+    }
+
+    // hash and hold the chunk, returns false when the body is over the maximum size
+    pub fn update(&mut self, chunk: &[u8]) -> bool {
+        if self.body.len() + chunk.len() > self.max_body_size {
+            return false;
+        }
+        match &mut self.hasher {
+            DigestHasher::Sha256(hasher) => hasher.update(chunk),
+            DigestHasher::Sha512(hasher) => hasher.update(chunk),
+        }
+        self.body.extend_from_slice(chunk);
+        true
+    }
+
+    // checks if the read body matches the digest, the held body is returned when it does
+    pub fn verify(self) -> Option<Bytes> {
+        let verified = match self.hasher {
+            DigestHasher::Sha256(hasher) => hasher.finalize().as_slice() == self.expected,
+            DigestHasher::Sha512(hasher) => hasher.finalize().as_slice() == self.expected,
+        };
+        verified.then(|| self.body.freeze())
+    }
+}
+
+// the hmac signature validator
+// the nonces are kept for twice the clock skew, the older requests are rejected by the date anyway
+pub struct SignatureValidator {
+    algorithms: Vec<HmacAlgorithm>,
+    components: Vec<String>,
+    clock_skew: i64,
+    nonce_header: Option<String>,
+    enforce_digest: bool,
+    max_body_size: usize,
+    nonces: CredentialCache<()>,
+}
+
+impl SignatureValidator {
+    pub fn new(config: &HmacConfig) -> SignatureValidator {
+        SignatureValidator {
+            algorithms: config
+                .algorithms
+                .clone()
+                .unwrap_or_else(|| vec![HmacAlgorithm::HmacSha256, HmacAlgorithm::HmacSha512]),
+            components: config
+                .components
+                .clone()
+                .unwrap_or_else(|| vec!["@method".into(), "@path".into(), "date".into()])
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            clock_skew: config.clock_skew.unwrap_or(DEFAULT_CLOCK_SKEW) as i64,
+            nonce_header: config
+                .nonce_header
+                .as_ref()
+                .map(|name| name.to_ascii_lowercase()),
+            enforce_digest: config.enforce_digest.unwrap_or(true),
+            max_body_size: config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            nonces: CredentialCache::new(),
+        }
+    }
+
+    // the maximum body size held until the digest is verified
+    pub fn get_max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    // verify the signature of the request and get the consumer
+    // the path is the original uri, before the cluster prefix is removed
+    pub fn verify(
+        &self,
+        secrets: &SecretStore,
+        params: &SignatureParams,
+        req: &RequestHeader,
+        path: &str,
+    ) -> Result<String, String> {
+        if !self.algorithms.contains(&params.algorithm) {
+            return Err("algorithm is not allowed".to_string());
+        }
+        // every required component must be signed
+        let mut required: Vec<&str> = self.components.iter().map(|c| c.as_str()).collect();
+        if let Some(nonce_header) = &self.nonce_header {
+            required.push(nonce_header);
+        }
+        let digest_header = req.headers.get("digest");
+        if digest_header.is_some() || (self.enforce_digest && has_body(req)) {
+            required.push("digest");
+        }
+        if let Some(missing) = required
+            .iter()
+            .find(|component| !params.headers.iter().any(|signed| signed == *component))
+        {
+            return Err(format!("{} is not signed", missing));
+        }
+        // the date must be signed and within the clock skew
+        let (date_header, date) = ["date", "x-date"]
+            .into_iter()
+            .find_map(|name| header_value(req, name).map(|date| (name, date)))
+            .ok_or_else(|| "date is required".to_string())?;
+        if !params.headers.iter().any(|signed| signed == date_header) {
+            return Err(format!("{} is not signed", date_header));
+        }
+        let date = DateTime::parse_from_rfc2822(date)
+            .map_err(|_| "invalid date".to_string())?
+            .with_timezone(&Utc);
+        if (Utc::now() - date).num_seconds().abs() > self.clock_skew {
+            return Err("date is outside the clock skew".to_string());
+        }
+        // verify the signature with the secret of the username
+        let (consumer, secret) = secrets
+            .get(&params.username)
+            .ok_or_else(|| "invalid signature".to_string())?;
+        let signing_string = signing_string(&params.headers, req, path)?;
+        let verified = match params.algorithm {
+            HmacAlgorithm::HmacSha256 => {
+                verify_mac::<Hmac<Sha256>>(secret, &signing_string, &params.signature)
+            }
+            HmacAlgorithm::HmacSha512 => {
+                verify_mac::<Hmac<Sha512>>(secret, &signing_string, &params.signature)
+            }
+        };
+        if !verified {
+            return Err("invalid signature".to_string());
+        }
+        // the nonce is checked once the signature is verified, so a forged request can not burn it
+        if let Some(nonce_header) = &self.nonce_header {
+            let nonce = header_value(req, nonce_header).unwrap_or_default();
+            let ttl = Duration::from_secs(self.clock_skew as u64 * 2);
+            if !self
+                .nonces
+                .insert_new(format!("{}:{}", consumer, nonce), (), ttl)
+            {
+                return Err("replayed request".to_string());
+            }
+        }
+        Ok(consumer.clone())
+    }
+}
+
+// build the signing string, one "name: value" line per signed component
+pub fn signing_string(
+    components: &[String],
+    req: &RequestHeader,
+    path: &str,
+) -> Result<String, String> {
+    let mut lines = Vec::with_capacity(components.len());
+    for component in components {
+        let value = match component.as_str() {
+            "@method" => req.method.as_str(),
+            "@path" => path,
+            name => header_value(req, name).ok_or_else(|| format!("{} is missing", name))?,
+        };
+        lines.push(format!("{}: {}", component, value));
+    }
+    Ok(lines.join("\n"))
+}
+
+// verify the mac in constant time
+fn verify_mac<M: Mac + hmac::digest::KeyInit>(
+    secret: &str,
+    message: &str,
+    signature: &[u8],
+) -> bool {
+    match <M as Mac>::new_from_slice(secret.as_bytes()) {
+        Ok(mut mac) => {
+            mac.update(message.as_bytes());
+            mac.verify_slice(signature).is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+fn header_value<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// checks if the request has a body
+pub fn has_body(req: &RequestHeader) -> bool {
+    req.headers.contains_key(header::TRANSFER_ENCODING)
+        || header_value(req, "content-length")
+            .and_then(|length| length.trim().parse::<u64>().ok())
+            .is_some_and(|length| length > 0)
+}
+
+#[cfg(test)]
+mod signature_mod {
+    use super::*;
+    use crate::def::{Consumer, Credentials, HmacCredential};
+
+    fn sign(secret: &str, message: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn verify_test() {
+        let secrets = SecretStore::from_consumers(&[Consumer {
+            id: "partner".to_string(),
+            groups: None,
+            acl: None,
+            metadata: None,
+            credentials: Some(Credentials {
+                keys: None,
+                basic: None,
+                hmac: Some(HmacCredential {
+                    username: None,
+                    secret: "shared".to_string(),
+                }),
+            }),
+            quota: None,
+        }]);
+        let validator = SignatureValidator::new(&HmacConfig {
+            algorithms: None,
+            components: None,
+            clock_skew: None,
+            nonce_header: Some("X-Nonce".to_string()),
+            enforce_digest: None,
+            max_body_size: None,
+        });
+        let date = Utc::now().to_rfc2822();
+        let mut req = RequestHeader::build("GET", b"/orders?page=1", None).unwrap();
+        req.insert_header("date", &date).unwrap();
+        req.insert_header("x-nonce", "n-1").unwrap();
+        let components = "@method @path date x-nonce";
+        let message = format!(
+            "@method: GET\n@path: /api/orders?page=1\ndate: {}\nx-nonce: n-1",
+            date
+        );
+        let authorization = format!(
+            "hmac username=\"partner\", algorithm=\"hmac-sha256\", headers=\"{}\", signature=\"{}\"",
+            components,
+            sign("shared", &message)
+        );
+        let params = SignatureParams::parse(&authorization).unwrap();
+        assert_eq!(
+            validator.verify(&secrets, &params, &req, "/api/orders?page=1"),
+            Ok("partner".to_string())
+        );
+        // the same nonce is rejected
+        assert_eq!(
+            validator.verify(&secrets, &params, &req, "/api/orders?page=1"),
+            Err("replayed request".to_string())
+        );
+        // the path is part of the signature
+        assert_eq!(
+            validator.verify(&secrets, &params, &req, "/api/orders?page=2"),
+            Err("invalid signature".to_string())
+        );
+        // the date must be signed, even when the components do not require it
+        let validator = SignatureValidator::new(&HmacConfig {
+            algorithms: None,
+            components: Some(vec!["@method".to_string(), "@path".to_string()]),
+            clock_skew: None,
+            nonce_header: None,
+            enforce_digest: None,
+            max_body_size: None,
+        });
+        let message = "@method: GET\n@path: /api/orders?page=1";
+        let authorization = format!(
+            "hmac username=\"partner\", algorithm=\"hmac-sha256\", headers=\"@method @path\", signature=\"{}\"",
+            sign("shared", message)
+        );
+        let params = SignatureParams::parse(&authorization).unwrap();
+        assert_eq!(
+            validator.verify(&secrets, &params, &req, "/api/orders?page=1"),
+            Err("date is not signed".to_string())
+        );
+    }
+
+    #[test]
+    fn body_digest_test() {
+        let digest = "SHA-256=LCa0a2j/xo/5m0U8HTBBNBNCLXBkg7+g+YpeiGJm564=";
+        // the held body is released once it matches the digest
+        let mut body = BodyDigest::parse(digest, 8).unwrap();
+        assert!(body.update(b"fo"));
+        assert!(body.update(b"o"));
+        assert_eq!(body.verify(), Some(Bytes::from_static(b"foo")));
+        let mut body = BodyDigest::parse(digest, 8).unwrap();
+        assert!(body.update(b"bar"));
+        assert_eq!(body.verify(), None);
+        // the body over the maximum size is refused
+        let mut body = BodyDigest::parse(digest, 2).unwrap();
+        assert!(!body.update(b"foo"));
+        assert!(BodyDigest::parse("MD5=rL0Y20zC+Fzt72VPzMSk2A==", 8).is_none());
+    }
+}
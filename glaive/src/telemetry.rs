@@ -0,0 +1,383 @@
+/**
+ * Copyright (c) 2024-2025 Glaive, Inc.
+ *
+ * This file is part of Glaive Gateway
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use pingora::http::RequestHeader;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+use async_trait::async_trait;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tokio::runtime::Runtime;
+use tracing::{warn, Span};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::def::{OtlpProtocol, Telemetry};
+
+// the default export timeout in milliseconds
+const DEFAULT_TIMEOUT: u64 = 10000;
+
+// the otlp tracer and the runtime used for exporting the spans
+// the exporter runs on its own runtime, because the pingora runtimes are not started yet
+pub struct TelemetryTracer {
+    provider: TracerProvider,
+    tracer: Tracer,
+    runtime: Mutex<Option<Runtime>>,
+}
+
+impl TelemetryTracer {
+    // build the otlp exporter and the tracer from the configuration
+    pub fn new(config: &Telemetry) -> Result<TelemetryTracer, String> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("telemetry exporter")
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        // the exporter and the batch processor spawn their tasks on the current runtime
+        let _guard = runtime.enter();
+        let timeout = Duration::from_millis(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let exporter = match config.protocol.unwrap_or(OtlpProtocol::Grpc) {
+            OtlpProtocol::Grpc => SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(config.endpoint.clone())
+                .with_timeout(timeout)
+                .build(),
+            OtlpProtocol::Http => SpanExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpBinary)
+                .with_endpoint(http_endpoint(&config.endpoint))
+                .with_timeout(timeout)
+                .build(),
+        }
+        .map_err(|e| e.to_string())?;
+        // respect the incoming sampling decision, otherwise sample by the ratio
+        let ratio = config.sample_ratio.unwrap_or(1.0).clamp(0.0, 1.0);
+        let service_name = config
+            .service_name
+            .clone()
+            .unwrap_or_else(|| "glaive".to_string());
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                ratio,
+            ))))
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name,
+            )]))
+            .build();
+        let tracer = provider.tracer("glaive");
+        // the w3c trace context is used for extracting and injecting the traceparent
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        Ok(TelemetryTracer {
+            provider,
+            tracer,
+            runtime: Mutex::new(Some(runtime)),
+        })
+    }
+
+    // the tracing layer that exports every span to the collector
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer.clone())
+    }
+}
+
+// the otlp http exporter expects the full traces url
+fn http_endpoint(endpoint: &str) -> String {
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+    }
+}
+
+// set the span parent from the incoming traceparent and tracestate headers
+pub fn extract_context(span: &Span, req: &RequestHeader) {
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(&req.headers))
+    });
+    span.set_parent(context);
+}
+
+// write the traceparent and tracestate headers of the span to the upstream request
+pub fn inject_context(span: &Span, req: &mut RequestHeader) {
+    let mut injector = HeaderInjector(Vec::new());
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut injector)
+    });
+    for (key, value) in injector.0 {
+        // the empty tracestate is not forwarded
+        if value.is_empty() {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            let _ = req.insert_header(key, value);
+        }
+    }
+}
+
+// read the propagation headers from the request
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// collect the propagation headers, they are inserted to the request afterward
+struct HeaderInjector(Vec<(HeaderName, String)>);
+
+impl Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        if let Ok(key) = HeaderName::from_bytes(key.as_bytes()) {
+            self.0.push((key, value));
+        }
+    }
+}
+
+// background processing service that flushes the pending spans on shutdown
+pub struct TracingBackgroundService {
+    pub tracer: TelemetryTracer,
+}
+
+#[async_trait]
+impl BackgroundService for TracingBackgroundService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let _ = shutdown.changed().await;
+        let runtime = match self.tracer.runtime.lock().unwrap().take() {
+            Some(runtime) => runtime,
+            None => return,
+        };
+        let provider = self.tracer.provider.clone();
+        // the shutdown blocks until the exporter runtime has flushed the spans
+        let result = runtime.spawn_blocking(move || provider.shutdown()).await;
+        if let Ok(Err(e)) = result {
+            warn!("unable to flush the pending spans: {}", e);
+        }
+        // the runtime can not be dropped within the async context
+        runtime.shutdown_background();
+    }
+}
+
+#[cfg(test)]
+mod telemetry_mod {
+    use super::*;
+    use crate::cluster::build_cluster;
+    use crate::config::ClusterConfig;
+    use crate::consumer::ConsumerRegistry;
+    use crate::gateway::Gateway;
+    use crate::proxy::ProxyRouter;
+
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use pingora::http::ResponseHeader;
+    use pingora::proxy::{ProxyHttp, Session};
+    use tokio::io::AsyncWriteExt;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    // the spans kept in memory, in place of a local collector
+    #[derive(Clone, Debug, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    // the tracing subscriber exporting the spans to memory
+    fn subscriber(exporter: &MemoryExporter) -> impl tracing::Subscriber {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("glaive")))
+    }
+
+    fn incoming_request() -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/service/items", None).unwrap();
+        req.insert_header("traceparent", TRACEPARENT).unwrap();
+        req
+    }
+
+    #[test]
+    fn propagation_test() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        let context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(&req.headers))
+        });
+        let span_context = context.span().span_context().clone();
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert!(span_context.is_remote());
+        assert!(span_context.is_sampled());
+        assert_eq!(
+            http_endpoint("http://localhost:4318/"),
+            "http://localhost:4318/v1/traces"
+        );
+    }
+
+    #[test]
+    fn inject_test() {
+        let exporter = MemoryExporter::default();
+        let _guard = tracing::subscriber::set_default(subscriber(&exporter));
+        let span = info_span!("request");
+        extract_context(&span, &incoming_request());
+        let span_id = span.context().span().span_context().span_id();
+        let mut upstream_request = RequestHeader::build("GET", b"/items", None).unwrap();
+        inject_context(&span, &mut upstream_request);
+        // the upstream continues the incoming trace as a child of the gateway span
+        let traceparent = upstream_request.headers.get("traceparent").unwrap();
+        assert_eq!(
+            traceparent.to_str().unwrap(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", span_id)
+        );
+        assert_ne!(span_id, SpanId::from_hex("00f067aa0ba902b7").unwrap());
+        // the empty tracestate is not forwarded
+        assert!(upstream_request.headers.get("tracestate").is_none());
+    }
+
+    #[tokio::test]
+    async fn span_test() {
+        let exporter = MemoryExporter::default();
+        let _guard = tracing::subscriber::set_default(subscriber(&exporter));
+        let cluster: ClusterConfig = serde_yaml::from_str(
+            "name: service\nprefix: /service\nhost: localhost\ntls: false\nupstream: [\"127.0.0.1:8000\"]\nauth:\n  key:\n    allowed: [\"secret\"]\nrate_limit:\n  client:\n    basic:\n      limit: 10\ncache:\n  memory:\n    cache_ttl: 10\n    max_size: 1\n    max_cache: 1\n    lock_timeout: 1000",
+        )
+        .unwrap();
+        let built_clusters = build_cluster(vec![cluster]);
+        let router = ProxyRouter {
+            gateway: Gateway::new(),
+            clusters: built_clusters.clusters,
+            prefix_map: built_clusters.prefix_map,
+            consumers: ConsumerRegistry::new(None),
+            quotas: None,
+            request_id_header: "x-request-id".to_string(),
+            access_logger: None,
+        };
+        // the request goes through every phase up to the upstream response
+        let (mut client, server) = tokio::io::duplex(4096);
+        let request = format!(
+            "GET /service/items HTTP/1.1\r\nAuthorization: Bearer secret\r\ntraceparent: {}\r\n\r\n",
+            TRACEPARENT
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut session = Session::new_h1(Box::new(server));
+        session.downstream_session.read_request().await.unwrap();
+        let mut ctx = router.new_ctx();
+        assert!(!router.request_filter(&mut session, &mut ctx).await.unwrap());
+        router.request_cache_filter(&mut session, &mut ctx).unwrap();
+        let peer = router.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        router
+            .connected_to_upstream(&mut session, false, &peer, 0, None, &mut ctx)
+            .await
+            .unwrap();
+        let mut upstream_request = session.req_header().clone();
+        router
+            .upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+            .await
+            .unwrap();
+        let mut upstream_response = ResponseHeader::build(200, None).unwrap();
+        router.upstream_response_filter(&mut session, &mut upstream_response, &mut ctx);
+        router.logging(&mut session, None, &mut ctx).await;
+        drop(ctx);
+
+        let spans = exporter.0.lock().unwrap().clone();
+        let find = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("no {} span", name))
+        };
+        // the request span continues the incoming trace
+        let request = find("request");
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        assert_eq!(request.span_context.trace_id(), trace_id);
+        assert_eq!(
+            request.parent_span_id,
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        );
+        // every phase is a child of the request span
+        for name in [
+            "auth",
+            "rate_limit",
+            "cache_lookup",
+            "upstream_connect",
+            "upstream_response",
+        ] {
+            let span = find(name);
+            assert_eq!(span.span_context.trace_id(), trace_id, "{}", name);
+            assert_eq!(
+                span.parent_span_id,
+                request.span_context.span_id(),
+                "{}",
+                name
+            );
+        }
+        // the upstream is a child of the upstream response span
+        let traceparent = upstream_request.headers.get("traceparent").unwrap();
+        assert_eq!(
+            traceparent.to_str().unwrap(),
+            format!(
+                "00-{}-{}-01",
+                trace_id,
+                find("upstream_response").span_context.span_id()
+            )
+        );
+    }
+}